@@ -1,14 +1,28 @@
 use std::collections::BTreeMap;
+use std::io::Read;
+use std::marker::PhantomData;
 use std::vec::Vec;
 use std::option::Option;
+use std::sync::Mutex;
 
 use hyper;
+use reqwest;
 use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json;
 use serde_json::map::Map;
+use url::Url;
 
+use comment::{self, Progress};
+use error::{Error, ErrorKind};
 use fanout;
 use rest;
+use state;
+
+/// Default number of items requested per page, which is the same as Bitbucket's default
+const DEFAULT_PAGE_SIZE: i32 = 25;
+/// Key of the build status for the overall verdict. Other build statuses are keyed by build configuration.
+const VERDICT_KEY: &'static str = "pr_demon";
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 #[allow(non_snake_case)]
@@ -18,6 +32,75 @@ struct PagedApi<T> {
     isLastPage: bool,
     values: Vec<T>,
     start: i32,
+    nextPageStart: Option<i32>,
+}
+
+/// Iterates through every page of a paged API, walking `start`/`nextPageStart` until `isLastPage`.
+///
+/// Iteration stops after the first error is returned.
+struct PagedIterator<T, F>
+where
+    F: Fn(&str) -> Result<PagedApi<T>, Error>,
+{
+    url: String,
+    page_size: i32,
+    next_start: Option<i32>,
+    fetch: F,
+    _marker: PhantomData<T>,
+}
+
+impl<T, F> PagedIterator<T, F>
+where
+    F: Fn(&str) -> Result<PagedApi<T>, Error>,
+{
+    fn new(url: &str, page_size: i32, fetch: F) -> PagedIterator<T, F> {
+        PagedIterator {
+            url: url.to_string(),
+            page_size: page_size,
+            next_start: Some(0),
+            fetch: fetch,
+            _marker: PhantomData,
+        }
+    }
+
+    fn page_url(url: &str, start: i32, page_size: i32) -> Result<String, Error> {
+        let mut url = Url::parse(url).map_err(|e| Error::config(format!("Invalid URL {}: {}", url, e)))?;
+        url.query_pairs_mut()
+            .append_pair("start", &start.to_string())
+            .append_pair("limit", &page_size.to_string());
+        Ok(url.into_string())
+    }
+}
+
+impl<T, F> Iterator for PagedIterator<T, F>
+where
+    F: Fn(&str) -> Result<PagedApi<T>, Error>,
+{
+    type Item = Result<Vec<T>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = match self.next_start.take() {
+            None => return None,
+            Some(start) => start,
+        };
+
+        let page = Self::page_url(&self.url, start, self.page_size).and_then(|url| (self.fetch)(&url));
+        match page {
+            Err(err) => Some(Err(err)),
+            Ok(page) => {
+                if !page.isLastPage {
+                    // Older versions of Bitbucket do not return `nextPageStart`
+                    let next_start = page.nextPageStart.unwrap_or(page.start + page.size);
+                    if next_start > start {
+                        self.next_start = Some(next_start);
+                    } else {
+                        warn!("Page at {} of {} does not lead to a later page; stopping", start, self.url);
+                    }
+                }
+                Some(Ok(page.values))
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
@@ -146,97 +229,138 @@ enum BuildState {
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct BitbucketCredentials {
-    pub username: String,
-    pub password: String,
+    /// Required with `password`. With `token`, defaults to the user that the token belongs to.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Personal access token, sent as a bearer token instead of `username` and `password`
+    pub token: Option<String>,
     /// Base URL for Bitbucket
     pub base_url: String,
     pub project_slug: String,
     pub repo_slug: String,
+    /// Number of items to request per page for paged APIs. Defaults to 25.
+    pub page_size: Option<i32>,
 }
 
 pub struct Bitbucket {
     pub credentials: BitbucketCredentials,
+    auth: rest::Auth,
+    /// The user posting comments, looked up from the token unless configured
+    username: Mutex<Option<String>>,
     broadcaster: fanout::Fanout<fanout::Message>,
+    /// Remembers the comments posted so that they need not be searched for
+    state: Option<state::Store>,
 }
 
-impl ::UsernameAndPassword for Bitbucket {
-    fn username(&self) -> &String {
-        &self.credentials.username
-    }
-
-    fn password(&self) -> &String {
-        &self.credentials.password
+fn to_pull_request(pr: &PullRequest) -> ::PullRequest {
+    ::PullRequest {
+        id: pr.id,
+        web_url: pr.links["self"][0].href.to_string(),
+        from_ref: pr.fromRef.id.to_string(),
+        from_commit: pr.fromRef.latestCommit.to_string(),
+        to_ref: pr.toRef.id.to_string(),
+        to_commit: pr.toRef.latestCommit.to_string(),
+        title: pr.title.to_string(),
+        author: ::User {
+            name: pr.author.user.displayName.to_string(),
+            email: pr.author.user.emailAddress.to_string(),
+        },
     }
 }
 
 impl ::Repository for Bitbucket {
-    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, String> {
+    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header();
         let url = format!(
             "{}/rest/api/latest/projects/{}/repos/{}/pull-requests",
             self.credentials.base_url, self.credentials.project_slug, self.credentials.repo_slug
         );
 
-        let prs = rest::get::<PagedApi<PullRequest>>(&url, headers.headers)
-            .map_err(|err| format!("Error getting list of Pull Requests {}", err))?;
-        Ok(prs.values
-            .iter()
-            .map(|pr| ::PullRequest {
-                id: pr.id,
-                web_url: pr.links["self"][0].href.to_string(),
-                from_ref: pr.fromRef.id.to_string(),
-                from_commit: pr.fromRef.latestCommit.to_string(),
-                to_ref: pr.toRef.id.to_string(),
-                to_commit: pr.toRef.latestCommit.to_string(),
-                title: pr.title.to_string(),
-                author: ::User {
-                    name: pr.author.user.displayName.to_string(),
-                    email: pr.author.user.emailAddress.to_string(),
-                },
-            })
-            .collect())
+        let prs = self.get_all_pages::<PullRequest>(&url, headers.headers)
+            .map_err(|err| err.context("Error getting list of Pull Requests"))?;
+        Ok(prs.iter().map(to_pull_request).collect())
+    }
+
+    fn get_pr(&self, id: i32) -> Result<Option<::PullRequest>, Error> {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_auth_header(&self.auth)
+            .add_accept_json_header();
+        let url = format!(
+            "{}/rest/api/latest/projects/{}/repos/{}/pull-requests/{}",
+            self.credentials.base_url, self.credentials.project_slug, self.credentials.repo_slug, id
+        );
+
+        match rest::get::<PullRequest>(&url, headers.headers) {
+            Ok(ref pr) if pr.open => Ok(Some(to_pull_request(pr))),
+            Ok(_) => Ok(None),
+            Err(ref err) if err.kind() == ErrorKind::Status(404) => Ok(None),
+            Err(err) => Err(err.context(format!("Error getting Pull Request #{}", id))),
+        }
+    }
+
+    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Queued)
+            .map_err(|err| err.context("Error submitting comment"))?;
+        Ok(())
     }
 
-    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), String> {
-        self.update_pr_build_status_comment(pr, build, &BuildState::INPROGRESS)
-            .map_err(|err| format!("Error submitting comment: {}", err))?;
+    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Running)
+            .map_err(|err| err.context("Error submitting comment"))?;
         Ok(())
     }
 
-    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), String> {
-        self.build_queued(pr, build)
+    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Success)
+            .map_err(|err| err.context("Error submitting comment"))?;
+        Ok(())
     }
 
-    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), String> {
-        self.update_pr_build_status_comment(pr, build, &BuildState::SUCCESSFUL)
-            .map_err(|err| format!("Error submitting comment: {}", err))?;
+    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Failure)
+            .map_err(|err| err.context("Error submitting comment"))?;
         Ok(())
     }
 
-    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), String> {
-        self.update_pr_build_status_comment(pr, build, &BuildState::FAILED)
-            .map_err(|err| format!("Error submitting comment: {}", err))?;
+    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.post_build_status(pr, &Bitbucket::make_build(build))?;
         Ok(())
     }
 
-    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), String> {
-        self.post_build_status(pr, build)?;
+    fn post_verdict(&self, pr: &::PullRequest, verdict: &::Verdict) -> Result<(), Error> {
+        self.post_build_status(pr, &Bitbucket::make_verdict_build(pr, verdict))?;
         Ok(())
     }
 }
 
 impl Bitbucket {
-    pub fn new(credentials: &BitbucketCredentials, broadcaster: &fanout::Fanout<fanout::Message>) -> Bitbucket {
-        Bitbucket {
+    pub fn new(
+        credentials: &BitbucketCredentials,
+        broadcaster: &fanout::Fanout<fanout::Message>,
+    ) -> Result<Bitbucket, String> {
+        if credentials.page_size.map_or(false, |page_size| page_size <= 0) {
+            return Err("`page_size` must be at least 1".to_string());
+        }
+        let auth = rest::Auth::new(&credentials.username, &credentials.password, &credentials.token)?;
+        Ok(Bitbucket {
             credentials: credentials.to_owned(),
+            auth: auth,
+            username: Mutex::new(credentials.username.clone()),
             broadcaster: broadcaster.to_owned(),
-        }
+            state: None,
+        })
+    }
+
+    pub fn with_state(mut self, store: &state::Store) -> Bitbucket {
+        self.state = Some(store.clone());
+        self
     }
 
-    fn broadcast<T>(&self, opcode: &str, payload: &T) -> Result<(), String>
+    fn broadcast<T>(&self, opcode: &str, payload: &T) -> Result<(), Error>
     where
         T: Serialize,
     {
@@ -248,6 +372,23 @@ impl Bitbucket {
         Ok(())
     }
 
+    /// Retrieve the items from every page of a paged API
+    fn get_all_pages<T>(&self, url: &str, headers: reqwest::header::Headers) -> Result<Vec<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let page_size = self.credentials.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        let pages = PagedIterator::new(url, page_size, |url| {
+            rest::get::<PagedApi<T>>(url, headers.clone())
+        });
+
+        let mut values = vec![];
+        for page in pages {
+            values.extend(page?);
+        }
+        Ok(values)
+    }
+
     fn matching_comments(comments: &[Comment], text: &str) -> Option<Comment> {
         comments
             .iter()
@@ -255,10 +396,11 @@ impl Bitbucket {
             .cloned()
     }
 
-    fn matching_comments_substring(comments: &[Comment], substr: &str) -> Option<Comment> {
+    /// Find the first comment containing all of `substrs`
+    fn matching_build_comments(comments: &[Comment], pr: &::PullRequest, build: &::BuildDetails) -> Option<Comment> {
         comments
             .iter()
-            .find(|&comment| comment.text.as_str().contains(substr))
+            .find(|&comment| comment::is_build_comment(&comment.text, pr, build))
             .cloned()
     }
 
@@ -266,71 +408,177 @@ impl Bitbucket {
         &self,
         pr: &::PullRequest,
         build: &::BuildDetails,
-        state: &BuildState,
-    ) -> Result<Comment, String> {
-        let text = match *state {
-            BuildState::INPROGRESS => make_queued_comment(build, pr, &self.credentials),
-            BuildState::FAILED => make_failure_comment(build, pr, &self.credentials),
-            BuildState::SUCCESSFUL => make_success_comment(build, pr, &self.credentials),
-        };
+        progress: Progress,
+    ) -> Result<(), Error> {
+        let text = make_comment(build, pr, &self.credentials, progress);
 
         let mut event_payload = Map::new();
         event_payload.insert(
             "pr".to_string(),
-            serde_json::to_value(&pr).map_err(|e| e.to_string())?,
+            serde_json::to_value(&pr).map_err(|e| Error::decode(e.to_string()))?,
         );
         event_payload.insert(
             "build".to_string(),
-            serde_json::to_value(&build).map_err(|e| e.to_string())?,
+            serde_json::to_value(&build).map_err(|e| Error::decode(e.to_string()))?,
         );
 
+        // Try the comment we posted last before searching through every comment of the PR
+        if let Some(tracked) = self.tracked_comment(pr.id, &build.build_id) {
+            let edited = if tracked.text == text {
+                Some((Ok(tracked.clone()), "Existing"))
+            } else if comment::is_build_comment(&tracked.text, pr, build) {
+                match self.edit_comment(pr.id, tracked.id, tracked.version, &text) {
+                    Ok(comment) => Some((Ok(Bitbucket::make_tracked_comment(&comment)), "Update")),
+                    Err(err) => {
+                        // Probably edited or deleted by someone else
+                        debug!("Unable to edit tracked comment {}: {}", tracked.id, err);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            if let Some((comment, opcode)) = edited {
+                return self.record_comment(pr, build, comment, opcode, event_payload);
+            }
+        }
+
         let (comment, opcode) = match self.get_comments(pr.id) {
             Ok(ref comments) => {
                 match Bitbucket::matching_comments(comments, &text) {
                     Some(comment) => (Ok(comment), "Existing"),
                     None => {
                         // Have to post or edit comment
-                        match Bitbucket::matching_comments_substring(comments, &pr.from_commit) {
-                            Some(comment) => (self.edit_comment(pr.id, &comment, &text), "Update"),
+                        match Bitbucket::matching_build_comments(comments, pr, build) {
+                            Some(comment) => (
+                                self.edit_comment(pr.id, comment.id, comment.version, &text),
+                                "Update",
+                            ),
                             None => (self.post_comment(pr.id, &text), "Post"),
                         }
                     }
                 }
             }
             Err(err) => (
-                Err(format!("Error getting list of comments {}", err)),
+                Err(err.context("Error getting list of comments")),
                 "Error",
             ),
         };
 
+        let comment = comment.map(|comment| Bitbucket::make_tracked_comment(&comment));
+        self.record_comment(pr, build, comment, opcode, event_payload)
+    }
+
+    fn tracked_comment(&self, pr_id: i32, build_id: &str) -> Option<state::TrackedComment> {
+        let store = match self.state {
+            Some(ref store) => store,
+            None => return None,
+        };
+        match store.get(pr_id) {
+            Ok(pr_state) => pr_state.and_then(|pr_state| pr_state.comments.get(build_id).cloned()),
+            Err(err) => {
+                warn!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// Remember and broadcast the outcome of updating the build status comment
+    fn record_comment(
+        &self,
+        pr: &::PullRequest,
+        build: &::BuildDetails,
+        comment: Result<state::TrackedComment, Error>,
+        opcode: &str,
+        mut event_payload: Map<String, serde_json::Value>,
+    ) -> Result<(), Error> {
         if let Ok(ref comment) = comment {
             event_payload.insert(
                 "comment".to_string(),
-                serde_json::to_value(&comment).map_err(|e| e.to_string())?,
+                serde_json::to_value(&comment).map_err(|e| Error::decode(e.to_string()))?,
             );
+
+            if let Some(ref store) = self.state {
+                let result = store.update(pr.id, |pr_state| {
+                    pr_state
+                        .comments
+                        .insert(build.build_id.to_string(), comment.clone())
+                });
+                if let Err(err) = result {
+                    warn!("Unable to record comment of PR #{}: {}", pr.id, err);
+                }
+            }
         }
 
         self.broadcast(&format!("Comment::{}", opcode), &event_payload)?;
-        comment
+        comment.map(|_| ())
     }
 
-    fn get_comments(&self, pr_id: i32) -> Result<Vec<Comment>, String> {
+    fn make_tracked_comment(comment: &Comment) -> state::TrackedComment {
+        state::TrackedComment {
+            id: comment.id,
+            version: comment.version,
+            text: comment.text.to_string(),
+        }
+    }
+
+    /// The user that comments are posted as
+    fn username(&self) -> Result<String, Error> {
+        let mut username = self.username
+            .lock()
+            .map_err(|err| Error::from(err.to_string()))?;
+        if let Some(ref username) = *username {
+            return Ok(username.to_string());
+        }
+
+        let resolved = self.get_token_user()
+            .map_err(|err| err.context("Error looking up the user of the token"))?;
+        info!("Comments are posted by {}", resolved);
+        *username = Some(resolved.to_string());
+        Ok(resolved)
+    }
+
+    /// The name of the user that the token belongs to
+    fn get_token_user(&self) -> Result<String, Error> {
+        let mut headers = rest::Headers::new();
+        headers.add_auth_header(&self.auth);
+        let url = format!("{}/plugins/servlet/applinks/whoami", self.credentials.base_url);
+
+        let mut response = rest::get_raw(&url, headers.headers)?;
+        if response.status() != &hyper::status::StatusCode::Ok {
+            let status = response.status();
+            return Err(Error::status(status.to_u16(), status.to_string()));
+        }
+        let mut name = String::new();
+        response
+            .read_to_string(&mut name)
+            .map_err(|err| Error::transport(err.to_string()))?;
+
+        // Anonymous requests are answered with an empty name
+        match name.trim() {
+            "" => Err(Error::config("The token was not accepted")),
+            name => Ok(name.to_string()),
+        }
+    }
+
+    fn get_comments(&self, pr_id: i32) -> Result<Vec<Comment>, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header();
         let url = format!(
             "{}/rest/api/latest/projects/{}/repos/{}/pull-requests/{}/activities?fromType=COMMENT",
             self.credentials.base_url, self.credentials.project_slug, self.credentials.repo_slug, pr_id
         );
 
-        let activities = rest::get::<PagedApi<Activity>>(&url, headers.headers)
-            .map_err(|err| format!("Error getting comments {}", err))?;
+        let activities = self.get_all_pages::<Activity>(&url, headers.headers)
+            .map_err(|err| err.context("Error getting comments"))?;
+        let username = self.username()?;
 
         Ok(activities
-            .values
             .iter()
-            .filter(|&activity| activity.comment.is_some() && activity.user.name == self.credentials.username)
+            .filter(|&activity| activity.comment.is_some() && activity.user.name == username)
             .map(|activity| {
                 // won't panic because of filter above
                 activity.comment.as_ref().unwrap().to_owned()
@@ -338,16 +586,16 @@ impl Bitbucket {
             .collect())
     }
 
-    fn post_comment(&self, pr_id: i32, text: &str) -> Result<Comment, String> {
+    fn post_comment(&self, pr_id: i32, text: &str) -> Result<Comment, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header()
             .add_content_type_json_header();
 
         let body = serde_json::to_string(&CommentSubmit {
             text: text.to_owned(),
-        }).map_err(|e| e.to_string())?;
+        }).map_err(|e| Error::decode(e.to_string()))?;
         let url = format!(
             "{}/rest/api/latest/projects/{}/repos/{}/pull-requests/{}/comments",
             self.credentials.base_url, self.credentials.project_slug, self.credentials.repo_slug, pr_id
@@ -358,53 +606,51 @@ impl Bitbucket {
             &body,
             headers.headers,
             &hyper::status::StatusCode::Created,
-        ).map_err(|err| format!("Error posting comment {}", err))?
+        ).map_err(|err| err.context("Error posting comment"))?
             .to_owned())
     }
 
-    fn edit_comment(&self, pr_id: i32, comment: &Comment, text: &str) -> Result<Comment, String> {
+    fn edit_comment(&self, pr_id: i32, comment_id: i32, version: i32, text: &str) -> Result<Comment, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header()
             .add_content_type_json_header();
 
         let body = serde_json::to_string(&CommentEdit {
             text: text.to_owned(),
-            version: comment.version,
-        }).map_err(|e| e.to_string())?;
+            version: version,
+        }).map_err(|e| Error::decode(e.to_string()))?;
         let url = format!(
             "{}/rest/api/latest/projects/{}/repos/{}/pull-requests/{}/comments/{}",
-            self.credentials.base_url, self.credentials.project_slug, self.credentials.repo_slug, pr_id, comment.id
+            self.credentials.base_url, self.credentials.project_slug, self.credentials.repo_slug, pr_id, comment_id
         );
 
         Ok(
             rest::put::<Comment>(&url, &body, headers.headers, &hyper::status::StatusCode::Ok)
-                .map_err(|err| format!("Error posting comment {}", err))?
+                .map_err(|err| err.context("Error posting comment"))?
                 .to_owned(),
         )
     }
 
-    fn post_build_status(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<Build, String> {
-        let bitbucket_build = Bitbucket::make_build(build);
-
+    fn post_build_status(&self, pr: &::PullRequest, bitbucket_build: &Build) -> Result<(), Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header()
             .add_content_type_json_header();
 
-        let body = serde_json::to_string(&bitbucket_build).map_err(|e| e.to_string())?;
+        let body = serde_json::to_string(bitbucket_build).map_err(|e| Error::decode(e.to_string()))?;
         let url = format!(
             "{}/rest/build-status/1.0/commits/{}",
             self.credentials.base_url, pr.from_commit
         );
 
         let response =
-            rest::post_raw(&url, &body, headers.headers).map_err(|err| format!("Error posting build {}", err))?;
+            rest::post_raw(&url, &body, headers.headers).map_err(|err| err.context("Error posting build"))?;
         match response.status() {
-            status if status == &hyper::status::StatusCode::NoContent => Ok(bitbucket_build),
-            e => Err(e.to_string()),
+            status if status == &hyper::status::StatusCode::NoContent => Ok(()),
+            status => Err(Error::status(status.to_u16(), status.to_string())),
         }
     }
 
@@ -430,6 +676,22 @@ impl Bitbucket {
             description: description.to_owned(),
         }
     }
+
+    fn make_verdict_build(pr: &::PullRequest, verdict: &::Verdict) -> Build {
+        let state = match *verdict {
+            ::Verdict::Pending => BuildState::INPROGRESS,
+            ::Verdict::Success => BuildState::SUCCESSFUL,
+            ::Verdict::Failure => BuildState::FAILED,
+        };
+
+        Build {
+            state: state,
+            key: VERDICT_KEY.to_string(),
+            name: "Every build".to_string(),
+            url: pr.web_url.to_owned(),
+            description: verdict.description().to_string(),
+        }
+    }
 }
 
 fn browse_url(base: &str, project_slug: &str, repo_slug: &str, reference: &str) -> String {
@@ -446,7 +708,12 @@ fn commit_url(base: &str, project_slug: &str, repo_slug: &str, commit: &str) ->
     )
 }
 
-fn make_queued_comment(build: &::BuildDetails, pr: &::PullRequest, config: &BitbucketCredentials) -> String {
+fn make_comment(
+    build: &::BuildDetails,
+    pr: &::PullRequest,
+    config: &BitbucketCredentials,
+    progress: Progress,
+) -> String {
     let reference_url = browse_url(
         &config.base_url,
         &config.project_slug,
@@ -459,72 +726,182 @@ fn make_queued_comment(build: &::BuildDetails, pr: &::PullRequest, config: &Bitb
         &config.repo_slug,
         &pr.from_commit,
     );
-    format!(
-        "⏳ [Build]({build_url}) for [{reference}]({reference_url}) ([{commit}]({commit_url})) queued",
-        build_url = build.web_url,
-        reference = pr.from_ref,
-        reference_url = reference_url,
-        commit = pr.from_commit,
-        commit_url = commit_url
-    )
+    let reference = format!("[{}]({})", pr.from_ref, reference_url);
+    comment::make_comment(build, pr, &reference, &commit_url, progress)
 }
 
-fn make_success_comment(build: &::BuildDetails, pr: &::PullRequest, config: &BitbucketCredentials) -> String {
-    let reference_url = browse_url(
-        &config.base_url,
-        &config.project_slug,
-        &config.repo_slug,
-        &pr.from_ref,
-    );
-    let commit_url = commit_url(
-        &config.base_url,
-        &config.project_slug,
-        &config.repo_slug,
-        &pr.from_commit,
-    );
-    let status_text = build
-        .status_text
-        .as_ref()
-        .map_or_else(|| "".to_string(), |s| s.to_string());
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use hyper::status::StatusCode;
+    use serde_json::Value;
+
+    use super::{Bitbucket, BitbucketCredentials, PagedApi, PagedIterator};
+    use error::Error;
+    use fanout::{Fanout, Message};
+    use test_server::{StubResponse, StubServer};
+
+    fn page(values: Vec<i32>, start: i32, next_page_start: Option<i32>) -> PagedApi<i32> {
+        PagedApi {
+            size: values.len() as i32,
+            limit: 2,
+            isLastPage: next_page_start.is_none(),
+            values: values,
+            start: start,
+            nextPageStart: next_page_start,
+        }
+    }
 
-    format!(
-        "✔️ [Build]({build_url}) for [{reference}]({reference_url}) ([{commit}]({commit_url})) \
-         is **successful**: {build_message}",
-        build_url = build.web_url,
-        reference = pr.from_ref,
-        reference_url = reference_url,
-        commit = pr.from_commit,
-        commit_url = commit_url,
-        build_message = status_text
-    )
-}
+    #[test]
+    fn paged_iterator_walks_every_page() {
+        let requested = RefCell::new(Vec::<String>::new());
+        let pages = PagedIterator::new("https://www.example.com/api?fromType=COMMENT", 2, |url| {
+            requested.borrow_mut().push(url.to_string());
+            match requested.borrow().len() {
+                1 => Ok(page(vec![1, 2], 0, Some(2))),
+                2 => Ok(page(vec![3, 4], 2, Some(4))),
+                _ => Ok(page(vec![5], 4, None)),
+            }
+        });
+
+        let values: Vec<i32> = pages.map(|page| page.unwrap()).flat_map(|page| page).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5], values);
+        assert_eq!(
+            vec![
+                "https://www.example.com/api?fromType=COMMENT&start=0&limit=2".to_string(),
+                "https://www.example.com/api?fromType=COMMENT&start=2&limit=2".to_string(),
+                "https://www.example.com/api?fromType=COMMENT&start=4&limit=2".to_string(),
+            ],
+            *requested.borrow()
+        );
+    }
 
-fn make_failure_comment(build: &::BuildDetails, pr: &::PullRequest, config: &BitbucketCredentials) -> String {
-    let reference_url = browse_url(
-        &config.base_url,
-        &config.project_slug,
-        &config.repo_slug,
-        &pr.from_ref,
-    );
-    let commit_url = commit_url(
-        &config.base_url,
-        &config.project_slug,
-        &config.repo_slug,
-        &pr.from_commit,
-    );
-    let status_text = build
-        .status_text
-        .as_ref()
-        .map_or_else(|| "".to_string(), |s| s.to_string());
+    #[test]
+    fn paged_iterator_falls_back_to_page_size_without_next_page_start() {
+        let requested = RefCell::new(Vec::<String>::new());
+        let pages = PagedIterator::new("https://www.example.com/api", 2, |url| {
+            requested.borrow_mut().push(url.to_string());
+            match requested.borrow().len() {
+                1 => {
+                    let mut first = page(vec![1, 2], 0, None);
+                    first.isLastPage = false;
+                    Ok(first)
+                }
+                _ => Ok(page(vec![3], 2, None)),
+            }
+        });
 
-    format!(
-        "❌ [Build]({build_url}) for [{reference}]({reference_url}) ([{commit}]({commit_url})) \
-         has **failed**: {build_message}",
-        build_url = build.web_url,
-        reference = pr.from_ref,
-        reference_url = reference_url,
-        commit = pr.from_commit,
-        commit_url = commit_url,
-        build_message = status_text
-    )
+        let values: Vec<i32> = pages.map(|page| page.unwrap()).flat_map(|page| page).collect();
+        assert_eq!(vec![1, 2, 3], values);
+        assert_eq!(
+            "https://www.example.com/api?start=2&limit=2",
+            requested.borrow()[1]
+        );
+    }
+
+    #[test]
+    fn paged_iterator_stops_when_the_next_page_does_not_advance() {
+        let requested = RefCell::new(0);
+        let pages = PagedIterator::new("https://www.example.com/api", 2, |_url| {
+            *requested.borrow_mut() += 1;
+            let mut empty = page(vec![], 0, None);
+            empty.isLastPage = false;
+            Ok(empty)
+        });
+
+        let values: Vec<i32> = pages.map(|page| page.unwrap()).flat_map(|page| page).collect();
+        assert!(values.is_empty());
+        assert_eq!(1, *requested.borrow());
+    }
+
+    #[test]
+    fn paged_iterator_stops_after_error() {
+        let pages = PagedIterator::new("https://www.example.com/api", 2, |_url| -> Result<PagedApi<i32>, Error> {
+            Err(Error::from("foobar"))
+        });
+
+        let results: Vec<Result<Vec<i32>, Error>> = pages.collect();
+        assert_eq!(vec![Err(Error::from("foobar"))], results);
+    }
+
+    #[test]
+    fn page_size_must_be_positive() {
+        let credentials = BitbucketCredentials {
+            username: Some("username".to_string()),
+            password: Some("password".to_string()),
+            token: None,
+            base_url: "https://www.example.com/bb".to_string(),
+            project_slug: "foo".to_string(),
+            repo_slug: "bar".to_string(),
+            page_size: Some(0),
+        };
+        assert!(Bitbucket::new(&credentials, &Fanout::<Message>::new()).is_err());
+    }
+
+    fn comment_activity(id: i32, author: &str) -> Value {
+        let user = json!({
+            "name": author,
+            "emailAddress": format!("{}@example.com", author),
+            "id": id,
+            "displayName": author,
+            "active": true,
+            "slug": author,
+            "links": {},
+        });
+        json!({
+            "id": id,
+            "createdDate": 0,
+            "user": user,
+            "action": "COMMENTED",
+            "commentAction": "ADDED",
+            "comment": {
+                "id": id,
+                "version": 0,
+                "text": format!("Comment by {}", author),
+                "author": user,
+                "createdDate": 0,
+                "updatedDate": 0,
+            },
+        })
+    }
+
+    #[test]
+    fn comments_are_filtered_by_the_user_of_the_token() {
+        let server = StubServer::new(|request| {
+            if request.path.starts_with("/plugins/servlet/applinks/whoami") {
+                StubResponse::json(StatusCode::Ok, "pr_demon")
+            } else {
+                let page = json!({
+                    "size": 2,
+                    "limit": 25,
+                    "isLastPage": true,
+                    "values": [comment_activity(1, "someone"), comment_activity(2, "pr_demon")],
+                    "start": 0,
+                });
+                StubResponse::json(StatusCode::Ok, &page.to_string())
+            }
+        });
+        let credentials = BitbucketCredentials {
+            username: None,
+            password: None,
+            token: Some("token".to_string()),
+            base_url: server.url(),
+            project_slug: "foo".to_string(),
+            repo_slug: "bar".to_string(),
+            page_size: None,
+        };
+        let bitbucket = Bitbucket::new(&credentials, &Fanout::<Message>::new()).unwrap();
+
+        let comments = bitbucket.get_comments(1).unwrap();
+        assert_eq!(vec![2], comments.iter().map(|comment| comment.id).collect::<Vec<_>>());
+        // The user is only looked up once
+        assert_eq!(vec![2], bitbucket.get_comments(1).unwrap().iter().map(|comment| comment.id).collect::<Vec<_>>());
+
+        let requests = server.requests();
+        assert_eq!(3, requests.len());
+        for request in requests {
+            assert_eq!(Some("Bearer token".to_string()), request.header("Authorization"));
+        }
+    }
 }
@@ -0,0 +1,129 @@
+//! Comments describing the builds of pull requests, shared by the repositories that post them
+
+/// What a comment says about a build
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Progress {
+    Queued,
+    Running,
+    Success,
+    Failure,
+}
+
+/// Marker of the comments posted before each build configuration had its own comment
+const LEGACY_BUILD_MARKER: &'static str = "[Build](";
+
+/// Identifies the comment of a build configuration, as each configuration has its own comment for a commit
+fn build_marker(build: &::BuildDetails) -> String {
+    format!("[Build `{}`]", build.build_id)
+}
+
+/// Whether `text` is the comment about `build` for the commit of `pr`, including comments posted before each build
+/// configuration had its own comment, so that they are edited rather than posted again
+pub fn is_build_comment(text: &str, pr: &::PullRequest, build: &::BuildDetails) -> bool {
+    text.contains(&pr.from_commit) && (text.contains(&build_marker(build)) || text.contains(LEGACY_BUILD_MARKER))
+}
+
+/// Describe `build` of `pr`, with `reference` being the markdown for the source branch of `pr`
+pub fn make_comment(
+    build: &::BuildDetails,
+    pr: &::PullRequest,
+    reference: &str,
+    commit_url: &str,
+    progress: Progress,
+) -> String {
+    let status_text = build
+        .status_text
+        .as_ref()
+        .map_or_else(|| "".to_string(), |s| s.to_string());
+    let (icon, outcome) = match progress {
+        Progress::Queued => ("⏳", "queued".to_string()),
+        Progress::Running => ("⏳", "is **running**".to_string()),
+        Progress::Success => ("✔️", format!("is **successful**: {}", status_text)),
+        Progress::Failure => ("❌", format!("has **failed**: {}", status_text)),
+    };
+
+    format!(
+        "{icon} {build_marker}({build_url}) for {reference} ([{commit}]({commit_url})) {outcome}",
+        icon = icon,
+        build_marker = build_marker(build),
+        build_url = build.web_url,
+        reference = reference,
+        commit = pr.from_commit,
+        commit_url = commit_url,
+        outcome = outcome
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_build_comment, make_comment, Progress};
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 111,
+            web_url: "https://www.example.com/pr/111".to_string(),
+            from_ref: "refs/heads/branch".to_string(),
+            from_commit: "abc123".to_string(),
+            to_ref: "refs/heads/master".to_string(),
+            to_commit: "def456".to_string(),
+            title: "Some PR".to_string(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_string(),
+                email: "aaron@xiao.ming".to_string(),
+            },
+        }
+    }
+
+    fn build() -> ::BuildDetails {
+        ::BuildDetails {
+            id: 1234,
+            build_id: "foobar".to_string(),
+            web_url: "https://ci.example.com/build/1234".to_string(),
+            commit: Some("abc123".to_string()),
+            branch_name: "pull/111/merge".to_string(),
+            state: ::BuildState::Finished,
+            status: ::BuildStatus::Failure,
+            status_text: Some("Tests failed: 1".to_string()),
+        }
+    }
+
+    #[test]
+    fn comments_describe_the_progress_of_the_build() {
+        let comment = |progress| {
+            make_comment(
+                &build(),
+                &pull_request(),
+                "`refs/heads/branch`",
+                "https://www.example.com/commits/abc123",
+                progress,
+            )
+        };
+
+        assert_eq!(
+            "⏳ [Build `foobar`](https://ci.example.com/build/1234) for `refs/heads/branch` \
+             ([abc123](https://www.example.com/commits/abc123)) queued",
+            comment(Progress::Queued)
+        );
+        assert!(comment(Progress::Running).ends_with(") is **running**"));
+        assert!(comment(Progress::Success).ends_with(") is **successful**: Tests failed: 1"));
+        assert!(comment(Progress::Failure).ends_with(") has **failed**: Tests failed: 1"));
+    }
+
+    #[test]
+    fn build_comments_are_recognised_by_commit_and_build_configuration() {
+        let comment = make_comment(&build(), &pull_request(), "", "", Progress::Queued);
+        assert!(is_build_comment(&comment, &pull_request(), &build()));
+
+        let mut other = build();
+        other.build_id = "other".to_string();
+        assert!(!is_build_comment(&comment, &pull_request(), &other));
+
+        let mut outdated = pull_request();
+        outdated.from_commit = "fff000".to_string();
+        assert!(!is_build_comment(&comment, &outdated, &build()));
+
+        let legacy = "⏳ [Build](https://ci.example.com/build/1) for `refs/heads/branch` ([abc123](url)) queued";
+        assert!(is_build_comment(legacy, &pull_request(), &build()));
+        assert!(is_build_comment(legacy, &pull_request(), &other));
+    }
+}
@@ -0,0 +1,280 @@
+//! Dry runs read from the repository and continuous integrators, but log and broadcast what they would have written
+use serde::Serialize;
+
+use error::Error;
+use fanout::{Fanout, Message, OpCode};
+
+fn broadcast<T>(broadcaster: &Fanout<Message>, opcode: &str, payload: &T) -> Result<(), Error>
+where
+    T: Serialize,
+{
+    let opcode = OpCode::Custom {
+        payload: format!("DryRun::{}", opcode),
+    };
+    broadcaster.broadcast(Message::new(opcode, payload)?);
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct Notification<'a> {
+    pr: &'a ::PullRequest,
+    build: &'a ::BuildDetails,
+}
+
+#[derive(Serialize, Debug)]
+struct VerdictNotification<'a> {
+    pr: &'a ::PullRequest,
+    verdict: &'a ::Verdict,
+}
+
+/// Lists pull requests, but only pretends to notify them of builds
+pub struct DryRunRepository {
+    inner: Box<::Repository>,
+    broadcaster: Fanout<Message>,
+}
+
+impl DryRunRepository {
+    pub fn new(inner: Box<::Repository>, broadcaster: &Fanout<Message>) -> DryRunRepository {
+        DryRunRepository {
+            inner: inner,
+            broadcaster: broadcaster.to_owned(),
+        }
+    }
+
+    fn notify(&self, action: &str, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        info!(
+            "Dry run: would notify PR #{} that build {} is {:?} {:?} ({})",
+            pr.id, build.id, build.state, build.status, action
+        );
+        broadcast(
+            &self.broadcaster,
+            &format!("Repository::{}", action),
+            &Notification { pr: pr, build: build },
+        )
+    }
+}
+
+impl ::Repository for DryRunRepository {
+    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+        self.inner.get_pr_list()
+    }
+
+    fn get_pr(&self, id: i32) -> Result<Option<::PullRequest>, Error> {
+        self.inner.get_pr(id)
+    }
+
+    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify("BuildQueued", pr, build)
+    }
+
+    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify("BuildRunning", pr, build)
+    }
+
+    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify("BuildSuccess", pr, build)
+    }
+
+    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify("BuildFailure", pr, build)
+    }
+
+    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify("PostBuild", pr, build)
+    }
+
+    fn post_verdict(&self, pr: &::PullRequest, verdict: &::Verdict) -> Result<(), Error> {
+        info!("Dry run: would post verdict {:?} of PR #{}", verdict, pr.id);
+        broadcast(
+            &self.broadcaster,
+            "Repository::PostVerdict",
+            &VerdictNotification {
+                pr: pr,
+                verdict: verdict,
+            },
+        )
+    }
+}
+
+/// Retrieves builds, but only pretends to queue them or to refresh the VCS
+pub struct DryRunIntegrator {
+    inner: Box<::ContinuousIntegrator>,
+    /// Build configuration of `inner`, as reported in `BuildDetails::build_id`
+    build_id: String,
+    broadcaster: Fanout<Message>,
+}
+
+impl DryRunIntegrator {
+    pub fn new(inner: Box<::ContinuousIntegrator>, build_id: &str, broadcaster: &Fanout<Message>) -> DryRunIntegrator {
+        DryRunIntegrator {
+            inner: inner,
+            build_id: build_id.to_string(),
+            broadcaster: broadcaster.to_owned(),
+        }
+    }
+}
+
+impl ::ContinuousIntegrator for DryRunIntegrator {
+    fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
+        self.inner.get_build_list(pr)
+    }
+
+    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+        self.inner.get_build(build_id)
+    }
+
+    /// Pretend that a build was queued. The build does not exist, so its ID is 0.
+    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, Error> {
+        info!(
+            "Dry run: would queue a build of {} for {} of PR #{}",
+            self.build_id, pr.from_commit, pr.id
+        );
+        broadcast(&self.broadcaster, "ContinuousIntegrator::QueueBuild", pr)?;
+        Ok(::BuildDetails {
+            id: 0,
+            build_id: self.build_id.to_string(),
+            web_url: pr.web_url.to_string(),
+            commit: Some(pr.from_commit.to_string()),
+            branch_name: pr.from_ref.to_string(),
+            state: ::BuildState::Queued,
+            status: ::BuildStatus::Unknown,
+            status_text: Some("Dry run".to_string()),
+        })
+    }
+
+    fn refresh_vcs(&self) -> Result<(), Error> {
+        info!("Dry run: would ask {} to refresh its VCS", self.build_id);
+        broadcast(&self.broadcaster, "ContinuousIntegrator::RefreshVcs", &self.build_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{DryRunIntegrator, DryRunRepository};
+    use error::Error;
+    use fanout::{Fanout, Message, OpCode};
+    use {ContinuousIntegrator, Repository};
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 111,
+            web_url: "http://www.foobar.com/pr/111".to_owned(),
+            from_ref: "refs/heads/branch_name".to_owned(),
+            from_commit: "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_owned(),
+            to_ref: "refs/heads/master".to_owned(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_owned(),
+            title: "A very important PR".to_owned(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_owned(),
+                email: "aaron@xiao.ming".to_owned(),
+            },
+        }
+    }
+
+    /// Records every call that would write to the backend
+    #[derive(Clone)]
+    struct Recorder {
+        writes: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, write: &'static str) -> Result<(), Error> {
+            self.writes.lock().unwrap().push(write);
+            Ok(())
+        }
+    }
+
+    impl Repository for Recorder {
+        fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+            Ok(vec![pull_request()])
+        }
+        fn build_queued(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.record("build_queued")
+        }
+        fn build_running(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.record("build_running")
+        }
+        fn build_success(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.record("build_success")
+        }
+        fn build_failure(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.record("build_failure")
+        }
+        fn post_build(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.record("post_build")
+        }
+    }
+
+    impl ContinuousIntegrator for Recorder {
+        fn build_id(&self) -> &str {
+            "foobar"
+        }
+        fn get_build_list(&self, _: &::PullRequest) -> Result<Vec<::Build>, Error> {
+            Ok(vec![])
+        }
+        fn get_build(&self, _: i32) -> Result<::BuildDetails, Error> {
+            Err(Error::not_found("Not found"))
+        }
+        fn queue_build(&self, _: &::PullRequest) -> Result<::BuildDetails, Error> {
+            self.record("queue_build")?;
+            Err("Not queued".into())
+        }
+        fn refresh_vcs(&self) -> Result<(), Error> {
+            self.record("refresh_vcs")
+        }
+    }
+
+    fn custom_payloads(messages: Vec<Message>) -> Vec<String> {
+        messages
+            .into_iter()
+            .filter_map(|message| match message.opcode {
+                OpCode::Custom { payload } => Some(payload),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dry_runs_read_but_do_not_write() {
+        let recorder = Recorder {
+            writes: Arc::new(Mutex::new(vec![])),
+        };
+        let mut fanout = Fanout::<Message>::new();
+        let subscriber = fanout.subscribe();
+        let pr = pull_request();
+
+        let repository = DryRunRepository::new(Box::new(recorder.clone()), &fanout);
+        let integrator = DryRunIntegrator::new(Box::new(recorder.clone()), "foobar", &fanout);
+
+        assert_eq!(vec![pr.clone()], repository.get_pr_list().unwrap());
+        assert_eq!(Vec::<::Build>::new(), integrator.get_build_list(&pr).unwrap());
+
+        let build = integrator.queue_build(&pr).unwrap();
+        assert_eq!("foobar", build.build_id);
+        assert_eq!(Some(pr.from_commit.to_string()), build.commit);
+        assert_eq!(::BuildState::Queued, build.state);
+        integrator.refresh_vcs().unwrap();
+        repository.build_queued(&pr, &build).unwrap();
+        repository.post_build(&pr, &build).unwrap();
+
+        assert!(recorder.writes.lock().unwrap().is_empty());
+        drop(repository);
+        drop(integrator);
+        fanout.join().unwrap();
+        assert_eq!(
+            vec![
+                "DryRun::ContinuousIntegrator::QueueBuild",
+                "DryRun::ContinuousIntegrator::RefreshVcs",
+                "DryRun::Repository::BuildQueued",
+                "DryRun::Repository::PostBuild",
+            ],
+            custom_payloads(subscriber.iter().collect())
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Errors of the repositories, continuous integrators and PR transformers, classified by what should be done about them
+use std::error;
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ErrorKind {
+    /// A backend responded with an unexpected HTTP status
+    Status(u16),
+    /// A backend could not be reached, or the connection failed
+    Transport,
+    /// A response could not be decoded
+    Decode,
+    /// A git operation failed, e.g. because of a merge conflict
+    Git,
+    /// The configuration does not work with a backend
+    Config,
+    /// What was looked for does not exist
+    NotFound,
+    Other,
+}
+
+/// What the daemon should do about an error, from the least to the most severe
+#[derive(Serialize, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The error is transient, so handling the pull request again at the next run should succeed
+    Retry,
+    /// The error only affects the pull request, which is left until the next run
+    Skip,
+    /// The daemon cannot work until someone intervenes, e.g. because credentials were revoked
+    Alert,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new<S>(kind: ErrorKind, message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error {
+            kind: kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn status<S>(status: u16, message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::new(ErrorKind::Status(status), message)
+    }
+
+    pub fn transport<S>(message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::new(ErrorKind::Transport, message)
+    }
+
+    pub fn decode<S>(message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::new(ErrorKind::Decode, message)
+    }
+
+    pub fn git<S>(message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::new(ErrorKind::Git, message)
+    }
+
+    pub fn config<S>(message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::new(ErrorKind::Config, message)
+    }
+
+    pub fn not_found<S>(message: S) -> Error
+    where
+        S: Into<String>,
+    {
+        Error::new(ErrorKind::NotFound, message)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Describe what was being done when the error happened, keeping its kind
+    pub fn context<S>(self, context: S) -> Error
+    where
+        S: fmt::Display,
+    {
+        Error {
+            kind: self.kind,
+            message: format!("{}: {}", context, self.message),
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        match self.kind {
+            ErrorKind::Status(401) | ErrorKind::Status(403) => Action::Alert,
+            ErrorKind::Status(408) | ErrorKind::Status(429) => Action::Retry,
+            ErrorKind::Status(status) if status >= 500 => Action::Retry,
+            ErrorKind::Transport => Action::Retry,
+            ErrorKind::Decode | ErrorKind::Config => Action::Alert,
+            ErrorKind::Status(_) | ErrorKind::Git | ErrorKind::NotFound | ErrorKind::Other => Action::Skip,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::new(ErrorKind::Other, message)
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(message: &'a str) -> Error {
+        Error::new(ErrorKind::Other, message)
+    }
+}
+
+/// How errors are described in broadcasts
+#[derive(Serialize)]
+struct Described<'a> {
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    message: &'a str,
+    action: Action,
+}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (kind, status) = match self.kind {
+            ErrorKind::Status(status) => ("status", Some(status)),
+            ErrorKind::Transport => ("transport", None),
+            ErrorKind::Decode => ("decode", None),
+            ErrorKind::Git => ("git", None),
+            ErrorKind::Config => ("config", None),
+            ErrorKind::NotFound => ("not_found", None),
+            ErrorKind::Other => ("other", None),
+        };
+        Described {
+            kind: kind,
+            status: status,
+            message: &self.message,
+            action: self.action(),
+        }.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::{Action, Error, ErrorKind};
+
+    #[test]
+    fn errors_are_classified_by_what_should_be_done_about_them() {
+        assert_eq!(Action::Alert, Error::status(401, "Unauthorized").action());
+        assert_eq!(Action::Retry, Error::status(503, "Service Unavailable").action());
+        assert_eq!(Action::Skip, Error::status(409, "Conflict").action());
+        assert_eq!(Action::Retry, Error::transport("Connection refused").action());
+        assert_eq!(Action::Alert, Error::decode("Unexpected field").action());
+        assert_eq!(Action::Skip, Error::git("Merge conflict").action());
+        assert_eq!(Action::Skip, Error::from("Something else").action());
+    }
+
+    #[test]
+    fn context_keeps_the_kind_of_errors() {
+        let error = Error::status(404, "Not Found").context("Error getting comments");
+        assert_eq!(ErrorKind::Status(404), error.kind());
+        assert_eq!("Error getting comments: Not Found", error.to_string());
+    }
+
+    #[test]
+    fn errors_are_described_in_broadcasts() {
+        let described = serde_json::to_value(&Error::status(401, "Unauthorized")).unwrap();
+        assert_eq!(
+            json!({"kind": "status", "status": 401, "message": "Unauthorized", "action": "alert"}),
+            described
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::thread::spawn;
+use std::thread::{spawn, JoinHandle};
 use std::marker::Send;
 
 use serde::Serialize;
@@ -22,6 +22,9 @@ pub enum OpCode {
 pub struct Message {
     pub opcode: OpCode,
     pub payload: Value,
+    /// Name of the project the message is about, if several projects are configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
 }
 
 impl Message {
@@ -33,6 +36,7 @@ impl Message {
         Ok(Message {
             opcode: opcode,
             payload: encoded,
+            project: None,
         })
     }
 }
@@ -42,8 +46,10 @@ pub struct Fanout<T>
 where
     T: 'static + Send + Sync + Clone,
 {
-    broadcast_tx: Sender<T>,
+    // Behind a mutex so that broadcasts can be made from worker threads
+    broadcast_tx: Arc<Mutex<Sender<T>>>,
     pub subscribers: Arc<Mutex<Vec<Sender<T>>>>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl<T> Fanout<T>
@@ -55,7 +61,7 @@ where
         let subscribers = Arc::new(Mutex::new(Vec::<Sender<T>>::new()));
 
         let cloned_subscribers = subscribers.clone();
-        spawn(move || {
+        let thread = spawn(move || {
             for message in broadcast_rx.iter() {
                 let subscribers_mutex = cloned_subscribers.lock();
                 if let Err(err) = subscribers_mutex {
@@ -77,8 +83,23 @@ where
         });
 
         Fanout {
-            broadcast_tx: broadcast_tx,
+            broadcast_tx: Arc::new(Mutex::new(broadcast_tx)),
             subscribers: subscribers,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        }
+    }
+
+    /// Wait for every message broadcast so far to be delivered, after which the subscribers are disconnected.
+    ///
+    /// Blocks until every other clone of this `Fanout` has been dropped.
+    pub fn join(self) -> Result<(), String> {
+        let thread = self.thread.lock().map_err(|err| err.to_string())?.take();
+        drop(self);
+        match thread {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| "Fanout thread panicked".to_string()),
+            None => Ok(()),
         }
     }
 
@@ -89,7 +110,8 @@ where
     }
 
     fn _broadcast(&self, message: T) -> Result<(), String> {
-        if let Err(err) = self.broadcast_tx.send(message) {
+        let broadcast_tx = self.broadcast_tx.lock().map_err(|err| err.to_string())?;
+        if let Err(err) = broadcast_tx.send(message) {
             Err(format!("Broadcaster has been deallocated {}", err))?;
         }
         Ok(())
@@ -102,6 +124,23 @@ where
     }
 }
 
+impl Fanout<Message> {
+    /// A fanout that tags its messages with `project` before broadcasting them through this fanout
+    pub fn tagged(&self, project: &str) -> Fanout<Message> {
+        let mut tagged = Fanout::new();
+        let messages = tagged.subscribe();
+        let fanout = self.clone();
+        let project = project.to_string();
+        spawn(move || {
+            for mut message in messages.iter() {
+                message.project = Some(project.to_string());
+                fanout.broadcast(message);
+            }
+        });
+        tagged
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use timebomb::timeout_ms;
@@ -179,4 +218,23 @@ mod tests {
 
         assert_eq!(fanout.subscribers.lock().unwrap().len(), 1);
     }
+
+    #[test]
+    fn tagged_fanouts_tag_messages_with_their_project() {
+        let mut fanout = Fanout::<Message>::new();
+        let subscriber = fanout.subscribe();
+        let tagged = fanout.tagged("foobar");
+
+        let message = Message::new(OpCode::OpenPullRequest, &test_payload()).unwrap();
+        tagged.broadcast(message.clone());
+
+        timeout_ms(
+            move || {
+                let received = subscriber.recv().unwrap();
+                assert_eq!(Some("foobar".to_string()), received.project);
+                assert_eq!(message.payload, received.payload);
+            },
+            TIMEOUT,
+        );
+    }
 }
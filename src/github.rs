@@ -0,0 +1,577 @@
+use std::vec::Vec;
+
+use hyper;
+use reqwest;
+use serde::Serialize;
+use serde_json;
+use serde_json::map::Map;
+
+use comment::{self, Progress};
+use error::{Error, ErrorKind};
+use fanout;
+use rest;
+
+const DEFAULT_API_URL: &'static str = "https://api.github.com";
+const DEFAULT_STATUS_CONTEXT: &'static str = "pr_demon";
+/// Maximum number of items GitHub will return per page
+const PAGE_SIZE: usize = 100;
+/// GitHub rejects commit status descriptions longer than this
+const MAX_DESCRIPTION_LENGTH: usize = 140;
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+struct PullRequest {
+    number: i32,
+    html_url: String,
+    title: String,
+    state: String,
+    head: GitReference,
+    base: GitReference,
+    user: User,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+struct GitReference {
+    #[serde(rename = "ref")]
+    reference: String,
+    sha: String,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct User {
+    login: String,
+    id: i64,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct Comment {
+    id: i64,
+    body: String,
+    user: User,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct CommentSubmit {
+    body: String,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct Status {
+    state: StatusState,
+    target_url: String,
+    description: String,
+    context: String,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[allow(non_camel_case_types)]
+enum StatusState {
+    pending,
+    success,
+    failure,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct GithubCredentials {
+    pub username: String,
+    /// Personal access token for `username`
+    pub token: String,
+    /// Base URL for the GitHub API. Defaults to `https://api.github.com`.
+    /// For GitHub Enterprise, this is usually `https://hostname/api/v3`.
+    pub api_url: Option<String>,
+    pub owner: String,
+    pub repo: String,
+    /// Context for the commit statuses posted. Defaults to `pr_demon`.
+    pub status_context: Option<String>,
+}
+
+pub struct Github {
+    pub credentials: GithubCredentials,
+    broadcaster: fanout::Fanout<fanout::Message>,
+}
+
+impl ::UsernameAndPassword for Github {
+    fn username(&self) -> &String {
+        &self.credentials.username
+    }
+
+    fn password(&self) -> &String {
+        &self.credentials.token
+    }
+}
+
+fn to_pull_request(pr: &PullRequest) -> ::PullRequest {
+    ::PullRequest {
+        id: pr.number,
+        web_url: pr.html_url.to_string(),
+        from_ref: format!("refs/heads/{}", pr.head.reference),
+        from_commit: pr.head.sha.to_string(),
+        to_ref: format!("refs/heads/{}", pr.base.reference),
+        to_commit: pr.base.sha.to_string(),
+        title: pr.title.to_string(),
+        author: ::User {
+            name: pr.user.login.to_string(),
+            // GitHub does not expose the author's email address on pull requests
+            email: "".to_string(),
+        },
+    }
+}
+
+impl ::Repository for Github {
+    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+        let url = format!("{}/pulls?state=open", self.repository_url());
+        let prs = rest::get_all_pages::<PullRequest>(&url, PAGE_SIZE, self.headers())
+            .map_err(|err| err.context("Error getting list of Pull Requests"))?;
+
+        Ok(prs.iter().map(to_pull_request).collect())
+    }
+
+    fn get_pr(&self, id: i32) -> Result<Option<::PullRequest>, Error> {
+        let url = format!("{}/pulls/{}", self.repository_url(), id);
+        match rest::get::<PullRequest>(&url, self.headers()) {
+            Ok(ref pr) if pr.state == "open" => Ok(Some(to_pull_request(pr))),
+            Ok(_) => Ok(None),
+            Err(ref err) if err.kind() == ErrorKind::Status(404) => Ok(None),
+            Err(err) => Err(err.context(format!("Error getting Pull Request #{}", id))),
+        }
+    }
+
+    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Queued)
+            .map_err(|err| err.context("Error submitting comment"))?;
+        Ok(())
+    }
+
+    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Running)
+            .map_err(|err| err.context("Error submitting comment"))?;
+        Ok(())
+    }
+
+    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Success)
+            .map_err(|err| err.context("Error submitting comment"))?;
+        Ok(())
+    }
+
+    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_comment(pr, build, Progress::Failure)
+            .map_err(|err| err.context("Error submitting comment"))?;
+        Ok(())
+    }
+
+    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.post_status(pr, &self.make_status(build))?;
+        Ok(())
+    }
+
+    fn post_verdict(&self, pr: &::PullRequest, verdict: &::Verdict) -> Result<(), Error> {
+        self.post_status(pr, &self.make_verdict_status(pr, verdict))?;
+        Ok(())
+    }
+}
+
+impl Github {
+    pub fn new(credentials: &GithubCredentials, broadcaster: &fanout::Fanout<fanout::Message>) -> Github {
+        Github {
+            credentials: credentials.to_owned(),
+            broadcaster: broadcaster.to_owned(),
+        }
+    }
+
+    fn repository_url(&self) -> String {
+        let api_url = self.credentials
+            .api_url
+            .as_ref()
+            .map_or(DEFAULT_API_URL, |s| s.as_str())
+            .trim_right_matches('/');
+        format!(
+            "{}/repos/{}/{}",
+            api_url, self.credentials.owner, self.credentials.repo
+        )
+    }
+
+    fn headers(&self) -> reqwest::header::Headers {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_accept_json_header()
+            .add_content_type_json_header();
+        headers.headers
+    }
+
+    fn broadcast<T>(&self, opcode: &str, payload: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let opcode = fanout::OpCode::Custom {
+            payload: format!("GitHub::{}", opcode).to_owned(),
+        };
+        let message = fanout::Message::new(opcode, payload)?;
+        self.broadcaster.broadcast(message);
+        Ok(())
+    }
+
+    fn matching_comments(comments: &[Comment], text: &str) -> Option<Comment> {
+        comments
+            .iter()
+            .find(|&comment| comment.body == text)
+            .cloned()
+    }
+
+    /// Find the first comment containing all of `substrs`
+    fn matching_build_comments(comments: &[Comment], pr: &::PullRequest, build: &::BuildDetails) -> Option<Comment> {
+        comments
+            .iter()
+            .find(|&comment| comment::is_build_comment(&comment.body, pr, build))
+            .cloned()
+    }
+
+    fn update_pr_build_status_comment(
+        &self,
+        pr: &::PullRequest,
+        build: &::BuildDetails,
+        progress: Progress,
+    ) -> Result<Comment, Error> {
+        let reference = format!("`{}`", pr.from_ref);
+        let commit_url = format!("{}/commits/{}", pr.web_url, pr.from_commit);
+        let text = comment::make_comment(build, pr, &reference, &commit_url, progress);
+
+        let mut event_payload = Map::new();
+        event_payload.insert(
+            "pr".to_string(),
+            serde_json::to_value(&pr).map_err(|e| Error::decode(e.to_string()))?,
+        );
+        event_payload.insert(
+            "build".to_string(),
+            serde_json::to_value(&build).map_err(|e| Error::decode(e.to_string()))?,
+        );
+
+        let (comment, opcode) = match self.get_comments(pr.id) {
+            Ok(ref comments) => {
+                match Github::matching_comments(comments, &text) {
+                    Some(comment) => (Ok(comment), "Existing"),
+                    None => {
+                        // Have to post or edit comment
+                        match Github::matching_build_comments(comments, pr, build) {
+                            Some(comment) => (self.edit_comment(&comment, &text), "Update"),
+                            None => (self.post_comment(pr.id, &text), "Post"),
+                        }
+                    }
+                }
+            }
+            Err(err) => (
+                Err(err.context("Error getting list of comments")),
+                "Error",
+            ),
+        };
+
+        if let Ok(ref comment) = comment {
+            event_payload.insert(
+                "comment".to_string(),
+                serde_json::to_value(&comment).map_err(|e| Error::decode(e.to_string()))?,
+            );
+        }
+
+        self.broadcast(&format!("Comment::{}", opcode), &event_payload)?;
+        comment
+    }
+
+    /// Comments on the pull request made by us
+    fn get_comments(&self, pr_id: i32) -> Result<Vec<Comment>, Error> {
+        // Pull request comments that are not on the diff are issue comments
+        let url = format!("{}/issues/{}/comments", self.repository_url(), pr_id);
+        let comments = rest::get_all_pages::<Comment>(&url, PAGE_SIZE, self.headers())
+            .map_err(|err| err.context("Error getting comments"))?;
+
+        Ok(comments
+            .into_iter()
+            .filter(|comment| comment.user.login == self.credentials.username)
+            .collect())
+    }
+
+    fn post_comment(&self, pr_id: i32, text: &str) -> Result<Comment, Error> {
+        let body = serde_json::to_string(&CommentSubmit {
+            body: text.to_owned(),
+        }).map_err(|e| Error::decode(e.to_string()))?;
+        let url = format!("{}/issues/{}/comments", self.repository_url(), pr_id);
+
+        rest::post::<Comment>(
+            &url,
+            &body,
+            self.headers(),
+            &hyper::status::StatusCode::Created,
+        ).map_err(|err| err.context("Error posting comment"))
+    }
+
+    fn edit_comment(&self, comment: &Comment, text: &str) -> Result<Comment, Error> {
+        let body = serde_json::to_string(&CommentSubmit {
+            body: text.to_owned(),
+        }).map_err(|e| Error::decode(e.to_string()))?;
+        let url = format!("{}/issues/comments/{}", self.repository_url(), comment.id);
+
+        rest::patch::<Comment>(&url, &body, self.headers(), &hyper::status::StatusCode::Ok)
+            .map_err(|err| err.context("Error editing comment"))
+    }
+
+    /// Post a commit status. The Checks API is not used because it is only available to GitHub Apps.
+    fn post_status(&self, pr: &::PullRequest, status: &Status) -> Result<Status, Error> {
+        let body = serde_json::to_string(status).map_err(|e| Error::decode(e.to_string()))?;
+        let url = format!("{}/statuses/{}", self.repository_url(), pr.from_commit);
+
+        rest::post::<Status>(
+            &url,
+            &body,
+            self.headers(),
+            &hyper::status::StatusCode::Created,
+        ).map_err(|err| err.context("Error posting build"))
+    }
+
+    fn make_status(&self, build: &::BuildDetails) -> Status {
+        let state = match build.state {
+            ::BuildState::Finished => match build.status {
+                ::BuildStatus::Success => StatusState::success,
+                _ => StatusState::failure,
+            },
+            _ => StatusState::pending,
+        };
+
+        let description = build
+            .status_text
+            .as_ref()
+            .map_or_else(|| "".to_string(), |s| s.chars().take(MAX_DESCRIPTION_LENGTH).collect());
+
+        Status {
+            state: state,
+            target_url: build.web_url.to_owned(),
+            description: description,
+            context: format!("{}/{}", self.status_context(), build.build_id),
+        }
+    }
+
+    /// The verdict has the status context itself, and every build configuration has a context under it
+    fn make_verdict_status(&self, pr: &::PullRequest, verdict: &::Verdict) -> Status {
+        let state = match *verdict {
+            ::Verdict::Pending => StatusState::pending,
+            ::Verdict::Success => StatusState::success,
+            ::Verdict::Failure => StatusState::failure,
+        };
+
+        Status {
+            state: state,
+            target_url: pr.web_url.to_owned(),
+            description: verdict.description().to_string(),
+            context: self.status_context().to_string(),
+        }
+    }
+
+    fn status_context(&self) -> &str {
+        self.credentials
+            .status_context
+            .as_ref()
+            .map_or(DEFAULT_STATUS_CONTEXT, |s| s.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+    use serde_json::{self, Value};
+
+    use fanout::{Fanout, Message};
+    use test_server::{StubResponse, StubServer};
+    use Repository;
+    use super::{Comment, Github, GithubCredentials, User};
+
+    const PULL_REQUESTS: &'static str = r#"[
+        {
+            "number": 42,
+            "html_url": "https://github.com/foo/bar/pull/42",
+            "title": "A very important PR",
+            "state": "open",
+            "head": { "ref": "feature", "sha": "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b" },
+            "base": { "ref": "master", "sha": "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c" },
+            "user": { "login": "aaron", "id": 1 }
+        }
+    ]"#;
+
+    fn credentials(server: &StubServer) -> GithubCredentials {
+        GithubCredentials {
+            username: "pr_demon".to_string(),
+            token: "token".to_string(),
+            api_url: Some(server.url()),
+            owner: "foo".to_string(),
+            repo: "bar".to_string(),
+            status_context: None,
+        }
+    }
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 42,
+            web_url: "https://github.com/foo/bar/pull/42".to_string(),
+            from_ref: "refs/heads/feature".to_string(),
+            from_commit: "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_string(),
+            to_ref: "refs/heads/master".to_string(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_string(),
+            title: "A very important PR".to_string(),
+            author: ::User {
+                name: "aaron".to_string(),
+                email: "".to_string(),
+            },
+        }
+    }
+
+    fn build() -> ::BuildDetails {
+        ::BuildDetails {
+            id: 1234,
+            build_id: "foobar".to_string(),
+            web_url: "https://ci.example.com/build/1234".to_string(),
+            commit: Some("363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_string()),
+            branch_name: "pull/42/merge".to_string(),
+            state: ::BuildState::Finished,
+            status: ::BuildStatus::Success,
+            status_text: Some("Tests passed: 42".to_string()),
+        }
+    }
+
+    fn comment(id: i64, login: &str, body: &str) -> String {
+        serde_json::to_string(&Comment {
+            id: id,
+            body: body.to_string(),
+            user: User {
+                login: login.to_string(),
+                id: 2,
+            },
+        }).unwrap()
+    }
+
+    #[test]
+    fn get_pr_list_maps_open_pull_requests() {
+        let server = StubServer::new(|_request| StubResponse::json(StatusCode::Ok, PULL_REQUESTS));
+        let github = Github::new(&credentials(&server), &Fanout::<Message>::new());
+
+        let prs = github.get_pr_list().unwrap();
+        assert_eq!(vec![pull_request()], prs);
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            "/repos/foo/bar/pulls?state=open&per_page=100&page=1",
+            requests[0].path
+        );
+        assert!(requests[0].header("Authorization").unwrap().starts_with("Basic "));
+    }
+
+    #[test]
+    fn get_pr_only_returns_open_pull_requests() {
+        let server = StubServer::new(|request| {
+            let pr = PULL_REQUESTS.trim().trim_left_matches('[').trim_right_matches(']');
+            match request.path.as_str() {
+                "/repos/foo/bar/pulls/42" => StubResponse::json(StatusCode::Ok, pr),
+                "/repos/foo/bar/pulls/43" => {
+                    StubResponse::json(StatusCode::Ok, &pr.replace(r#""open""#, r#""closed""#))
+                }
+                _ => StubResponse::json(StatusCode::NotFound, r#"{"message": "Not Found"}"#),
+            }
+        });
+        let github = Github::new(&credentials(&server), &Fanout::<Message>::new());
+
+        assert_eq!(Some(pull_request()), github.get_pr(42).unwrap());
+        assert_eq!(None, github.get_pr(43).unwrap());
+        assert_eq!(None, github.get_pr(44).unwrap());
+    }
+
+    #[test]
+    fn build_success_posts_new_comment() {
+        let server = StubServer::new(|request| match request.method {
+            Method::Get => {
+                let comments = format!("[{}]", comment(1, "someone_else", "Looks good"));
+                StubResponse::json(StatusCode::Ok, &comments)
+            }
+            _ => StubResponse::json(StatusCode::Created, &comment(2, "pr_demon", "")),
+        });
+        let github = Github::new(&credentials(&server), &Fanout::<Message>::new());
+
+        github.build_success(&pull_request(), &build()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(Method::Post, requests[1].method);
+        assert_eq!("/repos/foo/bar/issues/42/comments", requests[1].path);
+
+        let body: Value = serde_json::from_str(&requests[1].body).unwrap();
+        let text = body["body"].as_str().unwrap();
+        assert!(text.starts_with("✔️ [Build `foobar`](https://ci.example.com/build/1234)"));
+        assert!(text.contains("Tests passed: 42"));
+    }
+
+    #[test]
+    fn build_failure_edits_existing_comment_for_commit() {
+        let server = StubServer::new(|request| match request.method {
+            Method::Get => {
+                let existing = "⏳ [Build `foobar`] for 363c1dfda4cdf5a01c2d210e49942c8c8e7e898b queued";
+                let comments = format!("[{}]", comment(1, "pr_demon", existing));
+                StubResponse::json(StatusCode::Ok, &comments)
+            }
+            _ => StubResponse::json(StatusCode::Ok, &comment(1, "pr_demon", "")),
+        });
+        let github = Github::new(&credentials(&server), &Fanout::<Message>::new());
+
+        let mut build = build();
+        build.status = ::BuildStatus::Failure;
+        github.build_failure(&pull_request(), &build).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(Method::Patch, requests[1].method);
+        assert_eq!("/repos/foo/bar/issues/comments/1", requests[1].path);
+    }
+
+    #[test]
+    fn build_failure_leaves_comments_of_other_build_configurations() {
+        let server = StubServer::new(|request| match request.method {
+            Method::Get => {
+                let existing = "⏳ [Build `lint`] for 363c1dfda4cdf5a01c2d210e49942c8c8e7e898b queued";
+                let comments = format!("[{}]", comment(1, "pr_demon", existing));
+                StubResponse::json(StatusCode::Ok, &comments)
+            }
+            _ => StubResponse::json(StatusCode::Created, &comment(2, "pr_demon", "")),
+        });
+        let github = Github::new(&credentials(&server), &Fanout::<Message>::new());
+
+        let mut build = build();
+        build.status = ::BuildStatus::Failure;
+        github.build_failure(&pull_request(), &build).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(Method::Post, requests[1].method);
+        assert_eq!("/repos/foo/bar/issues/42/comments", requests[1].path);
+    }
+
+    #[test]
+    fn post_build_posts_commit_status() {
+        let server = StubServer::new(|request| {
+            StubResponse::json(StatusCode::Created, &request.body)
+        });
+        let github = Github::new(&credentials(&server), &Fanout::<Message>::new());
+
+        github.post_build(&pull_request(), &build()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            "/repos/foo/bar/statuses/363c1dfda4cdf5a01c2d210e49942c8c8e7e898b",
+            requests[0].path
+        );
+
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(Some("success"), body["state"].as_str());
+        assert_eq!(Some("pr_demon/foobar"), body["context"].as_str());
+        assert_eq!(
+            Some("https://ci.example.com/build/1234"),
+            body["target_url"].as_str()
+        );
+    }
+}
@@ -0,0 +1,681 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::vec::Vec;
+
+use hyper;
+use reqwest;
+use serde::Serialize;
+use serde_json;
+use serde_json::map::Map;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use comment::{self, Progress};
+use error::{Error, ErrorKind};
+use fanout;
+use rest;
+
+const DEFAULT_STATUS_NAME: &'static str = "pr_demon";
+/// Maximum number of items GitLab will return per page
+const PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+struct MergeRequest {
+    id: i32,
+    iid: i32,
+    project_id: i32,
+    source_project_id: i32,
+    title: String,
+    web_url: String,
+    state: String,
+    source_branch: String,
+    target_branch: String,
+    sha: String,
+    author: User,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct User {
+    id: i32,
+    name: String,
+    username: String,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+struct Branch {
+    name: String,
+    commit: Commit,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+struct Commit {
+    id: String,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct Note {
+    id: i32,
+    body: String,
+    author: User,
+    system: bool,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct NoteSubmit {
+    body: String,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+struct Status {
+    state: StatusState,
+    name: String,
+    target_url: String,
+    description: String,
+}
+
+#[derive(Deserialize, Serialize, Eq, PartialEq, Clone, Debug)]
+#[allow(non_camel_case_types)]
+enum StatusState {
+    pending,
+    running,
+    success,
+    failed,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct GitlabCredentials {
+    /// Username that owns `token`. Only notes made by this user will be edited.
+    pub username: String,
+    /// Personal access token with the `api` scope
+    pub token: String,
+    /// Base URL for GitLab, without the `/api/v4` suffix
+    pub base_url: String,
+    /// Numeric ID or full path (e.g. `group/project`) of the project
+    pub project: String,
+    /// Name of the commit statuses posted. Defaults to `pr_demon`.
+    pub status_name: Option<String>,
+}
+
+pub struct Gitlab {
+    pub credentials: GitlabCredentials,
+    broadcaster: fanout::Fanout<fanout::Message>,
+}
+
+/// Describe `mr`, with `to_commit` being the head of its target branch
+fn to_pull_request(mr: &MergeRequest, to_commit: String) -> ::PullRequest {
+    let from_ref = if mr.source_project_id == mr.project_id {
+        format!("refs/heads/{}", mr.source_branch)
+    } else {
+        // Branches from forks are only available in the target project under this reference
+        format!("refs/merge-requests/{}/head", mr.iid)
+    };
+
+    ::PullRequest {
+        id: mr.iid,
+        web_url: mr.web_url.to_string(),
+        from_ref: from_ref,
+        from_commit: mr.sha.to_string(),
+        to_ref: format!("refs/heads/{}", mr.target_branch),
+        to_commit: to_commit,
+        title: mr.title.to_string(),
+        author: ::User {
+            name: mr.author.name.to_string(),
+            // GitLab does not expose the author's email address on merge requests
+            email: "".to_string(),
+        },
+    }
+}
+
+impl ::Repository for Gitlab {
+    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+        let url = format!("{}/merge_requests?state=opened", self.project_url());
+        let mrs = rest::get_all_pages::<MergeRequest>(&url, PAGE_SIZE, self.headers())
+            .map_err(|err| err.context("Error getting list of Merge Requests"))?;
+
+        // Merge requests only tell us about the merge base, so we have to look up the heads of the target branches,
+        // once for every target branch
+        let mut target_commits = HashMap::<String, Result<String, Error>>::new();
+        let mut prs = vec![];
+        for mr in &mrs {
+            let target_commit = target_commits
+                .entry(mr.target_branch.to_string())
+                .or_insert_with(|| self.get_branch_commit(&mr.target_branch));
+            let to_commit = match *target_commit {
+                Ok(ref commit) => commit.to_string(),
+                Err(ref err) => {
+                    // Only the merge requests into this branch are affected
+                    warn!(
+                        "Skipping Merge Request !{}: Error getting target branch {}: {}",
+                        mr.iid, mr.target_branch, err
+                    );
+                    continue;
+                }
+            };
+
+            prs.push(to_pull_request(mr, to_commit));
+        }
+        Ok(prs)
+    }
+
+    fn get_pr(&self, id: i32) -> Result<Option<::PullRequest>, Error> {
+        let url = format!("{}/merge_requests/{}", self.project_url(), id);
+        let mr = match rest::get::<MergeRequest>(&url, self.headers()) {
+            Ok(ref mr) if mr.state != "opened" => return Ok(None),
+            Ok(mr) => mr,
+            Err(ref err) if err.kind() == ErrorKind::Status(404) => return Ok(None),
+            Err(err) => return Err(err.context(format!("Error getting Merge Request !{}", id))),
+        };
+
+        let to_commit = self.get_branch_commit(&mr.target_branch)
+            .map_err(|err| err.context(format!("Error getting target branch {}", mr.target_branch)))?;
+        Ok(Some(to_pull_request(&mr, to_commit)))
+    }
+
+    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_note(pr, build, Progress::Queued)
+            .map_err(|err| err.context("Error submitting note"))?;
+        Ok(())
+    }
+
+    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_note(pr, build, Progress::Running)
+            .map_err(|err| err.context("Error submitting note"))?;
+        Ok(())
+    }
+
+    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_note(pr, build, Progress::Success)
+            .map_err(|err| err.context("Error submitting note"))?;
+        Ok(())
+    }
+
+    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.update_pr_build_status_note(pr, build, Progress::Failure)
+            .map_err(|err| err.context("Error submitting note"))?;
+        Ok(())
+    }
+
+    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.post_status(pr, self.make_status(build))?;
+        Ok(())
+    }
+
+    fn post_verdict(&self, pr: &::PullRequest, verdict: &::Verdict) -> Result<(), Error> {
+        self.post_status(pr, self.make_verdict_status(pr, verdict))?;
+        Ok(())
+    }
+}
+
+impl Gitlab {
+    pub fn new(credentials: &GitlabCredentials, broadcaster: &fanout::Fanout<fanout::Message>) -> Gitlab {
+        Gitlab {
+            credentials: credentials.to_owned(),
+            broadcaster: broadcaster.to_owned(),
+        }
+    }
+
+    fn project_url(&self) -> String {
+        format!(
+            "{}/api/v4/projects/{}",
+            self.credentials.base_url.trim_right_matches('/'),
+            utf8_percent_encode(&self.credentials.project, PATH_SEGMENT_ENCODE_SET)
+        )
+    }
+
+    fn headers(&self) -> reqwest::header::Headers {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_private_token_header(&self.credentials.token)
+            .add_accept_json_header()
+            .add_content_type_json_header();
+        headers.headers
+    }
+
+    fn broadcast<T>(&self, opcode: &str, payload: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let opcode = fanout::OpCode::Custom {
+            payload: format!("GitLab::{}", opcode).to_owned(),
+        };
+        let message = fanout::Message::new(opcode, payload)?;
+        self.broadcaster.broadcast(message);
+        Ok(())
+    }
+
+    fn get_branch_commit(&self, branch: &str) -> Result<String, Error> {
+        let url = format!(
+            "{}/repository/branches/{}",
+            self.project_url(),
+            utf8_percent_encode(branch, PATH_SEGMENT_ENCODE_SET)
+        );
+        let branch = rest::get::<Branch>(&url, self.headers())?;
+        Ok(branch.commit.id)
+    }
+
+    fn matching_notes(notes: &[Note], text: &str) -> Option<Note> {
+        notes.iter().find(|&note| note.body == text).cloned()
+    }
+
+    /// Find the first note containing all of `substrs`
+    fn matching_build_notes(notes: &[Note], pr: &::PullRequest, build: &::BuildDetails) -> Option<Note> {
+        notes
+            .iter()
+            .find(|&note| comment::is_build_comment(&note.body, pr, build))
+            .cloned()
+    }
+
+    fn update_pr_build_status_note(
+        &self,
+        pr: &::PullRequest,
+        build: &::BuildDetails,
+        progress: Progress,
+    ) -> Result<Note, Error> {
+        let reference = format!("`{}`", pr.from_ref);
+        let commit_url = format!("{}/diffs?commit_id={}", pr.web_url, pr.from_commit);
+        let text = comment::make_comment(build, pr, &reference, &commit_url, progress);
+
+        let mut event_payload = Map::new();
+        event_payload.insert(
+            "pr".to_string(),
+            serde_json::to_value(&pr).map_err(|e| Error::decode(e.to_string()))?,
+        );
+        event_payload.insert(
+            "build".to_string(),
+            serde_json::to_value(&build).map_err(|e| Error::decode(e.to_string()))?,
+        );
+
+        let (note, opcode) = match self.get_notes(pr.id) {
+            Ok(ref notes) => {
+                match Gitlab::matching_notes(notes, &text) {
+                    Some(note) => (Ok(note), "Existing"),
+                    None => {
+                        // Have to post or edit note
+                        match Gitlab::matching_build_notes(notes, pr, build) {
+                            Some(note) => (self.edit_note(pr.id, &note, &text), "Update"),
+                            None => (self.post_note(pr.id, &text), "Post"),
+                        }
+                    }
+                }
+            }
+            Err(err) => (Err(err.context("Error getting list of notes")), "Error"),
+        };
+
+        if let Ok(ref note) = note {
+            event_payload.insert(
+                "note".to_string(),
+                serde_json::to_value(&note).map_err(|e| Error::decode(e.to_string()))?,
+            );
+        }
+
+        self.broadcast(&format!("Note::{}", opcode), &event_payload)?;
+        note
+    }
+
+    /// Notes on the merge request made by us, excluding system notes
+    fn get_notes(&self, mr_iid: i32) -> Result<Vec<Note>, Error> {
+        let url = format!("{}/merge_requests/{}/notes", self.project_url(), mr_iid);
+        let notes = rest::get_all_pages::<Note>(&url, PAGE_SIZE, self.headers())
+            .map_err(|err| err.context("Error getting notes"))?;
+
+        Ok(notes
+            .into_iter()
+            .filter(|note| !note.system && note.author.username == self.credentials.username)
+            .collect())
+    }
+
+    fn post_note(&self, mr_iid: i32, text: &str) -> Result<Note, Error> {
+        let body = serde_json::to_string(&NoteSubmit {
+            body: text.to_owned(),
+        }).map_err(|e| Error::decode(e.to_string()))?;
+        let url = format!("{}/merge_requests/{}/notes", self.project_url(), mr_iid);
+
+        rest::post::<Note>(
+            &url,
+            &body,
+            self.headers(),
+            &hyper::status::StatusCode::Created,
+        ).map_err(|err| err.context("Error posting note"))
+    }
+
+    fn edit_note(&self, mr_iid: i32, note: &Note, text: &str) -> Result<Note, Error> {
+        let body = serde_json::to_string(&NoteSubmit {
+            body: text.to_owned(),
+        }).map_err(|e| Error::decode(e.to_string()))?;
+        let url = format!(
+            "{}/merge_requests/{}/notes/{}",
+            self.project_url(),
+            mr_iid,
+            note.id
+        );
+
+        rest::put::<Note>(&url, &body, self.headers(), &hyper::status::StatusCode::Ok)
+            .map_err(|err| err.context("Error editing note"))
+    }
+
+    fn post_status(&self, pr: &::PullRequest, status: Status) -> Result<Status, Error> {
+        let body = serde_json::to_string(&status).map_err(|e| Error::decode(e.to_string()))?;
+        let url = format!("{}/statuses/{}", self.project_url(), pr.from_commit);
+
+        let mut response =
+            rest::post_raw(&url, &body, self.headers()).map_err(|err| err.context("Error posting build"))?;
+        let status_code = response.status().clone();
+        match status_code {
+            hyper::status::StatusCode::Created => Ok(status),
+            hyper::status::StatusCode::BadRequest => {
+                let mut message = String::new();
+                response
+                    .read_to_string(&mut message)
+                    .map_err(|e| Error::transport(e.to_string()))?;
+                // GitLab refuses to post the same state twice
+                if message.contains("Cannot transition status") {
+                    debug!("Status for {} is already {:?}", pr.from_commit, status.state);
+                    Ok(status)
+                } else {
+                    Err(Error::status(400, format!("Error posting build: {}", message)))
+                }
+            }
+            e => Err(Error::status(e.to_u16(), e.to_string())),
+        }
+    }
+
+    fn make_status(&self, build: &::BuildDetails) -> Status {
+        let state = match build.state {
+            ::BuildState::Finished => match build.status {
+                ::BuildStatus::Success => StatusState::success,
+                _ => StatusState::failed,
+            },
+            ::BuildState::Running => StatusState::running,
+            ::BuildState::Queued => StatusState::pending,
+        };
+
+        let description = build
+            .status_text
+            .as_ref()
+            .map_or_else(|| "".to_string(), |s| s.to_string());
+
+        Status {
+            state: state,
+            name: format!("{}/{}", self.status_name(), build.build_id),
+            target_url: build.web_url.to_owned(),
+            description: description,
+        }
+    }
+
+    /// The verdict has the status name itself, and every build configuration has a name under it
+    fn make_verdict_status(&self, pr: &::PullRequest, verdict: &::Verdict) -> Status {
+        let state = match *verdict {
+            ::Verdict::Pending => StatusState::pending,
+            ::Verdict::Success => StatusState::success,
+            ::Verdict::Failure => StatusState::failed,
+        };
+
+        Status {
+            state: state,
+            name: self.status_name().to_string(),
+            target_url: pr.web_url.to_owned(),
+            description: verdict.description().to_string(),
+        }
+    }
+
+    fn status_name(&self) -> &str {
+        self.credentials
+            .status_name
+            .as_ref()
+            .map_or(DEFAULT_STATUS_NAME, |s| s.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+    use serde_json::{self, Value};
+
+    use fanout::{Fanout, Message};
+    use test_server::{StubResponse, StubServer};
+    use Repository;
+    use super::{Gitlab, GitlabCredentials, Note, User};
+
+    const MERGE_REQUESTS: &'static str = r#"[
+        {
+            "id": 1001,
+            "iid": 7,
+            "project_id": 3,
+            "source_project_id": 3,
+            "title": "A very important MR",
+            "web_url": "https://gitlab.example.com/foo/bar/merge_requests/7",
+            "state": "opened",
+            "source_branch": "feature",
+            "target_branch": "master",
+            "sha": "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b",
+            "author": { "id": 1, "name": "Aaron Xiao Ming", "username": "aaron" }
+        },
+        {
+            "id": 1002,
+            "iid": 8,
+            "project_id": 3,
+            "source_project_id": 4,
+            "title": "A contribution from a fork",
+            "web_url": "https://gitlab.example.com/foo/bar/merge_requests/8",
+            "state": "opened",
+            "source_branch": "master",
+            "target_branch": "master",
+            "sha": "e898b363c1dfda4cdf5a01c2d210e49942c8c8e7",
+            "author": { "id": 2, "name": "Forky McForkface", "username": "forky" }
+        }
+    ]"#;
+
+    const BRANCH: &'static str = r#"{
+        "name": "master",
+        "commit": { "id": "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c" }
+    }"#;
+
+    fn credentials(server: &StubServer) -> GitlabCredentials {
+        GitlabCredentials {
+            username: "pr_demon".to_string(),
+            token: "token".to_string(),
+            base_url: server.url(),
+            project: "foo/bar".to_string(),
+            status_name: None,
+        }
+    }
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 7,
+            web_url: "https://gitlab.example.com/foo/bar/merge_requests/7".to_string(),
+            from_ref: "refs/heads/feature".to_string(),
+            from_commit: "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_string(),
+            to_ref: "refs/heads/master".to_string(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_string(),
+            title: "A very important MR".to_string(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_string(),
+                email: "".to_string(),
+            },
+        }
+    }
+
+    fn build() -> ::BuildDetails {
+        ::BuildDetails {
+            id: 1234,
+            build_id: "foobar".to_string(),
+            web_url: "https://ci.example.com/build/1234".to_string(),
+            commit: Some("363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_string()),
+            branch_name: "pull/7/merge".to_string(),
+            state: ::BuildState::Running,
+            status: ::BuildStatus::Unknown,
+            status_text: None,
+        }
+    }
+
+    fn note(id: i32, username: &str, body: &str, system: bool) -> String {
+        serde_json::to_string(&Note {
+            id: id,
+            body: body.to_string(),
+            author: User {
+                id: 1,
+                name: username.to_string(),
+                username: username.to_string(),
+            },
+            system: system,
+        }).unwrap()
+    }
+
+    #[test]
+    fn get_pr_list_maps_open_merge_requests() {
+        let server = StubServer::new(|request| {
+            if request.path.contains("/repository/branches/") {
+                StubResponse::json(StatusCode::Ok, BRANCH)
+            } else {
+                StubResponse::json(StatusCode::Ok, MERGE_REQUESTS)
+            }
+        });
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        let prs = gitlab.get_pr_list().unwrap();
+        assert_eq!(2, prs.len());
+        assert_eq!(pull_request(), prs[0]);
+        assert_eq!("refs/merge-requests/8/head", prs[1].from_ref);
+
+        let requests = server.requests();
+        // One request for the merge requests, and one for the shared target branch
+        assert_eq!(2, requests.len());
+        assert_eq!(
+            "/api/v4/projects/foo%2Fbar/merge_requests?state=opened&per_page=100&page=1",
+            requests[0].path
+        );
+        assert_eq!(
+            "/api/v4/projects/foo%2Fbar/repository/branches/master",
+            requests[1].path
+        );
+        assert_eq!(Some("token".to_string()), requests[0].header("PRIVATE-TOKEN"));
+    }
+
+    #[test]
+    fn get_pr_only_returns_open_merge_requests() {
+        let server = StubServer::new(|request| {
+            let mrs: Vec<Value> = serde_json::from_str(MERGE_REQUESTS).unwrap();
+            match request.path.as_str() {
+                "/api/v4/projects/foo%2Fbar/merge_requests/7" => {
+                    StubResponse::json(StatusCode::Ok, &mrs[0].to_string())
+                }
+                "/api/v4/projects/foo%2Fbar/merge_requests/8" => {
+                    let mut merged = mrs[1].clone();
+                    merged["state"] = Value::String("merged".to_string());
+                    StubResponse::json(StatusCode::Ok, &merged.to_string())
+                }
+                "/api/v4/projects/foo%2Fbar/repository/branches/master" => StubResponse::json(StatusCode::Ok, BRANCH),
+                _ => StubResponse::json(StatusCode::NotFound, r#"{"message": "404 Not found"}"#),
+            }
+        });
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        assert_eq!(Some(pull_request()), gitlab.get_pr(7).unwrap());
+        assert_eq!(None, gitlab.get_pr(8).unwrap());
+        assert_eq!(None, gitlab.get_pr(9).unwrap());
+    }
+
+    #[test]
+    fn merge_requests_into_missing_branches_are_skipped() {
+        let server = StubServer::new(|request| {
+            if request.path.ends_with("/repository/branches/gone") {
+                StubResponse::json(StatusCode::NotFound, r#"{"message": "404 Branch Not Found"}"#)
+            } else if request.path.contains("/repository/branches/") {
+                StubResponse::json(StatusCode::Ok, BRANCH)
+            } else {
+                StubResponse::json(StatusCode::Ok, &MERGE_REQUESTS.replacen(
+                    r#""target_branch": "master""#,
+                    r#""target_branch": "gone""#,
+                    1,
+                ))
+            }
+        });
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        let prs = gitlab.get_pr_list().unwrap();
+        assert_eq!(vec![8], prs.iter().map(|pr| pr.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn build_running_edits_own_note_for_commit() {
+        let server = StubServer::new(|request| match request.method {
+            Method::Get => {
+                let text = "❌ [Build `foobar`] for 363c1dfda4cdf5a01c2d210e49942c8c8e7e898b has failed";
+                let notes = format!(
+                    "[{}, {}, {}]",
+                    note(1, "pr_demon", "added 1 commit: 363c1dfda4cdf5a01c2d210e49942c8c8e7e898b", true),
+                    note(2, "someone_else", text, false),
+                    note(3, "pr_demon", text, false)
+                );
+                StubResponse::json(StatusCode::Ok, &notes)
+            }
+            _ => StubResponse::json(StatusCode::Ok, &note(3, "pr_demon", "", false)),
+        });
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        gitlab.build_running(&pull_request(), &build()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(Method::Put, requests[1].method);
+        assert_eq!(
+            "/api/v4/projects/foo%2Fbar/merge_requests/7/notes/3",
+            requests[1].path
+        );
+
+        let body: Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert!(body["body"].as_str().unwrap().starts_with("⏳"));
+    }
+
+    #[test]
+    fn build_queued_posts_new_note() {
+        let server = StubServer::new(|request| match request.method {
+            Method::Get => StubResponse::json(StatusCode::Ok, "[]"),
+            _ => StubResponse::json(StatusCode::Created, &note(4, "pr_demon", "", false)),
+        });
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        gitlab.build_queued(&pull_request(), &build()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(Method::Post, requests[1].method);
+        assert_eq!(
+            "/api/v4/projects/foo%2Fbar/merge_requests/7/notes",
+            requests[1].path
+        );
+    }
+
+    #[test]
+    fn post_build_posts_commit_status() {
+        let server = StubServer::new(|request| StubResponse::json(StatusCode::Created, &request.body));
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        gitlab.post_build(&pull_request(), &build()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            "/api/v4/projects/foo%2Fbar/statuses/363c1dfda4cdf5a01c2d210e49942c8c8e7e898b",
+            requests[0].path
+        );
+
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(Some("running"), body["state"].as_str());
+        assert_eq!(Some("pr_demon/foobar"), body["name"].as_str());
+    }
+
+    #[test]
+    fn post_build_ignores_repeated_states() {
+        let server = StubServer::new(|_request| {
+            StubResponse::json(
+                StatusCode::BadRequest,
+                r#"{"message": "Cannot transition status via :run from :running"}"#,
+            )
+        });
+        let gitlab = Gitlab::new(&credentials(&server), &Fanout::<Message>::new());
+
+        assert!(gitlab.post_build(&pull_request(), &build()).is_ok());
+    }
+}
@@ -0,0 +1,280 @@
+//! Liveness and readiness of the daemon, served at `/healthz` and `/readyz` alongside the metrics
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use serde_json::Value;
+
+use error::{Action, Error};
+
+/// The daemon is alive as long as the main loop iterates within this many run intervals
+pub const LIVENESS_INTERVALS: u32 = 3;
+
+lazy_static! {
+    static ref STATUS: Mutex<Status> = Mutex::new(Status::default());
+}
+
+#[derive(Default)]
+struct Status {
+    iteration: Option<Iteration>,
+    /// Outcome of the last call to each backend, by project
+    calls: BTreeMap<(Option<String>, String), Call>,
+    /// Fusionner checkouts of the current configuration, by project
+    checkouts: Vec<Checkout>,
+    /// Outcome of the last cycle of each project
+    cycles: BTreeMap<Option<String>, Cycle>,
+}
+
+/// An iteration of the main loop
+#[derive(Clone, Debug)]
+struct Iteration {
+    started: Instant,
+    started_at: String,
+    /// Run interval at the start of the iteration, or the time until the next scheduled run if that is longer
+    interval: Duration,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Call {
+    project: Option<String>,
+    backend: String,
+    succeeded: bool,
+    at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// What should be done about the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<Action>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Checkout {
+    project: Option<String>,
+    path: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct Cycle {
+    project: Option<String>,
+    finished_at: String,
+    duration_seconds: f64,
+    pull_requests: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn status() -> MutexGuard<'static, Status> {
+    // A panic while the status was being updated leaves it usable
+    STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn now() -> String {
+    Local::now().to_rfc3339()
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// The main loop has started an iteration with a run interval of `interval`
+pub fn iterated(interval: Duration) {
+    status().iteration = Some(Iteration {
+        started: Instant::now(),
+        started_at: now(),
+        interval: interval,
+    });
+}
+
+/// A call to `backend` by `project` has finished
+pub fn call_finished<T>(project: &Option<String>, backend: &str, result: &Result<T, Error>) {
+    status().calls.insert(
+        (project.clone(), backend.to_string()),
+        Call {
+            project: project.clone(),
+            backend: backend.to_string(),
+            succeeded: result.is_ok(),
+            at: now(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+            action: result.as_ref().err().map(|err| err.action()),
+        },
+    );
+}
+
+/// The configuration has been loaded, so calls made with the previous configuration no longer tell anything
+pub fn configured() {
+    let mut status = status();
+    status.calls.clear();
+    status.cycles.clear();
+}
+
+/// The Fusionner checkouts of every project of the current configuration are open
+pub fn checkouts_opened(checkouts: Vec<(Option<String>, String)>) {
+    status().checkouts = checkouts
+        .into_iter()
+        .map(|(project, path)| Checkout {
+            project: project,
+            path: path,
+        })
+        .collect();
+}
+
+/// A project has handled its open pull requests
+pub fn cycle_finished(project: &Option<String>, duration: Duration, pull_requests: usize, result: &Result<(), String>) {
+    status().cycles.insert(
+        project.clone(),
+        Cycle {
+            project: project.clone(),
+            finished_at: now(),
+            duration_seconds: seconds(duration),
+            pull_requests: pull_requests,
+            error: result.as_ref().err().cloned(),
+        },
+    );
+}
+
+/// Alive until the main loop has not iterated for `LIVENESS_INTERVALS` run intervals
+fn is_alive(iteration: &Option<Iteration>, now: Instant) -> bool {
+    match *iteration {
+        Some(ref iteration) => now.duration_since(iteration.started) <= iteration.interval * LIVENESS_INTERVALS,
+        None => true,
+    }
+}
+
+fn is_open(checkout: &Checkout) -> bool {
+    Path::new(&checkout.path).is_dir()
+}
+
+/// Whether a call tells that its backend is usable. Errors that only affect a pull request, e.g. because something
+/// was not found, do not.
+fn is_usable(call: &Call) -> bool {
+    call.succeeded || call.action == Some(Action::Skip)
+}
+
+/// Ready once a backend has been called, if the last call to every backend of every project shows it is usable and
+/// every checkout is open
+fn is_ready(status: &Status) -> bool {
+    !status.calls.is_empty() && status.calls.values().all(is_usable) && status.checkouts.iter().all(is_open)
+}
+
+fn cycles(status: &Status) -> Vec<Cycle> {
+    status.cycles.values().cloned().collect()
+}
+
+/// Whether the daemon is alive, and why
+pub fn liveness() -> (bool, Value) {
+    let status = status();
+    let alive = is_alive(&status.iteration, Instant::now());
+    let iteration = status.iteration.as_ref().map(|iteration| {
+        json!({
+            "started_at": iteration.started_at,
+            "seconds_ago": seconds(iteration.started.elapsed()),
+            "deadline_seconds": seconds(iteration.interval * LIVENESS_INTERVALS),
+        })
+    });
+
+    let body = json!({
+        "alive": alive,
+        "last_iteration": iteration,
+        "cycles": cycles(&status),
+    });
+    (alive, body)
+}
+
+/// Whether the daemon is ready, and why
+pub fn readiness() -> (bool, Value) {
+    let status = status();
+    let ready = is_ready(&status);
+    let checkouts: Vec<Value> = status
+        .checkouts
+        .iter()
+        .map(|checkout| {
+            json!({
+                "project": checkout.project,
+                "path": checkout.path,
+                "open": is_open(checkout),
+            })
+        })
+        .collect();
+
+    let body = json!({
+        "ready": ready,
+        "backends": status.calls.values().collect::<Vec<_>>(),
+        "checkouts": checkouts,
+        "cycles": cycles(&status),
+    });
+    (ready, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
+    use error::Action;
+    use super::{is_alive, is_ready, Call, Checkout, Iteration, Status};
+
+    fn call(project: &str, backend: &str, succeeded: bool) -> Call {
+        Call {
+            project: Some(project.to_string()),
+            backend: backend.to_string(),
+            succeeded: succeeded,
+            at: "2018-06-01T00:00:00+00:00".to_string(),
+            error: None,
+            action: if succeeded { None } else { Some(Action::Retry) },
+        }
+    }
+
+    #[test]
+    fn the_daemon_is_alive_while_the_main_loop_iterates() {
+        let started = Instant::now();
+        let iteration = Some(Iteration {
+            started: started,
+            started_at: "2018-06-01T00:00:00+00:00".to_string(),
+            interval: Duration::from_secs(60),
+        });
+
+        assert!(is_alive(&None, started));
+        assert!(is_alive(&iteration, started + Duration::from_secs(179)));
+        assert!(!is_alive(&iteration, started + Duration::from_secs(181)));
+    }
+
+    #[test]
+    fn the_daemon_is_ready_when_its_backends_and_checkouts_are() {
+        let td = TempDir::new("health").unwrap();
+        let mut status = Status::default();
+        assert!(!is_ready(&status));
+
+        let insert = |status: &mut Status, call: Call| {
+            status.calls.insert((call.project.clone(), call.backend.clone()), call);
+        };
+        insert(&mut status, call("foo", "bitbucket", true));
+        insert(&mut status, call("foo", "teamcity", true));
+        status.checkouts.push(Checkout {
+            project: None,
+            path: td.path().to_str().unwrap().to_string(),
+        });
+        assert!(is_ready(&status));
+
+        insert(&mut status, call("foo", "teamcity", false));
+        assert!(!is_ready(&status));
+
+        // Another project reaching the same backend does not make up for it
+        insert(&mut status, call("bar", "teamcity", true));
+        assert!(!is_ready(&status));
+
+        // e.g. a pull request that was not found
+        let mut skipped = call("foo", "teamcity", false);
+        skipped.action = Some(Action::Skip);
+        insert(&mut status, skipped);
+        assert!(is_ready(&status));
+
+        insert(&mut status, call("foo", "teamcity", true));
+        status.checkouts.push(Checkout {
+            project: Some("missing".to_string()),
+            path: td.path().join("missing").to_str().unwrap().to_string(),
+        });
+        assert!(!is_ready(&status));
+    }
+}
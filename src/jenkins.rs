@@ -0,0 +1,482 @@
+use hyper;
+use reqwest::header::Location;
+use serde_json::Value;
+use url::form_urlencoded;
+
+use error::Error;
+use rest;
+
+/// Names of the parameters passed to the Jenkins job
+const PR_ID_PARAMETER: &'static str = "PR_ID";
+const PR_URL_PARAMETER: &'static str = "PR_URL";
+const BRANCH_PARAMETER: &'static str = "BRANCH";
+const COMMIT_PARAMETER: &'static str = "COMMIT";
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct JenkinsCredentials {
+    pub username: String,
+    /// API token for `username`
+    pub password: String,
+    pub base_url: String,
+    /// Name of the parameterised job. Jobs in folders are separated by `/`, e.g. `folder/job`
+    pub job: String,
+}
+
+impl ::UsernameAndPassword for JenkinsCredentials {
+    fn username(&self) -> &String {
+        &self.username
+    }
+
+    fn password(&self) -> &String {
+        &self.password
+    }
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Job {
+    pub builds: Vec<BuildListItem>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct BuildListItem {
+    pub number: i32,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Build {
+    pub number: i32,
+    pub url: String,
+    pub building: bool,
+    pub result: Option<BuildResult>,
+    pub description: Option<String>,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+#[allow(non_camel_case_types)]
+pub enum BuildResult {
+    SUCCESS,
+    UNSTABLE,
+    FAILURE,
+    NOT_BUILT,
+    ABORTED,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Queue {
+    pub items: Vec<QueueItem>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct QueueItem {
+    pub id: i32,
+    pub task: Task,
+    pub why: Option<String>,
+    pub cancelled: Option<bool>,
+    pub executable: Option<Executable>,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct Task {
+    pub url: String,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct Executable {
+    pub number: i32,
+    pub url: String,
+}
+
+/// Jenkins actions are heterogeneous, but we are only interested in those with parameters
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Action {
+    pub parameters: Option<Vec<Parameter>>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+pub struct Parameter {
+    pub name: String,
+    pub value: Value,
+}
+
+fn find_parameter(actions: &[Action], name: &str) -> Option<String> {
+    actions
+        .iter()
+        .filter_map(|action| action.parameters.as_ref())
+        .flat_map(|parameters| parameters.iter())
+        .find(|parameter| parameter.name == name)
+        .and_then(|parameter| parameter.value.as_str().map(|s| s.to_string()))
+}
+
+impl Build {
+    fn to_build_details(&self, job: &str) -> ::BuildDetails {
+        let (state, status) = match self.result {
+            _ if self.building => (::BuildState::Running, ::BuildStatus::Unknown),
+            None => (::BuildState::Running, ::BuildStatus::Unknown),
+            Some(BuildResult::SUCCESS) => (::BuildState::Finished, ::BuildStatus::Success),
+            Some(BuildResult::NOT_BUILT) => (::BuildState::Finished, ::BuildStatus::Unknown),
+            Some(_) => (::BuildState::Finished, ::BuildStatus::Failure),
+        };
+        let status_text = match self.description {
+            Some(ref description) => Some(description.to_string()),
+            None => self.result.as_ref().map(|result| format!("{:?}", result)),
+        };
+
+        ::BuildDetails {
+            id: self.number,
+            build_id: job.to_string(),
+            web_url: self.url.to_string(),
+            commit: find_parameter(&self.actions, COMMIT_PARAMETER),
+            branch_name: find_parameter(&self.actions, BRANCH_PARAMETER).unwrap_or_else(|| "".to_string()),
+            state: state,
+            status: status,
+            status_text: status_text,
+        }
+    }
+}
+
+impl QueueItem {
+    /// Queue items are identified with negative IDs so that they do not collide with build numbers
+    fn to_build_details(&self, job: &str, web_url: &str) -> ::BuildDetails {
+        let (status, status_text) = match self.cancelled {
+            Some(true) => (::BuildStatus::Failure, Some("Cancelled".to_string())),
+            _ => (::BuildStatus::Unknown, self.why.to_owned()),
+        };
+        let state = match self.cancelled {
+            Some(true) => ::BuildState::Finished,
+            _ => ::BuildState::Queued,
+        };
+
+        ::BuildDetails {
+            id: -self.id,
+            build_id: job.to_string(),
+            web_url: web_url.to_string(),
+            commit: find_parameter(&self.actions, COMMIT_PARAMETER),
+            branch_name: find_parameter(&self.actions, BRANCH_PARAMETER).unwrap_or_else(|| "".to_string()),
+            state: state,
+            status: status,
+            status_text: status_text,
+        }
+    }
+}
+
+impl JenkinsCredentials {
+    fn base_url(&self) -> &str {
+        self.base_url.trim_right_matches('/')
+    }
+
+    /// Path of the job, relative to the base URL
+    fn job_path(&self) -> String {
+        let path: Vec<String> = self.job
+            .split('/')
+            .map(|segment| format!("job/{}", segment))
+            .collect();
+        path.join("/")
+    }
+
+    fn job_url(&self) -> String {
+        format!("{}/{}", self.base_url(), self.job_path())
+    }
+
+    fn get_queue_item(&self, queue_id: i32) -> Result<QueueItem, Error> {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_accept_json_header();
+
+        let url = format!("{}/queue/item/{}/api/json", self.base_url(), queue_id);
+        rest::get::<QueueItem>(&url, headers.headers).map_err(|err| err.context("Error getting queue item"))
+    }
+
+    /// Follow a queue item to the build it started, if any
+    fn follow_queue_item(&self, queue_id: i32) -> Result<::BuildDetails, Error> {
+        let item = self.get_queue_item(queue_id)?;
+        match item.executable {
+            Some(ref executable) => ::ContinuousIntegrator::get_build(self, executable.number),
+            None => Ok(item.to_build_details(&self.job, &format!("{}/queue/item/{}", self.base_url(), queue_id))),
+        }
+    }
+
+    fn queue_id_from_location(location: &str) -> Result<i32, Error> {
+        location
+            .trim_right_matches('/')
+            .rsplit('/')
+            .next()
+            .and_then(|id| id.parse::<i32>().ok())
+            .ok_or_else(|| Error::decode(format!("Unable to find queue item ID from {}", location)))
+    }
+}
+
+impl ::ContinuousIntegrator for JenkinsCredentials {
+    fn build_id(&self) -> &str {
+        &self.job
+    }
+
+    /// Builds started and items still waiting in the queue for the PR commit, with the latest first
+    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_accept_json_header();
+
+        let url = format!(
+            "{}/queue/api/json?tree=items[id,why,cancelled,task[url],actions[parameters[name,value]]]",
+            self.base_url()
+        );
+        let queue = rest::get::<Queue>(&url, headers.headers.clone())
+            .map_err(|err| err.context("Error getting build queue"))?;
+        // Jenkins uses its own root URL for tasks, which might not be the same as the configured base URL
+        let job_path = format!("/{}/", self.job_path());
+        let queued = queue
+            .items
+            .iter()
+            .filter(|item| item.task.url.ends_with(&job_path))
+            .filter(|item| find_parameter(&item.actions, COMMIT_PARAMETER).as_ref() == Some(&pr.from_commit))
+            .map(|item| ::Build { id: -item.id });
+
+        let url = format!(
+            "{}/api/json?tree=builds[number,actions[parameters[name,value]]]",
+            self.job_url()
+        );
+        let job = rest::get::<Job>(&url, headers.headers).map_err(|err| err.context("Error getting list of builds"))?;
+        let builds = job.builds
+            .iter()
+            .filter(|build| find_parameter(&build.actions, COMMIT_PARAMETER).as_ref() == Some(&pr.from_commit))
+            .map(|build| ::Build { id: build.number });
+
+        Ok(queued.chain(builds).collect())
+    }
+
+    /// Negative IDs refer to queue items, which are followed to their builds once they have started
+    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+        if build_id < 0 {
+            return self.follow_queue_item(-build_id);
+        }
+
+        let mut headers = rest::Headers::new();
+        headers
+            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_accept_json_header();
+
+        let url = format!("{}/{}/api/json", self.job_url(), build_id);
+        let build = rest::get::<Build>(&url, headers.headers).map_err(|err| err.context("Error getting build"))?;
+        Ok(build.to_build_details(&self.job))
+    }
+
+    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, Error> {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_accept_json_header();
+
+        let logical_branch_name = format!("pull/{}/merge", pr.id);
+        let parameters = form_urlencoded::Serializer::new(String::new())
+            .append_pair(PR_ID_PARAMETER, &pr.id.to_string())
+            .append_pair(PR_URL_PARAMETER, &pr.web_url)
+            .append_pair(BRANCH_PARAMETER, &logical_branch_name)
+            .append_pair(COMMIT_PARAMETER, &pr.from_commit)
+            .finish();
+        let url = format!("{}/buildWithParameters?{}", self.job_url(), parameters);
+
+        let response =
+            rest::post_raw(&url, "", headers.headers).map_err(|err| err.context("Error queuing build"))?;
+        let location = match *response.status() {
+            hyper::status::StatusCode::Created => match response.headers().get::<Location>() {
+                Some(location) => location.to_string(),
+                None => Err(Error::decode("Jenkins did not return the location of the queue item"))?,
+            },
+            ref e => Err(Error::status(e.to_u16(), format!("Error queuing build {}", e)))?,
+        };
+
+        let queue_id = Self::queue_id_from_location(&location)?;
+        self.follow_queue_item(queue_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+
+    use test_server::{StubResponse, StubServer};
+    use ContinuousIntegrator;
+    use super::JenkinsCredentials;
+
+    const COMMIT: &'static str = "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b";
+
+    fn credentials(server: &StubServer) -> JenkinsCredentials {
+        JenkinsCredentials {
+            username: "username".to_string(),
+            password: "token".to_string(),
+            base_url: server.url(),
+            job: "folder/pr_demon".to_string(),
+        }
+    }
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 111,
+            web_url: "http://www.foobar.com/pr/111".to_owned(),
+            from_ref: "refs/heads/branch_name".to_owned(),
+            from_commit: COMMIT.to_owned(),
+            to_ref: "refs/heads/master".to_owned(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_owned(),
+            title: "A very important PR".to_owned(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_owned(),
+                email: "aaron@xiao.ming".to_owned(),
+            },
+        }
+    }
+
+    fn parameters(commit: &str) -> String {
+        format!(
+            r#"[{{"_class": "hudson.model.CauseAction"}}, {{"parameters": [
+                {{"name": "PR_ID", "value": "111"}},
+                {{"name": "BRANCH", "value": "pull/111/merge"}},
+                {{"name": "COMMIT", "value": "{}"}}
+            ]}}]"#,
+            commit
+        )
+    }
+
+    fn build(number: i32, building: bool, result: &str) -> String {
+        format!(
+            r#"{{"number": {number}, "url": "http://jenkins/job/folder/job/pr_demon/{number}/",
+                "building": {building}, "result": {result}, "description": null, "actions": {actions}}}"#,
+            number = number,
+            building = building,
+            result = result,
+            actions = parameters(COMMIT)
+        )
+    }
+
+    #[test]
+    fn get_build_list_finds_queued_and_started_builds_for_commit() {
+        let server = StubServer::new(move |request| {
+            if request.path.starts_with("/queue/api/json") {
+                let body = format!(
+                    r#"{{"items": [
+                        {{"id": 20, "task": {{"url": "{task}"}}, "actions": {actions}}},
+                        {{"id": 21, "task": {{"url": "{task}"}}, "actions": {other}}},
+                        {{"id": 22, "task": {{"url": "http://jenkins/job/other/"}}, "actions": {actions}}}
+                    ]}}"#,
+                    task = "http://jenkins/job/folder/job/pr_demon/",
+                    actions = parameters(COMMIT),
+                    other = parameters("foobar")
+                );
+                StubResponse::json(StatusCode::Ok, &body)
+            } else {
+                let body = format!(
+                    r#"{{"builds": [
+                        {{"number": 3, "actions": {other}}},
+                        {{"number": 2, "actions": {actions}}},
+                        {{"number": 1, "actions": {actions}}}
+                    ]}}"#,
+                    actions = parameters(COMMIT),
+                    other = parameters("foobar")
+                );
+                StubResponse::json(StatusCode::Ok, &body)
+            }
+        });
+        let jenkins = credentials(&server);
+
+        let builds = jenkins.get_build_list(&pull_request()).unwrap();
+        let ids: Vec<i32> = builds.iter().map(|build| build.id).collect();
+        assert_eq!(vec![-20, 2, 1], ids);
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!(
+            "/job/folder/job/pr_demon/api/json?tree=builds[number,actions[parameters[name,value]]]",
+            requests[1].path
+        );
+    }
+
+    #[test]
+    fn get_build_maps_results() {
+        let server = StubServer::new(|request| {
+            let body = match request.path.as_str() {
+                "/job/folder/job/pr_demon/1/api/json" => build(1, false, r#""SUCCESS""#),
+                "/job/folder/job/pr_demon/2/api/json" => build(2, false, r#""UNSTABLE""#),
+                _ => build(3, true, "null"),
+            };
+            StubResponse::json(StatusCode::Ok, &body)
+        });
+        let jenkins = credentials(&server);
+
+        let success = jenkins.get_build(1).unwrap();
+        assert_eq!(::BuildState::Finished, success.state);
+        assert_eq!(::BuildStatus::Success, success.status);
+        assert_eq!(Some(COMMIT.to_string()), success.commit);
+        assert_eq!("pull/111/merge", success.branch_name);
+        assert_eq!("folder/pr_demon", success.build_id);
+
+        let unstable = jenkins.get_build(2).unwrap();
+        assert_eq!(::BuildState::Finished, unstable.state);
+        assert_eq!(::BuildStatus::Failure, unstable.status);
+        assert_eq!(Some("UNSTABLE".to_string()), unstable.status_text);
+
+        let running = jenkins.get_build(3).unwrap();
+        assert_eq!(::BuildState::Running, running.state);
+    }
+
+    #[test]
+    fn queue_build_follows_queue_item() {
+        let server = StubServer::new(|request| match request.method {
+            Method::Post => StubResponse::json(StatusCode::Created, "")
+                .with_header("Location", "http://jenkins/queue/item/42/"),
+            _ => {
+                let body = format!(
+                    r#"{{"id": 42, "task": {{"url": "http://jenkins/job/folder/job/pr_demon/"}},
+                        "why": "Waiting for next available executor", "actions": {}}}"#,
+                    parameters(COMMIT)
+                );
+                StubResponse::json(StatusCode::Ok, &body)
+            }
+        });
+        let jenkins = credentials(&server);
+
+        let build = jenkins.queue_build(&pull_request()).unwrap();
+        assert_eq!(-42, build.id);
+        assert_eq!(::BuildState::Queued, build.state);
+        assert_eq!(Some(COMMIT.to_string()), build.commit);
+
+        let requests = server.requests();
+        assert_eq!(2, requests.len());
+        assert!(
+            requests[0]
+                .path
+                .starts_with("/job/folder/job/pr_demon/buildWithParameters?PR_ID=111&")
+        );
+        assert!(requests[0].path.contains(&format!("COMMIT={}", COMMIT)));
+        assert_eq!("/queue/item/42/api/json", requests[1].path);
+    }
+
+    #[test]
+    fn get_build_follows_started_queue_items() {
+        let server = StubServer::new(|request| {
+            let body = match request.path.as_str() {
+                "/queue/item/42/api/json" => format!(
+                    r#"{{"id": 42, "task": {{"url": "http://jenkins/job/folder/job/pr_demon/"}},
+                        "executable": {{"number": 7, "url": "http://jenkins/job/folder/job/pr_demon/7/"}},
+                        "actions": {}}}"#,
+                    parameters(COMMIT)
+                ),
+                _ => build(7, true, "null"),
+            };
+            StubResponse::json(StatusCode::Ok, &body)
+        });
+        let jenkins = credentials(&server);
+
+        let build = jenkins.get_build(-42).unwrap();
+        assert_eq!(7, build.id);
+        assert_eq!(::BuildState::Running, build.state);
+        assert_eq!(
+            "/job/folder/job/pr_demon/7/api/json",
+            server.requests()[1].path
+        );
+    }
+}
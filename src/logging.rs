@@ -0,0 +1,413 @@
+//! Log output in text or JSON, to stdout, stderr or a rotated file, that can be held back and printed as a group
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use fern;
+use log;
+use serde_json::{Map, Value};
+use time;
+
+/// Rotate log files once they reach 10 MiB by default
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+/// Keep 5 rotated log files by default
+const DEFAULT_MAX_FILES: usize = 5;
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct LoggingConfiguration {
+    /// `trace`, `debug`, `info`, `warn` or `error`. Overridden by `--log-level`. Defaults to `info`.
+    pub level: Option<String>,
+    /// Defaults to `text`
+    pub format: Option<Format>,
+    /// Defaults to `stdout`
+    pub output: Option<Output>,
+    /// Required when `output` is `file`
+    pub file: Option<FileConfiguration>,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `[time][level] message`, indented by the nesting of what is being handled
+    Text,
+    /// One JSON object per line, with the context of the record as fields
+    Json,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    Stdout,
+    Stderr,
+    File,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct FileConfiguration {
+    pub path: String,
+    /// Size at which the file is rotated. Defaults to 10 MiB.
+    pub max_bytes: Option<u64>,
+    /// Number of rotated files to keep, as `path.1` (the most recent) to `path.{max_files}`. Defaults to 5.
+    pub max_files: Option<usize>,
+}
+
+/// What is being handled when a record is logged
+#[derive(Clone, Default, Debug)]
+pub struct Context {
+    pub pr_id: Option<i32>,
+    pub from_commit: Option<String>,
+    pub build_id: Option<i32>,
+    pub backend: Option<String>,
+}
+
+thread_local! {
+    /// Lines logged by this thread while it is capturing
+    static CAPTURED: RefCell<Option<Vec<String>>> = RefCell::new(None);
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Number of the current iteration of the main loop, shared by the worker threads. 0 before the first iteration.
+static CYCLE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref SINK: Mutex<Sink> = Mutex::new(Sink::Stdout);
+}
+
+/// Start the next iteration of the main loop
+pub fn next_cycle() {
+    CYCLE.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Puts back the context of this thread when dropped, even if the scope is left by a panic
+struct Restore(Option<Context>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            CONTEXT.with(|context| *context.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Run `f` with the context of this thread updated by `update`
+pub fn scoped<U, F, R>(update: U, f: F) -> R
+where
+    U: FnOnce(&mut Context),
+    F: FnOnce() -> R,
+{
+    let _restore = CONTEXT.with(|context| {
+        let previous = context.borrow().clone();
+        update(&mut *context.borrow_mut());
+        Restore(Some(previous))
+    });
+    f()
+}
+
+fn resolve_level(level: Option<&str>) -> Result<log::LogLevelFilter, String> {
+    match level {
+        Some("trace") => Ok(log::LogLevelFilter::Trace),
+        Some("debug") => Ok(log::LogLevelFilter::Debug),
+        Some("warn") => Ok(log::LogLevelFilter::Warn),
+        Some("error") => Ok(log::LogLevelFilter::Error),
+        None | Some("info") => Ok(log::LogLevelFilter::Info),
+        Some(level) => Err(format!("Unknown log level `{}`", level)),
+    }
+}
+
+fn format_text(msg: &str, level: &log::LogLevel) -> String {
+    format!("[{}][{}] {}", time::now().strftime("%FT%T%z").unwrap(), level, msg)
+}
+
+fn format_json(msg: &str, level: &log::LogLevel, location: &log::LogLocation) -> String {
+    let mut record = Map::new();
+    record.insert(
+        "time".to_string(),
+        Value::from(time::now().strftime("%FT%T%z").unwrap().to_string()),
+    );
+    record.insert("level".to_string(), Value::from(level.to_string()));
+    record.insert("module".to_string(), Value::from(location.module_path()));
+    // The indentation only makes sense in text
+    record.insert("message".to_string(), Value::from(msg.trim()));
+
+    let cycle = CYCLE.load(Ordering::SeqCst);
+    if cycle > 0 {
+        record.insert("cycle".to_string(), Value::from(cycle));
+    }
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        if let Some(pr_id) = context.pr_id {
+            record.insert("pr_id".to_string(), Value::from(pr_id));
+        }
+        if let Some(ref from_commit) = context.from_commit {
+            record.insert("from_commit".to_string(), Value::from(from_commit.to_string()));
+        }
+        if let Some(build_id) = context.build_id {
+            record.insert("build_id".to_string(), Value::from(build_id));
+        }
+        if let Some(ref backend) = context.backend {
+            record.insert("backend".to_string(), Value::from(backend.to_string()));
+        }
+    });
+
+    Value::Object(record).to_string()
+}
+
+/// Configure the logger, and where log lines are printed. `log_level` takes precedence over the configured level.
+pub fn configure<'a>(
+    log_level: &Option<String>,
+    config: Option<&LoggingConfiguration>,
+) -> Result<fern::DispatchConfig<'a>, String> {
+    let level = log_level
+        .as_ref()
+        .or_else(|| config.and_then(|config| config.level.as_ref()))
+        .map(|level| &**level);
+    let level = resolve_level(level)?;
+
+    let sink = match config.and_then(|config| config.output) {
+        None | Some(Output::Stdout) => Sink::Stdout,
+        Some(Output::Stderr) => Sink::Stderr,
+        Some(Output::File) => {
+            let file = config
+                .and_then(|config| config.file.as_ref())
+                .ok_or_else(|| "`logging.file` must be configured to log to a file".to_string())?;
+            Sink::File(RotatingFile::open(file)?)
+        }
+    };
+    *SINK.lock().map_err(|err| err.to_string())? = sink;
+
+    let format: Box<fern::Formatter> = match config.and_then(|config| config.format) {
+        None | Some(Format::Text) => Box::new(|msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
+            format_text(msg, level)
+        }),
+        Some(Format::Json) => Box::new(|msg: &str, level: &log::LogLevel, location: &log::LogLocation| {
+            format_json(msg, level, location)
+        }),
+    };
+
+    Ok(fern::DispatchConfig {
+        format: format,
+        output: vec![fern::OutputConfig::custom(Box::new(Grouped))],
+        level: level,
+    })
+}
+
+/// Where log lines are printed
+enum Sink {
+    Stdout,
+    Stderr,
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match *self {
+            Sink::Stdout => writeln!(io::stdout(), "{}", line),
+            Sink::Stderr => writeln!(io::stderr(), "{}", line),
+            Sink::File(ref mut file) => file.write_line(line),
+        }
+    }
+}
+
+/// A log file that is renamed to `path.1` once it reaches `max_bytes`, shifting older files up to `path.{max_files}`
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(config: &FileConfiguration) -> Result<RotatingFile, String> {
+        let path = PathBuf::from(&config.path);
+        let (file, size) = Self::open_file(&path)
+            .map_err(|err| format!("Unable to open log file {}: {}", path.display(), err))?;
+        Ok(RotatingFile {
+            path: path,
+            max_bytes: config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_files: config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            file: file,
+            size: size,
+        })
+    }
+
+    fn open_file(path: &PathBuf) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let rotated = self.rotated(index);
+                if rotated.exists() {
+                    fs::rename(&rotated, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        let (file, size) = Self::open_file(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+}
+
+/// Prints formatted log lines, unless the logging thread is capturing them
+pub struct Grouped;
+
+impl fern::Logger for Grouped {
+    fn log(&self, msg: &str, _level: &log::LogLevel, _location: &log::LogLocation) -> Result<(), fern::LogError> {
+        let captured = CAPTURED.with(|captured| match *captured.borrow_mut() {
+            Some(ref mut lines) => {
+                lines.push(msg.to_string());
+                true
+            }
+            None => false,
+        });
+
+        if !captured {
+            print(&[msg.to_string()]);
+        }
+        Ok(())
+    }
+}
+
+/// Run `f`, returning its result together with the lines it logged instead of printing them
+pub fn capture<F, R>(f: F) -> (R, Vec<String>)
+where
+    F: FnOnce() -> R,
+{
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(vec![]));
+    let result = f();
+    let lines = CAPTURED.with(|captured| captured.borrow_mut().take());
+    (result, lines.unwrap_or_else(Vec::new))
+}
+
+/// Print lines without lines from other threads in between
+pub fn print(lines: &[String]) {
+    let mut sink = SINK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for line in lines {
+        // Nowhere to report a failure to log
+        let _ = sink.write_line(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use std::panic;
+
+    use fern::Logger;
+    use log::{LogLevel, LogLocation};
+    use serde_json::{self, Value};
+
+    use tempdir::TempDir;
+    use super::{capture, format_json, scoped, FileConfiguration, Grouped, RotatingFile};
+
+    static LOCATION: LogLocation = LogLocation {
+        __module_path: "pr_demon",
+        __file: "logging.rs",
+        __line: 1,
+    };
+
+    #[test]
+    fn captured_lines_are_returned_in_order() {
+        let (result, lines) = capture(|| {
+            Grouped.log("first", &LogLevel::Info, &LOCATION).unwrap();
+            Grouped.log("second", &LogLevel::Warn, &LOCATION).unwrap();
+            42
+        });
+
+        assert_eq!(42, result);
+        assert_eq!(vec!["first".to_string(), "second".to_string()], lines);
+
+        let ((), lines) = capture(|| ());
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn json_records_carry_the_context_they_were_logged_in() {
+        let record = scoped(
+            |context| {
+                context.pr_id = Some(42);
+                context.from_commit = Some("ffffff".to_string());
+            },
+            || {
+                scoped(
+                    |context| context.backend = Some("teamcity".to_string()),
+                    || format_json("     Scheduling build", &LogLevel::Info, &LOCATION),
+                )
+            },
+        );
+        let record: Value = serde_json::from_str(&record).unwrap();
+        assert_eq!(Some("INFO"), record["level"].as_str());
+        assert_eq!(Some("Scheduling build"), record["message"].as_str());
+        assert_eq!(Some(42), record["pr_id"].as_i64());
+        assert_eq!(Some("ffffff"), record["from_commit"].as_str());
+        assert_eq!(Some("teamcity"), record["backend"].as_str());
+        assert!(record.get("build_id").is_none());
+
+        // The context is restored once the scope ends
+        let record: Value = serde_json::from_str(&format_json("Done", &LogLevel::Info, &LOCATION)).unwrap();
+        assert!(record.get("pr_id").is_none());
+    }
+
+    #[test]
+    fn context_is_restored_when_the_scope_panics() {
+        let result = panic::catch_unwind(|| scoped(|context| context.pr_id = Some(42), || panic!("Handling failed")));
+        assert!(result.is_err());
+
+        let record: Value = serde_json::from_str(&format_json("Done", &LogLevel::Info, &LOCATION)).unwrap();
+        assert!(record.get("pr_id").is_none());
+    }
+
+    #[test]
+    fn log_files_are_rotated() {
+        let td = TempDir::new("logging").unwrap();
+        let path = td.path().join("pr_demon.log");
+        let mut file = RotatingFile::open(&FileConfiguration {
+            path: path.to_str().unwrap().to_string(),
+            max_bytes: Some(10),
+            max_files: Some(2),
+        }).unwrap();
+
+        for line in &["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |path: &::std::path::Path| {
+            let mut contents = String::new();
+            File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+        assert_eq!("fourth\n", read(&path));
+        assert_eq!("third\n", read(&td.path().join("pr_demon.log.1")));
+        assert_eq!("second\n", read(&td.path().join("pr_demon.log.2")));
+        assert!(!td.path().join("pr_demon.log.3").exists());
+    }
+}
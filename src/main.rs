@@ -1,84 +1,311 @@
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 #[macro_use]
+extern crate prometheus;
+#[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 
+extern crate chrono;
+extern crate cron;
+extern crate crossbeam;
 extern crate docopt;
 extern crate fern;
 extern crate fusionner;
 extern crate git2;
+extern crate hmac;
 extern crate hyper;
+extern crate rand;
 extern crate reqwest;
 extern crate rustc_serialize;
 extern crate serde;
-extern crate serde_json;
 extern crate serde_yaml;
-extern crate time;
+extern crate sha2;
+extern crate signal_hook;
 extern crate url;
 extern crate ws;
 
+#[cfg(test)]
+extern crate tempdir;
 #[cfg(test)]
 extern crate timebomb;
 
 mod bitbucket;
+mod comment;
+mod dry_run;
+mod error;
 mod fanout;
+mod github;
+mod gitlab;
+mod health;
+mod jenkins;
+mod logging;
+mod metrics;
 mod transformer;
 mod rest;
+mod pool;
+mod reload;
+mod schedule;
+mod secrets;
+mod shell;
+mod shutdown;
+mod state;
 mod teamcity;
+mod webhook;
 mod websocket;
 
+#[cfg(test)]
+mod test_server;
+
 use std::fs::File;
+use std::path::Path;
 use std::io;
 use std::iter;
+use std::process;
 use std::boxed::Box;
 use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::cmp;
+use std::time::{Duration, Instant};
 
+use chrono::Local;
 use docopt::Docopt;
 
+use error::{Action, Error};
 use fanout::{Fanout, Message, OpCode};
 
+/// Pull requests are handled one at a time unless configured otherwise
+const DEFAULT_WORKERS: usize = 1;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+/// How often shutdown requests and configuration changes are checked for while waiting for the next run
+const POLL_INTERVAL_MS: u64 = 500;
+
 const USAGE: &'static str = "
 pr_demon
 
 Usage:
   pr_demon [options] <configuration-file>
+  pr_demon [options] once <configuration-file>
+  pr_demon [options] pr <configuration-file> <id>
   pr_demon -h | --help
 
 Use with a <configuration-file> to specify a path to configuration. Use `-` to read from stdin.
 
+By default, pull requests are handled every run interval until the daemon is stopped. `once` handles every open pull
+request once, and `pr` handles the open pull request <id>. Both exit with a non-zero status if handling failed.
+
 Configuration file is expected to be in YAML format.
 
 Options:
   -h --help                 Show this screen.
-  --log-level=<log-level>   The default log level is `info`. Can be set to `trace`, `debug`, `info`, `warn`, or `error`
+  --log-level=<log-level>   The default log level is `info`. Can be set to `trace`, `debug`, `info`, `warn`, or `error`.
+                            Takes precedence over the level in the `logging` section of the configuration.
+  --project=<name>          The project of the pull request for `pr`, if several projects are configured.
+  --dry-run                 Read pull requests and builds, but only log and broadcast what would have been written to
+                            the repository, the continuous integrators and the Fusionner remote.
 ";
 
 #[derive(RustcDecodable, Debug)]
 struct Args {
+    cmd_once: bool,
+    cmd_pr: bool,
     arg_configuration_file: String,
+    arg_id: Option<i32>,
     flag_log_level: Option<String>,
+    flag_project: Option<String>,
+    flag_dry_run: bool,
 }
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 struct Config {
     // TODO: Rename fields
-    teamcity: teamcity::TeamcityCredentials,
-    bitbucket: bitbucket::BitbucketCredentials,
+    teamcity: Option<teamcity::TeamcityCredentials>,
+    /// Use Jenkins as the continuous integrator instead of TeamCity
+    jenkins: Option<jenkins::JenkinsCredentials>,
+    /// Run builds as local shell commands instead of on TeamCity
+    shell: Option<shell::ShellConfiguration>,
+    bitbucket: Option<bitbucket::BitbucketCredentials>,
+    /// Use GitHub as the repository instead of Bitbucket
+    github: Option<github::GithubCredentials>,
+    /// Use GitLab as the repository instead of Bitbucket
+    gitlab: Option<gitlab::GitlabCredentials>,
     fusionner: Option<transformer::FusionnerConfiguration>,
     run_interval: Interval,
     stdout_broadcast: Option<bool>,
     post_build: bool,
     /// If you want to enable a websocket endpoint, set the listen address here
     websocket: Option<String>,
+    /// Serve Prometheus metrics at `/metrics`, and health checks at `/healthz` and `/readyz`, on this listen address
+    metrics: Option<String>,
+    /// Receive Bitbucket Server webhooks to handle updated pull requests immediately
+    webhook: Option<webhook::WebhookConfiguration>,
+    /// Number of pull requests to handle concurrently. Defaults to 1.
+    workers: Option<usize>,
+    /// Persist what is known about pull requests and their builds across restarts
+    state: Option<state::StateConfiguration>,
+    /// Seconds to wait for the pull requests being handled on SIGTERM or SIGINT. Defaults to 10.
+    shutdown_timeout: Option<u64>,
+    /// Monitor several repositories instead of the one configured at the top level
+    projects: Option<Vec<ProjectConfiguration>>,
+    /// Log format and output
+    logging: Option<logging::LoggingConfiguration>,
+    /// Timeouts and retries of requests to the repositories and continuous integrators
+    http: Option<rest::HttpConfiguration>,
+}
+
+/// A repository, the continuous integrator that builds its pull requests and how to handle them
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+struct ProjectConfiguration {
+    /// Tagged on broadcasts about the project. Required for every entry of `projects`.
+    name: Option<String>,
+    teamcity: Option<teamcity::TeamcityCredentials>,
+    jenkins: Option<jenkins::JenkinsCredentials>,
+    shell: Option<shell::ShellConfiguration>,
+    bitbucket: Option<bitbucket::BitbucketCredentials>,
+    github: Option<github::GithubCredentials>,
+    gitlab: Option<gitlab::GitlabCredentials>,
+    fusionner: Option<transformer::FusionnerConfiguration>,
+    /// Defaults to the top-level `post_build`
+    post_build: Option<bool>,
+}
+
+impl Config {
+    /// The configured projects. Without `projects`, the top level configures a single project without a name.
+    fn projects(&self) -> Result<Vec<ProjectConfiguration>, String> {
+        let configured = match self.projects {
+            Some(ref projects) => projects,
+            None => {
+                return Ok(vec![ProjectConfiguration {
+                    name: None,
+                    teamcity: self.teamcity.clone(),
+                    jenkins: self.jenkins.clone(),
+                    shell: self.shell.clone(),
+                    bitbucket: self.bitbucket.clone(),
+                    github: self.github.clone(),
+                    gitlab: self.gitlab.clone(),
+                    fusionner: self.fusionner.clone(),
+                    post_build: Some(self.post_build),
+                }])
+            }
+        };
+
+        let top_level = [
+            self.teamcity.is_some(),
+            self.jenkins.is_some(),
+            self.shell.is_some(),
+            self.bitbucket.is_some(),
+            self.github.is_some(),
+            self.gitlab.is_some(),
+            self.fusionner.is_some(),
+        ];
+        if top_level.iter().any(|&configured| configured) {
+            return Err("Repositories, continuous integrators and `fusionner` must be configured in `projects`".to_string());
+        }
+        if configured.is_empty() {
+            return Err("At least one project must be configured in `projects`".to_string());
+        }
+
+        let mut projects: Vec<ProjectConfiguration> = vec![];
+        for project in configured {
+            let name = match project.name {
+                Some(ref name) => name,
+                None => return Err("Every project must have a `name`".to_string()),
+            };
+            if projects.iter().any(|other| other.name.as_ref() == Some(name)) {
+                return Err(format!("Project `{}` is configured more than once", name));
+            }
+
+            let mut project = project.clone();
+            project.post_build = Some(project.post_build.unwrap_or(self.post_build));
+            projects.push(project);
+        }
+        Ok(projects)
+    }
 }
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 #[serde(untagged)]
 enum Interval {
-    Cron { expression: String },
+    /// One or more cron expressions. When more than one is provided, the earliest upcoming time is used.
+    Cron { expression: OneOrMany<String> },
     Fixed { interval: u64 },
 }
 
+/// Configuration value that can either be a single value or a list of values
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T>
+where
+    T: Clone,
+{
+    pub fn to_vec(&self) -> Vec<T> {
+        match *self {
+            OneOrMany::One(ref value) => vec![value.clone()],
+            OneOrMany::Many(ref values) => values.clone(),
+        }
+    }
+}
+
+/// Overall verdict of a PR across every build configuration. All of them must pass.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub enum Verdict {
+    Pending,
+    Success,
+    Failure,
+}
+
+impl Verdict {
+    /// `None` is a build configuration whose build could not be handled
+    fn from_results(results: &[Option<(BuildState, BuildStatus)>]) -> Verdict {
+        let failed = results.iter().any(|result| match *result {
+            Some((BuildState::Finished, ref status)) => *status != BuildStatus::Success,
+            _ => false,
+        });
+        let passed = results
+            .iter()
+            .all(|result| *result == Some((BuildState::Finished, BuildStatus::Success)));
+
+        if failed {
+            Verdict::Failure
+        } else if passed {
+            Verdict::Success
+        } else {
+            Verdict::Pending
+        }
+    }
+
+    /// Description of the build status posted for the verdict
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Verdict::Pending => "Waiting for every build to finish",
+            Verdict::Success => "Every build has passed",
+            Verdict::Failure => "A build has failed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VerdictPayload<'a> {
+    pr: &'a PullRequest,
+    verdict: &'a Verdict,
+}
+
+#[derive(Serialize)]
+struct ErrorPayload<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pr: Option<&'a PullRequest>,
+    error: &'a Error,
+}
+
 pub trait UsernameAndPassword {
     fn username(&self) -> &String;
     fn password(&self) -> &String;
@@ -102,13 +329,22 @@ pub struct User {
     pub email: String,
 }
 
-pub trait Repository {
-    fn get_pr_list(&self) -> Result<Vec<PullRequest>, String>;
-    fn build_queued(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), String>;
-    fn build_running(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), String>;
-    fn build_success(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), String>;
-    fn build_failure(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), String>;
-    fn post_build(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), String>;
+/// Repositories are shared by the worker threads handling pull requests
+pub trait Repository: Sync {
+    fn get_pr_list(&self) -> Result<Vec<PullRequest>, Error>;
+    /// Get the pull request `id`, or `None` if it is not open
+    fn get_pr(&self, id: i32) -> Result<Option<PullRequest>, Error> {
+        Ok(self.get_pr_list()?.into_iter().find(|pr| pr.id == id))
+    }
+    fn build_queued(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), Error>;
+    fn build_running(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), Error>;
+    fn build_success(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), Error>;
+    fn build_failure(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), Error>;
+    fn post_build(&self, pr: &PullRequest, build: &BuildDetails) -> Result<(), Error>;
+    /// Post the overall verdict of the PR across every build configuration, as a build status of its own
+    fn post_verdict(&self, _pr: &PullRequest, _verdict: &Verdict) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
@@ -142,17 +378,25 @@ pub struct BuildDetails {
     pub status_text: Option<String>,
 }
 
-pub trait ContinuousIntegrator {
-    fn get_build_list(&self, pr: &PullRequest) -> Result<Vec<Build>, String>;
-    fn get_build(&self, build_id: i32) -> Result<BuildDetails, String>;
-    fn queue_build(&self, pr: &PullRequest) -> Result<BuildDetails, String>;
-    fn refresh_vcs(&self) -> Result<(), String> {
+/// Continuous integrators are shared by the worker threads handling pull requests
+pub trait ContinuousIntegrator: Sync {
+    /// The build configuration reported in `BuildDetails::build_id` of the builds of this integrator
+    fn build_id(&self) -> &str;
+    fn get_build_list(&self, pr: &PullRequest) -> Result<Vec<Build>, Error>;
+    fn get_build(&self, build_id: i32) -> Result<BuildDetails, Error>;
+    fn queue_build(&self, pr: &PullRequest) -> Result<BuildDetails, Error>;
+    fn refresh_vcs(&self) -> Result<(), Error> {
         Ok(())
     }
 }
 
 pub trait PrTransformer {
-    fn prepare(&self, _prs: &[PullRequest], _repo: &Repository, _ci: &ContinuousIntegrator) -> Result<(), String> {
+    fn prepare(
+        &self,
+        _prs: &[PullRequest],
+        _repo: &Repository,
+        _cis: &[Box<ContinuousIntegrator>],
+    ) -> Result<(), Error> {
         Ok(())
     }
 
@@ -161,7 +405,7 @@ pub trait PrTransformer {
         pr: PullRequest,
         _repo: &Repository,
         _ci: &ContinuousIntegrator,
-    ) -> Result<PullRequest, String> {
+    ) -> Result<PullRequest, Error> {
         Ok(pr)
     }
 
@@ -170,7 +414,7 @@ pub trait PrTransformer {
         pr: PullRequest,
         _repo: &Repository,
         _ci: &ContinuousIntegrator,
-    ) -> Result<PullRequest, String> {
+    ) -> Result<PullRequest, Error> {
         Ok(pr)
     }
 
@@ -180,7 +424,7 @@ pub trait PrTransformer {
         _build: &BuildDetails,
         _repo: &Repository,
         _ci: &ContinuousIntegrator,
-    ) -> Result<PullRequest, String> {
+    ) -> Result<PullRequest, Error> {
         Ok(pr)
     }
 
@@ -190,11 +434,16 @@ pub trait PrTransformer {
         _build: &BuildDetails,
         _repo: &Repository,
         _ci: &ContinuousIntegrator,
-    ) -> Result<PullRequest, String> {
+    ) -> Result<PullRequest, Error> {
         Ok(pr)
     }
 
-    fn finalize(&self, _prs: &[PullRequest], _repo: &Repository, _ci: &ContinuousIntegrator) -> Result<(), String> {
+    fn finalize(
+        &self,
+        _prs: &[PullRequest],
+        _repo: &Repository,
+        _cis: &[Box<ContinuousIntegrator>],
+    ) -> Result<(), Error> {
         Ok(())
     }
 }
@@ -204,13 +453,23 @@ fn main() {
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
-    let logger_config = configure_logger(&args.flag_log_level);
+    let config_file = read_config(&args.arg_configuration_file, io::stdin()).unwrap();
+    let config = parse_config(config_file).unwrap();
+
+    let logger_config = match logging::configure(&args.flag_log_level, config.logging.as_ref()) {
+        Ok(logger_config) => logger_config,
+        Err(err) => panic!("Invalid logging configuration: {}", err),
+    };
     if let Err(e) = fern::init_global_logger(logger_config, log::LogLevelFilter::Debug) {
         panic!("Failed to initialize global logger: {}", e);
     }
 
-    let config_file = read_config(&args.arg_configuration_file, io::stdin()).unwrap();
-    let config = parse_config(config_file).unwrap();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT));
+    let shutdown = match shutdown::Shutdown::register(shutdown_timeout) {
+        Ok(shutdown) => shutdown,
+        Err(err) => panic!("{}", err),
+    };
+    rest::stop_retrying_on(&shutdown);
 
     let mut fanout = Fanout::<Message>::new();
     if let Some(true) = config.stdout_broadcast {
@@ -225,71 +484,616 @@ fn main() {
         });
     }
 
-    if let Some(ref address) = config.websocket {
+    let websocket = config.websocket.as_ref().map(|address| {
         let subscriber = fanout.subscribe();
         // Panic if we cannot start server
-        websocket::listen(address, subscriber).unwrap();
-    }
-
-    let bitbucket = bitbucket::Bitbucket::new(&config.bitbucket, &fanout);
+        websocket::listen(address, subscriber).unwrap()
+    });
 
-    let pr_transformer: Box<PrTransformer> = match config.fusionner {
-        Some(ref config) => {
-            let transformer = transformer::Fusionner::new(config);
-            if let Err(err) = transformer {
-                panic!("Failed to initialise Fusionner: {}", err)
+    let metrics_listener = config.metrics.as_ref().map(|address| {
+        if let Err(err) = metrics::record(fanout.subscribe()) {
+            panic!("{}", err);
+        }
+        match metrics::listen(address) {
+            Ok(listening) => {
+                info!("{}Serving metrics on {}", prefix(0), listening.socket);
+                listening
             }
-            Box::new(transformer.unwrap())
+            Err(err) => panic!("{}", err),
         }
-        None => Box::new(transformer::NoOp {}),
+    });
+
+    if args.flag_dry_run {
+        info!("{}Dry run: nothing will be written to the repository or the continuous integrators", prefix(0));
+    }
+    let mut daemon = match Daemon::new(config, &fanout, args.flag_dry_run) {
+        Ok(daemon) => daemon,
+        Err(err) => panic!("{}", err),
     };
 
-    let sleep_interval = match config.run_interval {
-        Interval::Cron { .. } => {
-            unimplemented!("Cron is not implemented at the moment");
+    let result = if args.cmd_once {
+        run_once(&daemon, &shutdown)
+    } else if args.cmd_pr {
+        run_single_pull_request(&daemon, args.arg_id, &args.flag_project, &shutdown)
+    } else {
+        run_forever(&mut daemon, &args.arg_configuration_file, &fanout, &shutdown);
+        Ok(())
+    };
+
+    info!("{}Shutting down", prefix(0));
+    // Drop everything that can broadcast so that the remaining broadcasts can be delivered
+    drop(daemon);
+    if let Err(err) = fanout.join() {
+        error!("{}Error delivering remaining broadcasts: {}", prefix(0), err);
+    }
+    if let Some(websocket) = websocket {
+        if let Err(err) = websocket.shutdown() {
+            error!("{}Error shutting down websocket endpoint: {}", prefix(0), err);
         }
-        Interval::Fixed { interval } => std::time::Duration::new(interval, 0),
+    }
+    // Dropping the listener would wait for its thread, which never stops accepting connections
+    if let Some(mut listening) = metrics_listener {
+        if let Err(err) = listening.close() {
+            warn!("{}Unable to stop serving metrics: {}", prefix(0), err);
+        }
+    }
+
+    if let Err(err) = result {
+        error!("{}{}", prefix(0), err);
+        process::exit(1);
+    }
+}
+
+/// Handle pull requests every run interval, and as webhooks arrive, until a shutdown is requested
+fn run_forever(daemon: &mut Daemon, configuration_file: &str, fanout: &Fanout<Message>, shutdown: &shutdown::Shutdown) {
+    let watcher = match reload::Watcher::new(configuration_file).register_hangup() {
+        Ok(watcher) => watcher,
+        Err(err) => panic!("{}", err),
+    };
+
+    let (webhook_sender, webhook_events) = channel();
+    let webhook_listener = match daemon.config.webhook {
+        Some(ref webhook) => match webhook::listen(webhook, webhook_sender) {
+            Ok(listening) => {
+                info!("{}Listening for webhooks on {}", prefix(0), listening.socket);
+                Some(listening)
+            }
+            Err(err) => panic!("{}", err),
+        },
+        None => None,
     };
 
     loop {
-        match bitbucket.get_pr_list() {
-            Err(err) => {
-                error!("{}Error getting Pull Requests: {}", prefix(0), err);
+        if watcher.changed() {
+            if let Some(path) = watcher.reload() {
+                reload_daemon(daemon, path, fanout);
             }
-            Ok(prs) => {
+        }
+
+        logging::next_cycle();
+        let now = Local::now();
+        if let Some(next_run) = daemon.schedule.next_after(&now) {
+            // The next run can be moments away, so allow for at least a whole run interval
+            let until_next_run = schedule::Schedule::duration_until(&now, &next_run);
+            let period = daemon.schedule.period_after(&now).unwrap_or(until_next_run);
+            health::iterated(cmp::max(until_next_run, period));
+        }
+
+        for project in &daemon.projects {
+            if shutdown.is_requested() {
+                break;
+            }
+            if let Err(err) = project.handle_open_pull_requests(daemon.workers, shutdown) {
+                error!("{}{}", prefix(0), err);
+            }
+        }
+
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let now = Local::now();
+        let next_run = match daemon.schedule.next_after(&now) {
+            Some(next_run) => next_run,
+            None => {
+                warn!("{}Run interval will never fire again — exiting", prefix(0));
+                break;
+            }
+        };
+        let sleep_duration = schedule::Schedule::duration_until(&now, &next_run);
+
+        info!(
+            "{} Sleeping for {} seconds until {}",
+            prefix(0),
+            sleep_duration.as_secs(),
+            next_run.to_rfc3339()
+        );
+
+        // Polling remains as a reconciliation fallback for missed webhooks
+        let deadline = Instant::now() + sleep_duration;
+        let interrupted = || shutdown.is_requested() || watcher.changed();
+        while let Some(events) = wait_for_webhooks(&webhook_events, deadline, &interrupted) {
+            for project in &daemon.projects {
+                if shutdown.is_requested() {
+                    break;
+                }
+                project.handle_webhook_events(&events, daemon.workers, shutdown);
+            }
+        }
+
+        if shutdown.is_requested() {
+            break;
+        }
+    }
+
+    // Dropping the listener would wait for its thread, which never stops accepting connections
+    if let Some(mut listening) = webhook_listener {
+        if let Err(err) = listening.close() {
+            warn!("{}Unable to stop listening for webhooks: {}", prefix(0), err);
+        }
+    }
+}
+
+/// Handle the open pull requests of every project once
+fn run_once(daemon: &Daemon, shutdown: &shutdown::Shutdown) -> Result<(), String> {
+    logging::next_cycle();
+    let mut failed = 0;
+    for project in &daemon.projects {
+        if shutdown.is_requested() {
+            break;
+        }
+        if let Err(err) = project.handle_open_pull_requests(daemon.workers, shutdown) {
+            error!("{}{}", prefix(0), err);
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} projects could not be handled", failed, daemon.projects.len())),
+    }
+}
+
+/// Handle the open pull request `id` of the project named `project_name`, which may be omitted if there is only one
+fn run_single_pull_request(
+    daemon: &Daemon,
+    id: Option<i32>,
+    project_name: &Option<String>,
+    shutdown: &shutdown::Shutdown,
+) -> Result<(), String> {
+    let id = id.ok_or_else(|| "A Pull Request ID is required".to_string())?;
+    let project = match *project_name {
+        Some(ref name) => daemon
+            .projects
+            .iter()
+            .find(|project| project.name.as_ref() == Some(name))
+            .ok_or_else(|| format!("Project `{}` is not configured", name))?,
+        None if daemon.projects.len() == 1 => &daemon.projects[0],
+        None => return Err("`--project` is required when several projects are configured".to_string()),
+    };
+
+    logging::next_cycle();
+    project.handle_pull_request(id, shutdown)
+}
+
+/// Everything built from the configuration, which is rebuilt when the configuration is reloaded
+struct Daemon {
+    config: Config,
+    projects: Vec<Project>,
+    schedule: schedule::Schedule,
+    workers: usize,
+    dry_run: bool,
+}
+
+impl Daemon {
+    fn new(config: Config, fanout: &Fanout<Message>, dry_run: bool) -> Result<Daemon, String> {
+        let workers = config.workers.unwrap_or(DEFAULT_WORKERS);
+        if workers == 0 {
+            return Err("`workers` must be at least 1".to_string());
+        }
+
+        let schedule = schedule::Schedule::from_interval(&config.run_interval)
+            .map_err(|err| format!("Invalid run interval: {}", err))?;
+
+        let project_configs = config.projects()?;
+        let projects = project_configs
+            .iter()
+            .map(|project| Project::new(project, config.state.as_ref(), fanout, dry_run))
+            .collect::<Result<Vec<_>, String>>()?;
+        health::configured();
+        health::checkouts_opened(
+            project_configs
+                .iter()
+                .filter_map(|project| {
+                    project.fusionner.as_ref().map(|fusionner| {
+                        (project.name.clone(), fusionner.repository.checkout_path.to_string())
+                    })
+                })
+                .collect(),
+        );
+        rest::configure(&config.http.clone().unwrap_or_default())?;
+
+        Ok(Daemon {
+            config: config,
+            projects: projects,
+            schedule: schedule,
+            workers: workers,
+            dry_run: dry_run,
+        })
+    }
+}
+
+/// A repository together with the continuous integrators that build its pull requests
+struct Project {
+    name: Option<String>,
+    repository: Box<Repository>,
+    integrators: Vec<Box<ContinuousIntegrator>>,
+    pr_transformer: Box<PrTransformer + Sync>,
+    /// Tags broadcasts with the name of the project
+    fanout: Fanout<Message>,
+    post_build: bool,
+    /// Project and repository slugs of a Bitbucket repository, which webhooks identify pull requests by
+    bitbucket_repository: Option<(String, String)>,
+}
+
+impl Project {
+    fn new(
+        config: &ProjectConfiguration,
+        state: Option<&state::StateConfiguration>,
+        fanout: &Fanout<Message>,
+        dry_run: bool,
+    ) -> Result<Project, String> {
+        let label = match config.name {
+            Some(ref name) => format!("Project `{}`: ", name),
+            None => "".to_string(),
+        };
+        let fanout = match config.name {
+            Some(ref name) => fanout.tagged(name),
+            None => fanout.clone(),
+        };
+
+        // Builds that were only pretended to be queued must not outlive a dry run
+        let store = match state {
+            Some(_) if dry_run => None,
+            Some(state) => {
+                let state = match config.name {
+                    Some(ref name) => state.for_project(name),
+                    None => state.clone(),
+                };
+                let store = state::Store::open(&state)
+                    .map_err(|err| format!("{}Invalid state configuration: {}", label, err))?;
+                Some(store)
+            }
+            None => None,
+        };
+
+        let repository = make_repository(config, &fanout, store.as_ref(), dry_run)
+            .map_err(|err| format!("{}Invalid repository configuration: {}", label, err))?;
+
+        let integrators = make_integrators(config, &fanout, store.as_ref(), dry_run)
+            .map_err(|err| format!("{}Invalid continuous integrator configuration: {}", label, err))?;
+
+        let pr_transformer: Box<PrTransformer + Sync> = match config.fusionner {
+            Some(ref fusionner) => {
+                let transformer = transformer::Fusionner::new(fusionner)
+                    .map_err(|err| format!("{}Failed to initialise Fusionner: {}", label, err))?
+                    .with_dry_run(dry_run)
+                    .with_project(&config.name);
+                Box::new(transformer::Serialized::new(transformer))
+            }
+            None => Box::new(transformer::NoOp {}),
+        };
+
+        Ok(Project {
+            name: config.name.clone(),
+            repository: repository,
+            integrators: integrators,
+            pr_transformer: pr_transformer,
+            fanout: fanout,
+            post_build: config.post_build.unwrap_or(false),
+            bitbucket_repository: config
+                .bitbucket
+                .as_ref()
+                .map(|bitbucket| (bitbucket.project_slug.to_string(), bitbucket.repo_slug.to_string())),
+        })
+    }
+
+    fn log_name(&self) {
+        if let Some(ref name) = self.name {
+            info!("{}Project {}", prefix(0), name);
+        }
+    }
+
+    /// List the open pull requests, broadcasting why if they cannot be listed
+    fn get_pr_list(&self) -> Result<Vec<PullRequest>, Error> {
+        self.repository.get_pr_list().map_err(|err| {
+            let err = err.context("Error getting Pull Requests");
+            broadcast_error(&self.fanout, "Repository::Error", None, &err);
+            err
+        })
+    }
+
+    /// Handle every open pull request
+    fn handle_open_pull_requests(&self, workers: usize, shutdown: &shutdown::Shutdown) -> Result<(), String> {
+        self.log_name();
+        let start = Instant::now();
+        let mut pull_requests = 0;
+        let result = self.get_pr_list()
+            .map_err(|err| err.to_string())
+            .and_then(|prs| {
                 info!("{}{} Open Pull Requests Found", prefix(0), prs.len());
-                if let Err(err) = pr_transformer.prepare(&prs, &bitbucket, &config.teamcity) {
-                    error!("{}Error preparing PR Transformer: {}", prefix(0), err);
+                pull_requests = prs.len();
+                metrics::pull_requests_seen(&self.name, prs.len());
+                handle_pull_requests(
+                    &prs,
+                    &*self.repository,
+                    &self.integrators,
+                    &*self.pr_transformer,
+                    &self.fanout,
+                    self.post_build,
+                    workers,
+                    shutdown,
+                )
+            });
+
+        metrics::cycle_finished(&self.name, start.elapsed());
+        health::cycle_finished(&self.name, start.elapsed(), pull_requests, &result);
+        result
+    }
+
+    /// Handle the open pull request `id`
+    fn handle_pull_request(&self, id: i32, shutdown: &shutdown::Shutdown) -> Result<(), String> {
+        self.log_name();
+        let pr = self.repository
+            .get_pr(id)
+            .map_err(|err| {
+                let err = err.context(format!("Error getting Pull Request #{}", id));
+                broadcast_error(&self.fanout, "Repository::Error", None, &err);
+                err.to_string()
+            })?
+            .ok_or_else(|| format!("Pull Request #{} is not open", id))?;
+
+        handle_pull_requests(
+            &[pr],
+            &*self.repository,
+            &self.integrators,
+            &*self.pr_transformer,
+            &self.fanout,
+            self.post_build,
+            1,
+            shutdown,
+        )
+    }
+
+    /// Whether `event` is about a pull request of the repository or a build of the build configurations of this project
+    fn is_concerned_by(&self, event: &webhook::Event) -> bool {
+        match *event {
+            webhook::Event::PullRequestUpdated { ref repository, .. } => self.bitbucket_repository
+                .as_ref()
+                .map_or(false, |&(ref project, ref repo)| repository.is(project, repo)),
+            webhook::Event::BuildFinished { ref build_type_id, .. } => {
+                self.integrators.iter().any(|ci| ci.build_id() == build_type_id)
+            }
+        }
+    }
+
+    /// Handle the pull requests and builds of this project that webhooks reported as updated
+    fn handle_webhook_events(&self, events: &[webhook::Event], workers: usize, shutdown: &shutdown::Shutdown) {
+        let events: Vec<&webhook::Event> = events.iter().filter(|event| self.is_concerned_by(event)).collect();
+        if events.is_empty() {
+            return;
+        }
+
+        self.log_name();
+        let mut ids = vec![];
+        for event in &events {
+            let id = match **event {
+                webhook::Event::PullRequestUpdated { pr_id, .. } | webhook::Event::BuildFinished { pr_id, .. } => pr_id,
+            };
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+
+        // Look up only the pull requests that the events are about
+        let mut prs = vec![];
+        for id in ids {
+            match self.repository.get_pr(id) {
+                Ok(Some(pr)) => prs.push(pr),
+                Ok(None) => (),
+                Err(err) => {
+                    log_error(0, &err);
+                    broadcast_error(&self.fanout, "Repository::Error", None, &err);
                 }
+            }
+        }
 
-                for pr in &prs {
-                    info!("{}Pull Request #{} ({})", prefix(1), pr.id, pr.web_url);
-                    if let Err(handled_pr) = handle_pull_request(
-                        pr.clone(),
-                        &bitbucket,
-                        &config.teamcity,
-                        &*pr_transformer,
-                        &fanout,
-                        config.post_build,
-                    ) {
-                        error!("{}{}", prefix(2), handled_pr);
-                    }
+        let updated_prs: Vec<PullRequest> = prs.iter()
+            .filter(|pr| {
+                events.iter().any(|event| match **event {
+                    webhook::Event::PullRequestUpdated { pr_id, .. } => pr_id == pr.id,
+                    webhook::Event::BuildFinished { .. } => false,
+                })
+            })
+            .cloned()
+            .collect();
+        if !updated_prs.is_empty() {
+            info!("{}{} Pull Requests updated by webhooks", prefix(0), updated_prs.len());
+            if let Err(err) = handle_pull_requests(
+                &updated_prs,
+                &*self.repository,
+                &self.integrators,
+                &*self.pr_transformer,
+                &self.fanout,
+                self.post_build,
+                workers,
+                shutdown,
+            ) {
+                error!("{}{}", prefix(0), err);
+            }
+        }
+
+        for event in &events {
+            if shutdown.is_requested() {
+                break;
+            }
+            if let webhook::Event::BuildFinished { pr_id, build_id, .. } = **event {
+                // Updated PRs have just had all of their builds checked
+                if updated_prs.iter().any(|pr| pr.id == pr_id) {
+                    continue;
                 }
+                let pr = match prs.iter().find(|pr| pr.id == pr_id) {
+                    Some(pr) => pr.clone(),
+                    None => {
+                        info!("{}Build {} finished for closed Pull Request #{}", prefix(0), build_id, pr_id);
+                        continue;
+                    }
+                };
+
+                logging::scoped(
+                    |context| {
+                        context.pr_id = Some(pr.id);
+                        context.from_commit = Some(pr.from_commit.to_string());
+                        context.build_id = Some(build_id);
+                    },
+                    || {
+                        info!("{}Build {} finished for Pull Request #{} ({})", prefix(1), build_id, pr.id, pr.web_url);
+                        if let Err(err) = handle_finished_build(
+                            pr.clone(),
+                            build_id,
+                            &*self.repository,
+                            &self.integrators,
+                            &*self.pr_transformer,
+                            &self.fanout,
+                            self.post_build,
+                        ) {
+                            log_error(2, &err);
+                            broadcast_error(&self.fanout, "PullRequest::Error", Some(&pr), &err);
+                        }
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Replace `daemon` with one built from the configuration file at `path`, unless the configuration is invalid
+fn reload_daemon(daemon: &mut Daemon, path: &Path, fanout: &Fanout<Message>) {
+    info!("{}Reloading configuration from {}", prefix(0), path.display());
+    let config = match File::open(path)
+        .map_err(|e| format!("Unable to read file because: {}", e))
+        .and_then(parse_config)
+    {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}Keeping the current configuration: {}", prefix(0), err);
+            return;
+        }
+    };
 
-                if let Err(err) = pr_transformer.finalize(&prs, &bitbucket, &config.teamcity) {
-                    error!("{}Error finalizing PR Transformer: {}", prefix(0), err);
+    for setting in settings_requiring_restart(&daemon.config, &config) {
+        warn!("{}Changes to `{}` take effect after a restart", prefix(0), setting);
+    }
+
+    match Daemon::new(config, fanout, daemon.dry_run) {
+        Ok(reloaded) => {
+            *daemon = reloaded;
+            info!("{}Configuration reloaded", prefix(0));
+        }
+        Err(err) => error!("{}Keeping the current configuration: {}", prefix(0), err),
+    }
+}
+
+/// Settings that are only read when the daemon starts
+fn settings_requiring_restart(current: &Config, reloaded: &Config) -> Vec<&'static str> {
+    let changes = [
+        ("stdout_broadcast", current.stdout_broadcast != reloaded.stdout_broadcast),
+        ("websocket", current.websocket != reloaded.websocket),
+        ("metrics", current.metrics != reloaded.metrics),
+        ("webhook", current.webhook != reloaded.webhook),
+        ("shutdown_timeout", current.shutdown_timeout != reloaded.shutdown_timeout),
+        ("logging", current.logging != reloaded.logging),
+    ];
+    changes
+        .iter()
+        .filter(|&&(_, changed)| changed)
+        .map(|&(setting, _)| setting)
+        .collect()
+}
+
+/// Wait for webhook events until `deadline`, or until `interrupted` returns true
+fn wait_for_webhooks(
+    events: &Receiver<webhook::Event>,
+    deadline: Instant,
+    interrupted: &Fn() -> bool,
+) -> Option<Vec<webhook::Event>> {
+    loop {
+        let now = Instant::now();
+        if now >= deadline || interrupted() {
+            return None;
+        }
+
+        let timeout = cmp::min(deadline - now, Duration::from_millis(POLL_INTERVAL_MS));
+        match events.recv_timeout(timeout) {
+            Ok(event) => {
+                // Coalesce events that arrived together
+                let mut received = vec![event];
+                for event in events.try_iter() {
+                    if !received.contains(&event) {
+                        received.push(event);
+                    }
                 }
+                return Some(received);
             }
-        };
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                // The webhook listener has stopped, so only the deadline or an interruption can end the wait
+                thread::sleep(timeout);
+            }
+        }
+    }
+}
 
-        let sleep_duration = sleep_interval;
+fn handle_pull_requests(
+    prs: &[PullRequest],
+    repository: &Repository,
+    integrators: &[Box<ContinuousIntegrator>],
+    pr_transformer: &(PrTransformer + Sync),
+    fanout: &Fanout<Message>,
+    post_build: bool,
+    workers: usize,
+    shutdown: &shutdown::Shutdown,
+) -> Result<(), String> {
+    if let Err(err) = pr_transformer.prepare(prs, repository, integrators) {
+        error!("{}Error preparing PR Transformer: {}", prefix(0), err);
+    }
 
-        info!(
-            "{} Sleeping for {} seconds",
-            prefix(0),
-            sleep_duration.as_secs()
-        );
-        std::thread::sleep(sleep_duration);
+    let failed = AtomicUsize::new(0);
+    pool::for_each(prs, workers, shutdown, |pr| {
+        logging::scoped(
+            |context| {
+                context.pr_id = Some(pr.id);
+                context.from_commit = Some(pr.from_commit.to_string());
+            },
+            || {
+                info!("{}Pull Request #{} ({})", prefix(1), pr.id, pr.web_url);
+                if let Err(err) =
+                    handle_pull_request_builds(pr, repository, integrators, pr_transformer, fanout, post_build)
+                {
+                    log_error(2, &err);
+                    failed.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+        )
+    });
+
+    if let Err(err) = pr_transformer.finalize(prs, repository, integrators) {
+        error!("{}Error finalizing PR Transformer: {}", prefix(0), err);
+    }
+
+    match failed.load(Ordering::SeqCst) {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} Pull Requests could not be handled", failed, prs.len())),
     }
 }
 
@@ -309,51 +1113,145 @@ fn parse_config<R>(reader: R) -> Result<Config, String>
 where
     R: std::io::Read,
 {
-    serde_yaml::from_reader(reader).map_err(|err| format!("Unable to decode YAML file {}", err))
+    let value = serde_yaml::from_reader(reader).map_err(|err| format!("Unable to decode YAML file {}", err))?;
+    let value = secrets::resolve(value)?;
+    serde_yaml::from_value(value).map_err(|err| format!("Unable to decode YAML file {}", err))
+}
+
+fn make_repository(
+    config: &ProjectConfiguration,
+    fanout: &Fanout<Message>,
+    store: Option<&state::Store>,
+    dry_run: bool,
+) -> Result<Box<Repository>, String> {
+    // Repositories paired with the name of their backend in metrics
+    let mut repositories: Vec<(&'static str, Box<Repository>)> = vec![];
+    if let Some(ref credentials) = config.bitbucket {
+        let bitbucket = bitbucket::Bitbucket::new(credentials, fanout)?;
+        repositories.push(match store {
+            Some(store) => ("bitbucket", Box::new(bitbucket.with_state(store)) as Box<Repository>),
+            None => ("bitbucket", Box::new(bitbucket) as Box<Repository>),
+        });
+    }
+    if let Some(ref credentials) = config.github {
+        let github = github::Github::new(credentials, fanout);
+        repositories.push(("github", Box::new(github) as Box<Repository>));
+    }
+    if let Some(ref credentials) = config.gitlab {
+        let gitlab = gitlab::Gitlab::new(credentials, fanout);
+        repositories.push(("gitlab", Box::new(gitlab) as Box<Repository>));
+    }
+
+    let (backend, repository) = match repositories.len() {
+        0 => Err("One of `bitbucket`, `github` or `gitlab` must be configured".to_string()),
+        1 => Ok(repositories.remove(0)),
+        _ => Err("Only one of `bitbucket`, `github` or `gitlab` can be configured".to_string()),
+    }?;
+    let repository: Box<Repository> = Box::new(metrics::MeteredRepository::new(repository, &config.name, backend));
+    let repository: Box<Repository> = if dry_run {
+        Box::new(dry_run::DryRunRepository::new(repository, fanout))
+    } else {
+        repository
+    };
+
+    // Only notify the repository of changes in build states, even if the state is not persisted. What a dry run only
+    // pretended to notify is never persisted, so that a later run still notifies the repository.
+    let store = match store {
+        Some(store) if !dry_run => store.clone(),
+        _ => state::Store::in_memory(),
+    };
+    Ok(Box::new(state::TrackedRepository::new(repository, &store)))
 }
 
-fn get_latest_build(pr: &PullRequest, ci: &ContinuousIntegrator) -> Option<BuildDetails> {
+fn make_integrators(
+    config: &ProjectConfiguration,
+    fanout: &Fanout<Message>,
+    store: Option<&state::Store>,
+    dry_run: bool,
+) -> Result<Vec<Box<ContinuousIntegrator>>, String> {
+    let configured = [
+        config.teamcity.is_some(),
+        config.jenkins.is_some(),
+        config.shell.is_some(),
+    ];
+    match configured.iter().filter(|&&configured| configured).count() {
+        0 => Err("One of `teamcity`, `jenkins` or `shell` must be configured")?,
+        1 => (),
+        _ => Err("Only one of `teamcity`, `jenkins` or `shell` can be configured")?,
+    }
+
+    // Integrators paired with the build configuration they report in `BuildDetails::build_id`
+    let integrators: Vec<(String, Box<ContinuousIntegrator>)> = if let Some(ref credentials) = config.teamcity {
+        let build_ids = credentials.build_id.to_vec();
+        if build_ids.is_empty() {
+            return Err("At least one TeamCity `build_id` must be configured".to_string());
+        }
+        build_ids
+            .iter()
+            .map(|build_id| {
+                let teamcity = teamcity::Teamcity::new(credentials, build_id, fanout)?;
+                let integrator = metrics::MeteredIntegrator::new(Box::new(teamcity), &config.name, "teamcity");
+                Ok((build_id.to_string(), Box::new(integrator) as Box<ContinuousIntegrator>))
+            })
+            .collect::<Result<_, String>>()?
+    } else if let Some(ref credentials) = config.jenkins {
+        let integrator = metrics::MeteredIntegrator::new(Box::new(credentials.clone()), &config.name, "jenkins");
+        vec![(credentials.job.to_string(), Box::new(integrator) as Box<ContinuousIntegrator>)]
+    } else if let Some(ref shell) = config.shell {
+        // A dry run must not run builds, including those that were interrupted by a restart
+        let runner = if dry_run {
+            shell::Shell::read_only(shell)?
+        } else {
+            shell::Shell::new(shell)?
+        };
+        let integrator = metrics::MeteredIntegrator::new(Box::new(runner), &config.name, "shell");
+        vec![(shell::BUILD_ID.to_string(), Box::new(integrator) as Box<ContinuousIntegrator>)]
+    } else {
+        unreachable!()
+    };
+
+    // The dry run wraps the tracked integrator, so that builds that were only pretended to be queued are never tracked
+    Ok(integrators
+        .into_iter()
+        .map(|(build_id, integrator)| {
+            let integrator: Box<ContinuousIntegrator> = match store {
+                Some(store) => Box::new(state::TrackedIntegrator::new(integrator, &build_id, store)),
+                None => integrator,
+            };
+            (build_id, integrator)
+        })
+        .map(|(build_id, integrator)| {
+            if dry_run {
+                Box::new(dry_run::DryRunIntegrator::new(integrator, &build_id, fanout)) as Box<ContinuousIntegrator>
+            } else {
+                integrator
+            }
+        })
+        .collect())
+}
+
+fn get_latest_build(pr: &PullRequest, ci: &ContinuousIntegrator) -> Result<Option<BuildDetails>, Error> {
     let pr_commit = &pr.from_commit;
 
     info!("{}Reference: {}", prefix(2), pr.from_ref);
     info!("{}Commit: {}", prefix(2), pr_commit);
     info!("{}Finding latest build for commit", prefix(2));
 
-    let latest_build = match ci.get_build_list(pr) {
-        Ok(ref build_list) => {
-            if build_list.is_empty() {
-                info!("{}Build does not exist — running build", prefix(2));
-                None
-            } else {
-                let latest_build_id = build_list.first().unwrap().id;
-                match ci.get_build(latest_build_id) {
-                    Ok(build) => {
-                        info!("{}Latest Build Found {}", prefix(2), build.web_url);
-                        Some(build)
-                    }
-                    Err(err) => {
-                        error!(
-                            "{}Unable to retrieve information for build ID {}: {}",
-                            prefix(2),
-                            latest_build_id,
-                            err
-                        );
-                        None
-                    }
-                }
-            }
-        }
-        Err(err) => {
-            warn!(
-                "{}Error fetching builds — queuing anyway: {}",
-                prefix(2),
-                err
-            );
+    let build_list = ci.get_build_list(pr).map_err(|err| err.context("Error fetching builds"))?;
+    let latest_build = match build_list.first() {
+        None => {
+            info!("{}Build does not exist — running build", prefix(2));
             None
         }
+        Some(latest) => {
+            let build = ci.get_build(latest.id)
+                .map_err(|err| err.context(format!("Unable to retrieve information for build ID {}", latest.id)))?;
+            info!("{}Latest Build Found {}", prefix(2), build.web_url);
+            Some(build)
+        }
     };
 
-    match latest_build {
+    Ok(match latest_build {
         None => None,
         Some(ref build) => match build.commit {
             Some(ref commit) => {
@@ -378,53 +1276,177 @@ fn get_latest_build(pr: &PullRequest, ci: &ContinuousIntegrator) -> Option<Build
                 None
             }
         },
+    })
+}
+
+/// Log an error at the level of what should be done about it
+fn log_error(indent: usize, err: &Error) {
+    match err.action() {
+        Action::Alert => error!("{}{}", prefix(indent), err),
+        Action::Retry | Action::Skip => warn!("{}{}", prefix(indent), err),
+    }
+}
+
+fn broadcast_error(fanout: &Fanout<Message>, opcode: &str, pr: Option<&PullRequest>, err: &Error) {
+    let opcode = OpCode::Custom {
+        payload: opcode.to_string(),
+    };
+    match Message::new(opcode, &ErrorPayload { pr: pr, error: err }) {
+        Ok(message) => fanout.broadcast(message),
+        Err(e) => warn!("{}Unable to broadcast error: {}", prefix(2), e),
+    }
+}
+
+/// Handle the PR once for every build configuration, and broadcast the overall verdict. With several build
+/// configurations and `post_build`, the verdict is also posted to the repository.
+///
+/// Errors are not retried here, since handling a build configuration again could queue a second build. The most
+/// severe of the errors of the build configurations that could not be handled is returned.
+fn handle_pull_request_builds(
+    pr: &PullRequest,
+    repo: &Repository,
+    cis: &[Box<ContinuousIntegrator>],
+    pr_transformer: &PrTransformer,
+    fanout: &Fanout<Message>,
+    post_build: bool,
+) -> Result<Verdict, Error> {
+    let mut results: Vec<Option<(BuildState, BuildStatus)>> = vec![];
+    let mut errors: Vec<Error> = vec![];
+    for ci in cis {
+        match handle_pull_request(pr.clone(), repo, &**ci, pr_transformer, fanout, post_build) {
+            Ok(result) => results.push(Some(result)),
+            Err(err) => {
+                log_error(2, &err);
+                broadcast_error(fanout, "PullRequest::Error", Some(pr), &err);
+                results.push(None);
+                errors.push(err);
+            }
+        }
+    }
+
+    let verdict = Verdict::from_results(&results);
+    info!("{}Verdict: {:?}", prefix(2), verdict);
+    let opcode = OpCode::Custom {
+        payload: "PullRequest::Verdict".to_string(),
+    };
+    fanout.broadcast(Message::new(
+        opcode,
+        &VerdictPayload {
+            pr: pr,
+            verdict: &verdict,
+        },
+    )?);
+
+    let failed = errors.len();
+    let mut error = errors
+        .into_iter()
+        .max_by_key(|err| err.action())
+        .map(|err| err.context(format!("{} of {} builds could not be handled", failed, results.len())));
+
+    if post_build && cis.len() > 1 {
+        if let Err(err) = repo.post_verdict(pr, &verdict) {
+            let err = err.context("Error posting verdict");
+            log_error(2, &err);
+            broadcast_error(fanout, "PullRequest::Error", Some(pr), &err);
+            let more_severe = error.as_ref().map_or(true, |error| err.action() > error.action());
+            if more_severe {
+                error = Some(err);
+            }
+        }
+    }
+
+    match error {
+        None => Ok(verdict),
+        Some(err) => Err(err),
+    }
+}
+
+fn handle_pull_request(
+    pr: PullRequest,
+    repo: &Repository,
+    ci: &ContinuousIntegrator,
+    pr_transformer: &PrTransformer,
+    fanout: &Fanout<Message>,
+    post_build: bool,
+) -> Result<(BuildState, BuildStatus), Error> {
+    fanout.broadcast(Message::new(OpCode::OpenPullRequest, &pr)?);
+
+    let pr = pr_transformer.pre_build_retrieval(pr, repo, ci)?;
+
+    match get_latest_build(&pr, ci)? {
+        None => {
+            fanout.broadcast(Message::new(OpCode::BuildNotFound, &pr)?);
+            let pr = pr_transformer.pre_build_scheduling(pr, repo, ci)?;
+            schedule_build(&pr, ci, repo).and_then(|build| {
+                fanout.broadcast(Message::new(OpCode::BuildScheduled, &build)?);
+                Ok((build.state, build.status))
+            })
+        }
+        Some(build) => logging::scoped(
+            |context| context.build_id = Some(build.id),
+            || {
+                fanout.broadcast(Message::new(OpCode::BuildFound, &build)?);
+                post_build_status(pr, &build, repo, ci, pr_transformer, fanout, post_build)
+            },
+        ),
     }
 }
 
-fn handle_pull_request(
+/// Post the status of an existing build to the PR
+fn post_build_status(
+    pr: PullRequest,
+    build: &BuildDetails,
+    repo: &Repository,
+    ci: &ContinuousIntegrator,
+    pr_transformer: &PrTransformer,
+    fanout: &Fanout<Message>,
+    post_build: bool,
+) -> Result<(BuildState, BuildStatus), Error> {
+    let pr = pr_transformer.pre_build_checking(pr, build, repo, ci)?;
+    check_build_status(&pr, build, repo).and_then(|(build_state, build_status)| {
+        let opcode = match build_state {
+            BuildState::Queued => OpCode::BuildQueued,
+            BuildState::Running => OpCode::BuildRunning,
+            BuildState::Finished => OpCode::BuildFinished {
+                success: build_status == BuildStatus::Success,
+            },
+        };
+        fanout.broadcast(Message::new(opcode, build)?);
+        let pr = pr_transformer.pre_build_status_posting(pr, build, repo, ci)?;
+        if post_build {
+            repo.post_build(&pr, build)?;
+        }
+        Ok((build_state, build_status))
+    })
+}
+
+/// Post the status of a build that a CI notification reported as finished
+fn handle_finished_build(
     pr: PullRequest,
+    build_id: i32,
     repo: &Repository,
-    ci: &ContinuousIntegrator,
+    cis: &[Box<ContinuousIntegrator>],
     pr_transformer: &PrTransformer,
     fanout: &Fanout<Message>,
     post_build: bool,
-) -> Result<(), String> {
-    fanout.broadcast(Message::new(OpCode::OpenPullRequest, &pr)?);
-
-    let pr = pr_transformer.pre_build_retrieval(pr, repo, ci)?;
-
-    match get_latest_build(&pr, ci) {
-        None => {
-            fanout.broadcast(Message::new(OpCode::BuildNotFound, &pr)?);
-            let pr = pr_transformer.pre_build_scheduling(pr, repo, ci)?;
-            schedule_build(&pr, ci, repo).and_then(|build| {
-                fanout.broadcast(Message::new(OpCode::BuildScheduled, &build)?);
-                Ok(())
-            })
-        }
-        Some(build) => {
-            fanout.broadcast(Message::new(OpCode::BuildFound, &build)?);
-            let pr = pr_transformer.pre_build_checking(pr, &build, repo, ci)?;
-            check_build_status(&pr, &build, repo).and_then(|(build_state, build_status)| {
-                let opcode = match build_state {
-                    BuildState::Queued => OpCode::BuildQueued,
-                    BuildState::Running => OpCode::BuildRunning,
-                    BuildState::Finished => OpCode::BuildFinished {
-                        success: build_status == BuildStatus::Success,
-                    },
-                };
-                fanout.broadcast(Message::new(opcode, &build)?);
-                let pr = pr_transformer.pre_build_status_posting(pr, &build, repo, ci)?;
-                if post_build {
-                    repo.post_build(&pr, &build)?;
-                }
-                Ok(())
-            })
-        }
+) -> Result<(), Error> {
+    // Integrators of several build configurations can retrieve each other's builds
+    let (ci, build) = cis.iter()
+        .filter_map(|ci| ci.get_build(build_id).ok().map(|build| (ci, build)))
+        .find(|&(ci, ref build)| build.build_id == ci.build_id())
+        .ok_or_else(|| Error::not_found(format!("Unable to retrieve information for build ID {}", build_id)))?;
+
+    let pr = pr_transformer.pre_build_retrieval(pr, repo, &**ci)?;
+    if build.commit.as_ref() != Some(&pr.from_commit) {
+        info!("{}Build {} is for an outdated commit — skipping", prefix(2), build.web_url);
+        return Ok(());
     }
+
+    fanout.broadcast(Message::new(OpCode::BuildFound, &build)?);
+    post_build_status(pr, &build, repo, &**ci, pr_transformer, fanout, post_build).map(|_| ())
 }
 
-fn schedule_build(pr: &PullRequest, ci: &ContinuousIntegrator, repo: &Repository) -> Result<BuildDetails, String> {
+fn schedule_build(pr: &PullRequest, ci: &ContinuousIntegrator, repo: &Repository) -> Result<BuildDetails, Error> {
     info!("{}Scheduling build", prefix(2));
     let queued_build = ci.queue_build(pr);
     match queued_build {
@@ -443,7 +1465,7 @@ fn check_build_status(
     pr: &PullRequest,
     build: &BuildDetails,
     repo: &Repository,
-) -> Result<(BuildState, BuildStatus), String> {
+) -> Result<(BuildState, BuildStatus), Error> {
     info!("{}Build exists: {}", prefix(2), build.web_url);
     match build.state {
         BuildState::Finished => match build.status {
@@ -463,105 +1485,81 @@ fn prefix(x: usize) -> String {
     format!("{} ", iter::repeat("    ").take(x).collect::<String>())
 }
 
-fn to_option_str(opt: &Option<String>) -> Option<&str> {
-    opt.as_ref().map(|s| &**s)
-}
-
-// TODO: Support logging to file/stderr/etc.
-fn configure_logger<'a>(log_level: &Option<String>) -> fern::DispatchConfig<'a> {
-    let log_level = resolve_log_level(log_level)
-        .or_else(|| {
-            panic!("Unknown log level `{}``", log_level.as_ref().unwrap());
-        })
-        .unwrap();
-
-    fern::DispatchConfig {
-        format: Box::new(
-            |msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
-                format!(
-                    "[{}][{}] {}",
-                    time::now().strftime("%FT%T%z").unwrap(),
-                    level,
-                    msg
-                )
-            },
-        ),
-        output: vec![fern::OutputConfig::stdout()],
-        level: log_level,
-    }
-}
-
-fn resolve_log_level(log_level: &Option<String>) -> Option<log::LogLevelFilter> {
-    match to_option_str(log_level) {
-        Some("trace") => Some(log::LogLevelFilter::Trace),
-        Some("debug") => Some(log::LogLevelFilter::Debug),
-        Some("warn") => Some(log::LogLevelFilter::Warn),
-        Some("error") => Some(log::LogLevelFilter::Error),
-        None | Some("info") => Some(log::LogLevelFilter::Info),
-        Some(_) => None,
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::{bitbucket, teamcity, Build, Config, ContinuousIntegrator, Interval, PullRequest};
-    use super::{BuildDetails, BuildState, BuildStatus, Repository, User};
-    use super::{get_latest_build, parse_config, read_config, schedule_build};
-    use super::check_build_status;
+    use super::{BuildDetails, BuildState, BuildStatus, PrTransformer, Repository, User};
+    use super::{get_latest_build, make_integrators, make_repository, parse_config, read_config, schedule_build};
+    use super::{check_build_status, handle_pull_request, handle_pull_request_builds, wait_for_webhooks, Verdict};
+    use super::{handle_finished_build, settings_requiring_restart, Project, ProjectConfiguration};
 
+    use std::env;
     use std::fs::File;
     use std::io::{Cursor, Read};
+    use std::thread;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
     use fusionner;
 
+    use tempdir::TempDir;
+    use error::Error;
+    use fanout::{Fanout, Message, OpCode};
+    use shutdown::Shutdown;
+
     struct StubBuild {
-        build_list: Result<Vec<Build>, String>,
-        build: Result<BuildDetails, String>,
-        queued: Result<BuildDetails, String>,
+        build_list: Result<Vec<Build>, Error>,
+        build: Result<BuildDetails, Error>,
+        queued: Result<BuildDetails, Error>,
     }
 
     impl ContinuousIntegrator for StubBuild {
-        fn get_build_list(&self, _: &PullRequest) -> Result<Vec<Build>, String> {
+        fn build_id(&self) -> &str {
+            "somethingsomething"
+        }
+
+        fn get_build_list(&self, _: &PullRequest) -> Result<Vec<Build>, Error> {
             self.build_list.clone().to_owned()
         }
 
-        fn get_build(&self, _: i32) -> Result<BuildDetails, String> {
+        fn get_build(&self, _: i32) -> Result<BuildDetails, Error> {
             self.build.clone().to_owned()
         }
 
-        fn queue_build(&self, _: &PullRequest) -> Result<BuildDetails, String> {
+        fn queue_build(&self, _: &PullRequest) -> Result<BuildDetails, Error> {
             self.queued.clone().to_owned()
         }
     }
 
     struct StubRepository {
-        pr_list: Result<Vec<PullRequest>, String>,
-        queued: Result<(), String>,
-        running: Result<(), String>,
-        success: Result<(), String>,
-        failure: Result<(), String>,
+        pr_list: Result<Vec<PullRequest>, Error>,
+        queued: Result<(), Error>,
+        running: Result<(), Error>,
+        success: Result<(), Error>,
+        failure: Result<(), Error>,
     }
 
     impl Repository for StubRepository {
-        fn get_pr_list(&self) -> Result<Vec<PullRequest>, String> {
+        fn get_pr_list(&self) -> Result<Vec<PullRequest>, Error> {
             self.pr_list.clone().to_owned()
         }
 
-        fn build_queued(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), String> {
+        fn build_queued(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
             self.queued.clone().to_owned()
         }
 
-        fn build_running(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), String> {
+        fn build_running(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
             self.running.clone().to_owned()
         }
 
-        fn build_success(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), String> {
+        fn build_success(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
             self.success.clone().to_owned()
         }
 
-        fn build_failure(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), String> {
+        fn build_failure(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
             self.failure.clone().to_owned()
         }
-        fn post_build(&self, _pr: &PullRequest, _build: &BuildDetails) -> Result<(), String> {
+        fn post_build(&self, _pr: &PullRequest, _build: &BuildDetails) -> Result<(), Error> {
             Ok(())
         }
     }
@@ -665,19 +1663,27 @@ mod tests {
     #[test]
     fn it_reads_and_parses_a_config_file() {
         let expected = Config {
-            bitbucket: bitbucket::BitbucketCredentials {
-                username: "username".to_string(),
-                password: "password".to_string(),
+            bitbucket: Some(bitbucket::BitbucketCredentials {
+                username: Some("username".to_string()),
+                password: Some("password".to_string()),
+                token: None,
                 base_url: "https://www.example.com/bb".to_string(),
                 project_slug: "foo".to_string(),
                 repo_slug: "bar".to_string(),
-            },
-            teamcity: teamcity::TeamcityCredentials {
-                username: "username".to_string(),
-                password: "password".to_string(),
-                build_id: "foobar".to_string(),
+                page_size: None,
+            }),
+            github: None,
+            gitlab: None,
+            teamcity: Some(teamcity::TeamcityCredentials {
+                username: Some("username".to_string()),
+                password: Some("password".to_string()),
+                token: None,
+                build_id: ::OneOrMany::One("foobar".to_string()),
+                cancel_superseded: None,
                 base_url: "https://www.foobar.com/rest".to_string(),
-            },
+            }),
+            jenkins: None,
+            shell: None,
             fusionner: Some(::transformer::FusionnerConfiguration {
                 notes_namespace: Some("foobar".to_string()),
                 push: Some(true),
@@ -698,6 +1704,14 @@ mod tests {
             stdout_broadcast: Some(false),
             post_build: false,
             websocket: Some("0.0.0.0:8080".to_string()),
+            metrics: None,
+            webhook: None,
+            workers: None,
+            state: None,
+            shutdown_timeout: None,
+            projects: None,
+            logging: None,
+            http: None,
         };
 
         let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
@@ -706,16 +1720,665 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn secrets_are_resolved_when_parsing_the_configuration() {
+        let fixture = include_str!("../tests/fixtures/config.yaml");
+        let bitbucket_password = "  password: password\n  base_url: https://www.example.com/bb";
+        assert!(fixture.contains(bitbucket_password));
+
+        env::set_var("PR_DEMON_TEST_BITBUCKET_PASSWORD", "secret");
+        let referenced = fixture.replace(
+            bitbucket_password,
+            "  password: ${env:PR_DEMON_TEST_BITBUCKET_PASSWORD}\n  base_url: https://www.example.com/bb",
+        );
+        let config = parse_config(Cursor::new(referenced)).unwrap();
+        assert_eq!(Some("secret".to_string()), config.bitbucket.unwrap().password);
+
+        let missing = fixture.replace(
+            bitbucket_password,
+            "  password: file:/nonexistent/bitbucket_password\n  base_url: https://www.example.com/bb",
+        );
+        let err = parse_config(Cursor::new(missing)).unwrap_err();
+        assert!(err.contains("`bitbucket.password`"));
+    }
+
+    /// The single project configured at the top level of `config`
+    fn project(config: &Config) -> ProjectConfiguration {
+        config.projects().unwrap().remove(0)
+    }
+
+    #[test]
+    fn top_level_settings_configure_a_single_project() {
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let config = parse_config(reader).unwrap();
+
+        let projects = config.projects().unwrap();
+        assert_eq!(1, projects.len());
+        assert_eq!(None, projects[0].name);
+        assert_eq!(config.bitbucket, projects[0].bitbucket);
+        assert_eq!(config.teamcity, projects[0].teamcity);
+        assert_eq!(Some(false), projects[0].post_build);
+    }
+
+    #[test]
+    fn projects_must_be_named_uniquely() {
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let mut config = parse_config(reader).unwrap();
+        let mut foo = project(&config);
+        foo.name = Some("foo".to_string());
+        foo.post_build = None;
+        let mut bar = foo.clone();
+        bar.name = Some("bar".to_string());
+        bar.post_build = Some(true);
+
+        config.projects = Some(vec![foo.clone(), bar.clone()]);
+        // Top-level repositories and integrators would be ignored
+        assert!(config.projects().is_err());
+
+        config.teamcity = None;
+        config.bitbucket = None;
+        config.fusionner = None;
+        let projects = config.projects().unwrap();
+        assert_eq!(Some(false), projects[0].post_build);
+        assert_eq!(Some(true), projects[1].post_build);
+
+        config.projects = Some(vec![foo.clone(), foo.clone()]);
+        assert!(config.projects().is_err());
+
+        foo.name = None;
+        config.projects = Some(vec![foo, bar]);
+        assert!(config.projects().is_err());
+
+        config.projects = Some(vec![]);
+        assert!(config.projects().is_err());
+    }
+
+    #[test]
+    fn settings_requiring_restart_are_detected() {
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let current = parse_config(reader).unwrap();
+        let mut reloaded = current.clone();
+
+        reloaded.workers = Some(4);
+        reloaded.post_build = true;
+        assert!(settings_requiring_restart(&current, &reloaded).is_empty());
+
+        reloaded.websocket = None;
+        reloaded.shutdown_timeout = Some(1);
+        assert_eq!(
+            vec!["websocket", "shutdown_timeout"],
+            settings_requiring_restart(&current, &reloaded)
+        );
+    }
+
+    #[test]
+    fn make_repository_requires_exactly_one_repository() {
+        let fanout = Fanout::<Message>::new();
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let mut config = parse_config(reader).unwrap();
+        assert!(make_repository(&project(&config), &fanout, None, false).is_ok());
+
+        config.github = Some(::github::GithubCredentials {
+            username: "username".to_string(),
+            token: "token".to_string(),
+            api_url: None,
+            owner: "foo".to_string(),
+            repo: "bar".to_string(),
+            status_context: None,
+        });
+        assert!(make_repository(&project(&config), &fanout, None, false).is_err());
+
+        config.bitbucket = None;
+        assert!(make_repository(&project(&config), &fanout, None, false).is_ok());
+
+        config.gitlab = Some(::gitlab::GitlabCredentials {
+            username: "username".to_string(),
+            token: "token".to_string(),
+            base_url: "https://gitlab.example.com".to_string(),
+            project: "foo/bar".to_string(),
+            status_name: None,
+        });
+        assert!(make_repository(&project(&config), &fanout, None, false).is_err());
+
+        config.github = None;
+        assert!(make_repository(&project(&config), &fanout, None, false).is_ok());
+
+        config.gitlab = None;
+        assert!(make_repository(&project(&config), &fanout, None, false).is_err());
+    }
+
+    #[test]
+    fn make_integrator_requires_exactly_one_integrator() {
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let mut config = parse_config(reader).unwrap();
+        let fanout = Fanout::<Message>::new();
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_ok());
+
+        config.jenkins = Some(::jenkins::JenkinsCredentials {
+            username: "username".to_string(),
+            password: "password".to_string(),
+            base_url: "https://jenkins.example.com".to_string(),
+            job: "pr_demon".to_string(),
+        });
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_err());
+
+        config.teamcity = None;
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_ok());
+
+        config.shell = Some(::shell::ShellConfiguration {
+            command: "true".to_string(),
+            state_path: "target/test_shell".to_string(),
+            repository_path: None,
+            concurrency: None,
+            keep_builds: None,
+        });
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_err());
+
+        config.jenkins = None;
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_ok());
+
+        config.shell = None;
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_err());
+    }
+
+    #[test]
+    fn make_integrators_creates_one_integrator_per_teamcity_build_configuration() {
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let mut config = parse_config(reader).unwrap();
+        let fanout = Fanout::<Message>::new();
+        assert_eq!(1, make_integrators(&project(&config), &fanout, None, false).unwrap().len());
+
+        config.teamcity.as_mut().unwrap().build_id =
+            ::OneOrMany::Many(vec!["unit".to_string(), "integration".to_string(), "lint".to_string()]);
+        assert_eq!(3, make_integrators(&project(&config), &fanout, None, false).unwrap().len());
+
+        config.teamcity.as_mut().unwrap().build_id = ::OneOrMany::Many(vec![]);
+        assert!(make_integrators(&project(&config), &fanout, None, false).is_err());
+    }
+
+    #[test]
+    fn dry_runs_leave_the_state_unchanged() {
+        let td = TempDir::new("dry_run").unwrap();
+        let store = ::state::Store::open(&::state::StateConfiguration {
+            path: td.path().to_string_lossy().into_owned(),
+        }).unwrap();
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let config = parse_config(reader).unwrap();
+        let fanout = Fanout::<Message>::new();
+        let pr = pull_request();
+
+        let repository = make_repository(&project(&config), &fanout, Some(&store), true).unwrap();
+        let integrators = make_integrators(&project(&config), &fanout, Some(&store), true).unwrap();
+        let build = integrators[0].queue_build(&pr).unwrap();
+        repository.build_queued(&pr, &build).unwrap();
+        repository.post_build(&pr, &build).unwrap();
+
+        assert!(store.ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn verdict_requires_every_build_configuration_to_pass() {
+        let success = Some((BuildState::Finished, BuildStatus::Success));
+        let failure = Some((BuildState::Finished, BuildStatus::Failure));
+        let running = Some((BuildState::Running, BuildStatus::Success));
+
+        assert_eq!(Verdict::Success, Verdict::from_results(&[success.clone(), success.clone()]));
+        assert_eq!(Verdict::Pending, Verdict::from_results(&[success.clone(), running.clone()]));
+        assert_eq!(Verdict::Pending, Verdict::from_results(&[success.clone(), None]));
+        assert_eq!(Verdict::Failure, Verdict::from_results(&[failure, running, None]));
+    }
+
+    #[test]
+    fn handle_pull_request_builds_aggregates_every_build_configuration() {
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Ok(()),
+            running: Ok(()),
+            failure: Ok(()),
+            queued: Ok(()),
+        };
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![
+            Box::new(StubBuild {
+                build_list: Ok(vec![Build { id: 213232321 }]),
+                build: Ok(build_success()),
+                queued: Err("This does not matter".into()),
+            }),
+            Box::new(StubBuild {
+                build_list: Ok(vec![Build { id: 213232321 }]),
+                build: Ok(build_failure()),
+                queued: Err("This does not matter".into()),
+            }),
+        ];
+        let mut fanout = Fanout::<Message>::new();
+        let subscriber = fanout.subscribe();
+
+        let verdict = handle_pull_request_builds(
+            &pull_request(),
+            &stub_repo,
+            &integrators,
+            &::transformer::NoOp {},
+            &fanout,
+            false,
+        ).unwrap();
+        assert_eq!(Verdict::Failure, verdict);
+
+        let message = subscriber.iter().find(|message| match message.opcode {
+            OpCode::Custom { .. } => true,
+            _ => false,
+        });
+        let message = message.unwrap();
+        assert_eq!(
+            OpCode::Custom {
+                payload: "PullRequest::Verdict".to_string(),
+            },
+            message.opcode
+        );
+        assert_eq!(Some("Failure"), message.payload["verdict"].as_str());
+    }
+
+    /// Records the verdicts posted
+    struct VerdictRepository {
+        verdicts: Mutex<Vec<Verdict>>,
+    }
+
+    impl Repository for VerdictRepository {
+        fn get_pr_list(&self) -> Result<Vec<PullRequest>, Error> {
+            Ok(vec![])
+        }
+        fn build_queued(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            Ok(())
+        }
+        fn build_running(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            Ok(())
+        }
+        fn build_success(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            Ok(())
+        }
+        fn build_failure(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            Ok(())
+        }
+        fn post_build(&self, _: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            Ok(())
+        }
+        fn post_verdict(&self, _: &PullRequest, verdict: &Verdict) -> Result<(), Error> {
+            self.verdicts.lock().unwrap().push(verdict.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handle_pull_request_builds_posts_the_verdict_of_several_build_configurations() {
+        let repo = VerdictRepository {
+            verdicts: Mutex::new(vec![]),
+        };
+        let integrator = || -> Box<ContinuousIntegrator> {
+            Box::new(StubBuild {
+                build_list: Ok(vec![Build { id: 213232321 }]),
+                build: Ok(build_success()),
+                queued: Err("This does not matter".into()),
+            })
+        };
+        let fanout = Fanout::<Message>::new();
+
+        let one = vec![integrator()];
+        handle_pull_request_builds(&pull_request(), &repo, &one, &::transformer::NoOp {}, &fanout, true).unwrap();
+        assert!(repo.verdicts.lock().unwrap().is_empty());
+
+        let several = vec![integrator(), integrator()];
+        handle_pull_request_builds(&pull_request(), &repo, &several, &::transformer::NoOp {}, &fanout, false)
+            .unwrap();
+        assert!(repo.verdicts.lock().unwrap().is_empty());
+        handle_pull_request_builds(&pull_request(), &repo, &several, &::transformer::NoOp {}, &fanout, true)
+            .unwrap();
+        assert_eq!(vec![Verdict::Success], *repo.verdicts.lock().unwrap());
+    }
+
+    const MERGE_COMMIT: &'static str = "e898b363c1dfda4cdf5a01c2d210e49942c8c8e7";
+
+    /// Builds the merge commit of a PR, and posts build statuses for its head commit, like Fusionner does
+    struct MergingTransformer;
+
+    impl PrTransformer for MergingTransformer {
+        fn pre_build_retrieval(
+            &self,
+            mut pr: PullRequest,
+            _: &Repository,
+            _: &ContinuousIntegrator,
+        ) -> Result<PullRequest, Error> {
+            pr.from_commit = MERGE_COMMIT.to_owned();
+            Ok(pr)
+        }
+
+        fn pre_build_status_posting(
+            &self,
+            _: PullRequest,
+            _: &BuildDetails,
+            _: &Repository,
+            _: &ContinuousIntegrator,
+        ) -> Result<PullRequest, Error> {
+            Ok(pull_request())
+        }
+    }
+
+    /// Records the notifications received, by the commit they are for
+    struct NotifiedRepository {
+        notified: Arc<Mutex<Vec<(&'static str, String)>>>,
+    }
+
+    impl NotifiedRepository {
+        fn notify(&self, notification: &'static str, pr: &PullRequest) -> Result<(), Error> {
+            self.notified.lock().unwrap().push((notification, pr.from_commit.to_owned()));
+            Ok(())
+        }
+    }
+
+    impl Repository for NotifiedRepository {
+        fn get_pr_list(&self) -> Result<Vec<PullRequest>, Error> {
+            Ok(vec![])
+        }
+        fn build_queued(&self, pr: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            self.notify("queued", pr)
+        }
+        fn build_running(&self, pr: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            self.notify("running", pr)
+        }
+        fn build_success(&self, pr: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            self.notify("success", pr)
+        }
+        fn build_failure(&self, pr: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            self.notify("failure", pr)
+        }
+        fn post_build(&self, pr: &PullRequest, _: &BuildDetails) -> Result<(), Error> {
+            self.notify("post_build", pr)
+        }
+    }
+
+    #[test]
+    fn tracked_notifications_of_merge_commits_are_only_posted_once() {
+        let notified = Arc::new(Mutex::new(vec![]));
+        let repo = NotifiedRepository {
+            notified: notified.clone(),
+        };
+        let tracked = ::state::TrackedRepository::new(Box::new(repo), &::state::Store::in_memory());
+        let mut merged = build_success();
+        merged.commit = Some(MERGE_COMMIT.to_owned());
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![Box::new(StubBuild {
+            build_list: Ok(vec![Build { id: 213232321 }]),
+            build: Ok(merged),
+            queued: Err("This does not matter".into()),
+        })];
+        let fanout = Fanout::<Message>::new();
+
+        for _ in 0..2 {
+            handle_pull_request_builds(&pull_request(), &tracked, &integrators, &MergingTransformer, &fanout, true)
+                .unwrap();
+        }
+        assert_eq!(
+            vec![
+                ("success", MERGE_COMMIT.to_owned()),
+                ("post_build", pull_request().from_commit),
+            ],
+            *notified.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn handle_pull_request_builds_fails_if_a_build_configuration_cannot_be_handled() {
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Ok(()),
+            running: Ok(()),
+            failure: Ok(()),
+            queued: Ok(()),
+        };
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![
+            Box::new(StubBuild {
+                build_list: Ok(vec![Build { id: 213232321 }]),
+                build: Ok(build_success()),
+                queued: Err("This does not matter".into()),
+            }),
+            Box::new(StubBuild {
+                build_list: Err("Unreachable".into()),
+                build: Ok(build_success()),
+                queued: Err("This does not matter".into()),
+            }),
+        ];
+        let fanout = Fanout::<Message>::new();
+
+        let result = handle_pull_request_builds(
+            &pull_request(),
+            &stub_repo,
+            &integrators,
+            &::transformer::NoOp {},
+            &fanout,
+            false,
+        );
+        assert_eq!(
+            Err(Error::from("Unreachable")
+                .context("Error fetching builds")
+                .context("1 of 2 builds could not be handled")),
+            result
+        );
+    }
+
+    #[test]
+    fn handle_pull_request_builds_returns_the_most_severe_error() {
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Ok(()),
+            running: Ok(()),
+            failure: Ok(()),
+            queued: Ok(()),
+        };
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![
+            Box::new(StubBuild {
+                build_list: Ok(vec![]),
+                build: Ok(build_success()),
+                queued: Err(Error::transport("Connection refused")),
+            }),
+            Box::new(StubBuild {
+                build_list: Ok(vec![]),
+                build: Ok(build_success()),
+                queued: Err(Error::status(401, "Unauthorized")),
+            }),
+        ];
+        let fanout = Fanout::<Message>::new();
+
+        let result = handle_pull_request_builds(
+            &pull_request(),
+            &stub_repo,
+            &integrators,
+            &::transformer::NoOp {},
+            &fanout,
+            false,
+        );
+        assert_eq!(
+            Err(Error::status(401, "Unauthorized").context("2 of 2 builds could not be handled")),
+            result
+        );
+    }
+
+    #[test]
+    fn projects_are_only_concerned_by_their_own_webhook_events() {
+        use webhook::{Event, RepositoryKey};
+
+        let reader = read_config("tests/fixtures/config.yaml", Cursor::new("")).unwrap();
+        let mut config = project(&parse_config(reader).unwrap());
+        config.fusionner = None;
+        let project = Project::new(&config, None, &Fanout::<Message>::new(), false).unwrap();
+
+        let updated = |project: &str, slug: &str| Event::PullRequestUpdated {
+            repository: RepositoryKey {
+                project: project.to_string(),
+                slug: slug.to_string(),
+            },
+            pr_id: 1,
+        };
+        assert!(project.is_concerned_by(&updated("FOO", "bar")));
+        assert!(!project.is_concerned_by(&updated("FOO", "baz")));
+
+        let finished = |build_type_id: &str| Event::BuildFinished {
+            build_type_id: build_type_id.to_string(),
+            pr_id: 1,
+            build_id: 10,
+        };
+        assert!(project.is_concerned_by(&finished("foobar")));
+        assert!(!project.is_concerned_by(&finished("other")));
+    }
+
+    #[test]
+    fn wait_for_webhooks_coalesces_events_until_deadline() {
+        use webhook::{Event, RepositoryKey};
+
+        let updated = Event::PullRequestUpdated {
+            repository: RepositoryKey {
+                project: "FOO".to_string(),
+                slug: "bar".to_string(),
+            },
+            pr_id: 2,
+        };
+        let finished = Event::BuildFinished {
+            build_type_id: "foobar".to_string(),
+            pr_id: 1,
+            build_id: 10,
+        };
+        let (sender, receiver) = channel();
+        sender.send(updated.clone()).unwrap();
+        sender.send(finished.clone()).unwrap();
+        sender.send(updated.clone()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert_eq!(
+            Some(vec![updated, finished]),
+            wait_for_webhooks(&receiver, deadline, &|| false)
+        );
+        assert_eq!(None, wait_for_webhooks(&receiver, deadline, &|| false));
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn wait_for_webhooks_stops_waiting_when_interrupted() {
+        let (_sender, receiver) = channel::<::webhook::Event>();
+        let shutdown = Shutdown::new();
+        let requested = shutdown.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            requested.request();
+        });
+
+        let start = Instant::now();
+        assert_eq!(
+            None,
+            wait_for_webhooks(&receiver, start + Duration::from_secs(60), &|| shutdown.is_requested())
+        );
+        assert!(Instant::now() < start + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn handle_finished_build_skips_builds_of_outdated_commits() {
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Err("Should not post status".into()),
+            running: Ok(()),
+            failure: Ok(()),
+            queued: Ok(()),
+        };
+        let mut outdated = build_success();
+        outdated.commit = Some("a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_owned());
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![Box::new(StubBuild {
+            build_list: Err("This does not matter".into()),
+            build: Ok(outdated),
+            queued: Err("This does not matter".into()),
+        })];
+        let fanout = Fanout::<Message>::new();
+
+        let result = handle_finished_build(
+            pull_request(),
+            213232321,
+            &stub_repo,
+            &integrators,
+            &::transformer::NoOp {},
+            &fanout,
+            false,
+        );
+        assert_eq!(Ok(()), result);
+    }
+
+    #[test]
+    fn handle_finished_build_posts_build_status() {
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Err("Status posted".into()),
+            running: Ok(()),
+            failure: Ok(()),
+            queued: Ok(()),
+        };
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![Box::new(StubBuild {
+            build_list: Err("This does not matter".into()),
+            build: Ok(build_success()),
+            queued: Err("This does not matter".into()),
+        })];
+        let fanout = Fanout::<Message>::new();
+
+        let result = handle_finished_build(
+            pull_request(),
+            213232321,
+            &stub_repo,
+            &integrators,
+            &::transformer::NoOp {},
+            &fanout,
+            false,
+        );
+        assert_eq!(Err(Error::from("Status posted")), result);
+    }
+
+    #[test]
+    fn handle_finished_build_uses_the_integrator_of_the_build_configuration() {
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Err("Status posted".into()),
+            running: Ok(()),
+            failure: Err("Status of another build configuration posted".into()),
+            queued: Ok(()),
+        };
+        let mut other = build_failure();
+        other.build_id = "other".to_owned();
+        let integrators: Vec<Box<ContinuousIntegrator>> = vec![
+            Box::new(StubBuild {
+                build_list: Err("This does not matter".into()),
+                build: Ok(other),
+                queued: Err("This does not matter".into()),
+            }),
+            Box::new(StubBuild {
+                build_list: Err("This does not matter".into()),
+                build: Ok(build_success()),
+                queued: Err("This does not matter".into()),
+            }),
+        ];
+        let fanout = Fanout::<Message>::new();
+
+        let result = handle_finished_build(
+            pull_request(),
+            213232321,
+            &stub_repo,
+            &integrators,
+            &::transformer::NoOp {},
+            &fanout,
+            false,
+        );
+        assert_eq!(Err(Error::from("Status posted")), result);
+    }
+
     #[test]
     fn get_latest_build_returns_latest_buiild_successfully() {
         let expected = &build_success();
         let stub_build = StubBuild {
             build_list: Ok(vec![Build { id: 213232321 }, Build { id: 21323232 }]),
             build: Ok(expected.to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            queued: Err("This does not matter".into()),
         };
 
-        let actual = get_latest_build(&pull_request(), &stub_build).unwrap();
+        let actual = get_latest_build(&pull_request(), &stub_build).unwrap().unwrap();
         assert_eq!(expected, &actual);
     }
 
@@ -723,11 +2386,11 @@ mod tests {
     fn get_latest_build_returns_none_if_no_builds_found() {
         let stub_build = StubBuild {
             build_list: Ok(vec![]),
-            build: Err("ignored".to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            build: Err("ignored".into()),
+            queued: Err("This does not matter".into()),
         };
         let actual = get_latest_build(&pull_request(), &stub_build);
-        assert_eq!(None, actual);
+        assert_eq!(Ok(None), actual);
     }
 
     #[test]
@@ -738,11 +2401,11 @@ mod tests {
         let stub_build = StubBuild {
             build_list: Ok(vec![Build { id: 213232321 }, Build { id: 21323232 }]),
             build: Ok(build.to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            queued: Err("This does not matter".into()),
         };
 
         let actual = get_latest_build(&pull_request(), &stub_build);
-        assert_eq!(None, actual);
+        assert_eq!(Ok(None), actual);
     }
 
     #[test]
@@ -751,35 +2414,38 @@ mod tests {
         let stub_build = StubBuild {
             build_list: Ok(vec![Build { id: 213232321 }, Build { id: 21323232 }]),
             build: Ok(expected.to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            queued: Err("This does not matter".into()),
         };
 
-        let actual = get_latest_build(&pull_request(), &stub_build).unwrap();
+        let actual = get_latest_build(&pull_request(), &stub_build).unwrap().unwrap();
         assert_eq!(expected, &actual);
     }
 
     #[test]
-    fn get_latest_build_returns_none_for_error_fetching_build_list() {
+    fn get_latest_build_fails_for_error_fetching_build_list() {
         let stub_build = StubBuild {
-            build_list: Err("foobar".to_owned()),
-            build: Err("This does not matter".to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            build_list: Err("foobar".into()),
+            build: Err("This does not matter".into()),
+            queued: Err("This does not matter".into()),
         };
 
         let actual = get_latest_build(&pull_request(), &stub_build);
-        assert_eq!(None, actual);
+        assert_eq!(Err(Error::from("foobar").context("Error fetching builds")), actual);
     }
 
     #[test]
-    fn get_latest_build_returns_none_for_error_fetching_build() {
+    fn get_latest_build_fails_for_error_fetching_build() {
         let stub_build = StubBuild {
             build_list: Ok(vec![Build { id: 213232321 }, Build { id: 21323232 }]),
-            build: Err("foobar".to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            build: Err("foobar".into()),
+            queued: Err("This does not matter".into()),
         };
 
         let actual = get_latest_build(&pull_request(), &stub_build);
-        assert_eq!(None, actual);
+        assert_eq!(
+            Err(Error::from("foobar").context("Unable to retrieve information for build ID 213232321")),
+            actual
+        );
     }
 
     #[test]
@@ -790,24 +2456,24 @@ mod tests {
         let stub_build = StubBuild {
             build_list: Ok(vec![Build { id: 213232321 }, Build { id: 21323232 }]),
             build: Ok(build.to_owned()),
-            queued: Err("This does not matter".to_owned()),
+            queued: Err("This does not matter".into()),
         };
 
         let actual = get_latest_build(&pull_request(), &stub_build);
-        assert_eq!(None, actual);
+        assert_eq!(Ok(None), actual);
     }
 
     #[test]
     fn schedule_build_returns_build_on_scheduling() {
         let build = build_queuing();
         let stub_build = StubBuild {
-            build_list: Err("This does not matter".to_owned()),
-            build: Err("This does not matter".to_owned()),
+            build_list: Err("This does not matter".into()),
+            build: Err("This does not matter".into()),
             queued: Ok(build.to_owned()),
         };
 
         let stub_repo = StubRepository {
-            pr_list: Err("This does not matter".to_owned()),
+            pr_list: Err("This does not matter".into()),
             success: Ok(()),
             running: Ok(()),
             failure: Ok(()),
@@ -822,7 +2488,7 @@ mod tests {
     fn check_build_status_returns_correct_state_and_status_on_build_success() {
         let build = build_success();
         let stub_repo = StubRepository {
-            pr_list: Err("This does not matter".to_owned()),
+            pr_list: Err("This does not matter".into()),
             success: Ok(()),
             running: Ok(()),
             failure: Ok(()),
@@ -837,7 +2503,7 @@ mod tests {
     fn check_build_status_returns_correct_state_and_status_on_build_failure() {
         let build = build_failure();
         let stub_repo = StubRepository {
-            pr_list: Err("This does not matter".to_owned()),
+            pr_list: Err("This does not matter".into()),
             success: Ok(()),
             running: Ok(()),
             failure: Ok(()),
@@ -853,7 +2519,7 @@ mod tests {
         let build = build_queuing();
 
         let stub_repo = StubRepository {
-            pr_list: Err("This does not matter".to_owned()),
+            pr_list: Err("This does not matter".into()),
             success: Ok(()),
             running: Ok(()),
             failure: Ok(()),
@@ -869,7 +2535,7 @@ mod tests {
         let build = build_running();
 
         let stub_repo = StubRepository {
-            pr_list: Err("This does not matter".to_owned()),
+            pr_list: Err("This does not matter".into()),
             success: Ok(()),
             running: Ok(()),
             failure: Ok(()),
@@ -879,4 +2545,55 @@ mod tests {
         let actual = check_build_status(&pull_request(), &build, &stub_repo);
         assert_eq!(Ok((BuildState::Running, BuildStatus::Success)), actual);
     }
+
+    #[test]
+    fn handle_pull_request_runs_builds_end_to_end_with_shell() {
+        let td = TempDir::new("shell").unwrap();
+        let ci = ::shell::Shell::new(&::shell::ShellConfiguration {
+            command: "test \"$PR_DEMON_FROM_COMMIT\" = 363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_string(),
+            state_path: td.path().to_str().unwrap().to_string(),
+            repository_path: None,
+            concurrency: None,
+            keep_builds: None,
+        }).unwrap();
+        let stub_repo = StubRepository {
+            pr_list: Err("This does not matter".into()),
+            success: Ok(()),
+            running: Ok(()),
+            failure: Ok(()),
+            queued: Ok(()),
+        };
+        let mut fanout = Fanout::<Message>::new();
+        let subscriber = fanout.subscribe();
+        let transformer = ::transformer::NoOp {};
+
+        handle_pull_request(pull_request(), &stub_repo, &ci, &transformer, &fanout, false).unwrap();
+        let scheduled: Vec<OpCode> = subscriber.iter().take(3).map(|message| message.opcode).collect();
+        assert_eq!(
+            vec![
+                OpCode::OpenPullRequest,
+                OpCode::BuildNotFound,
+                OpCode::BuildScheduled,
+            ],
+            scheduled
+        );
+
+        for _ in 0..100 {
+            if ci.get_build(1).unwrap().state == BuildState::Finished {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        handle_pull_request(pull_request(), &stub_repo, &ci, &transformer, &fanout, false).unwrap();
+        let checked: Vec<OpCode> = subscriber.iter().take(3).map(|message| message.opcode).collect();
+        assert_eq!(
+            vec![
+                OpCode::OpenPullRequest,
+                OpCode::BuildFound,
+                OpCode::BuildFinished { success: true },
+            ],
+            checked
+        );
+    }
 }
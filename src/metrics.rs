@@ -0,0 +1,334 @@
+//! Prometheus metrics of the daemon, served over HTTP at `/metrics` together with the health checks
+use std::sync::mpsc::Receiver;
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use hyper::method::Method;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use prometheus::{self, Counter, CounterVec, Encoder, Gauge, HistogramVec, TextEncoder};
+use serde_json::Value;
+
+use error::Error;
+use fanout::{Message, OpCode};
+use health;
+use logging;
+
+const METRICS_PATH: &'static str = "/metrics";
+const LIVENESS_PATH: &'static str = "/healthz";
+const READINESS_PATH: &'static str = "/readyz";
+
+lazy_static! {
+    static ref CYCLE_DURATION: HistogramVec = register_histogram_vec!(
+        "pr_demon_cycle_duration_seconds",
+        "Time taken to handle every open pull request of a project",
+        &["project"]
+    ).unwrap();
+    static ref PULL_REQUESTS_SEEN: CounterVec = register_counter_vec!(
+        "pr_demon_pull_requests_seen_total",
+        "Open pull requests listed by each cycle",
+        &["project"]
+    ).unwrap();
+    static ref BUILDS: CounterVec = register_counter_vec!(
+        "pr_demon_builds_total",
+        "Builds queued, found, and found to have finished by status",
+        &["project", "event"]
+    ).unwrap();
+    static ref API_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "pr_demon_api_request_duration_seconds",
+        "Latency of calls to the repository, continuous integrator and git backends",
+        &["backend"]
+    ).unwrap();
+    static ref API_ERRORS: CounterVec = register_counter_vec!(
+        "pr_demon_api_errors_total",
+        "Calls to the repository, continuous integrator and git backends that failed",
+        &["backend"]
+    ).unwrap();
+    static ref FUSIONNER_MERGE_FAILURES: Counter = register_counter!(
+        "pr_demon_fusionner_merge_failures_total",
+        "Pull requests that Fusionner was unable to merge"
+    ).unwrap();
+    static ref WEBSOCKET_CLIENTS: Gauge = register_gauge!(
+        "pr_demon_websocket_clients",
+        "Clients connected to the websocket endpoint"
+    ).unwrap();
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+fn project_label(project: &Option<String>) -> &str {
+    project.as_ref().map_or("", |project| &**project)
+}
+
+/// Time a call to `backend` by `project`, counting it as an error if it fails
+pub fn observe<T, F>(project: &Option<String>, backend: &str, call: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    let start = Instant::now();
+    let result = logging::scoped(|context| context.backend = Some(backend.to_string()), call);
+    API_REQUEST_DURATION
+        .with_label_values(&[backend])
+        .observe(seconds(start.elapsed()));
+    if result.is_err() {
+        API_ERRORS.with_label_values(&[backend]).inc();
+    }
+    health::call_finished(project, backend, &result);
+    result
+}
+
+pub fn cycle_finished(project: &Option<String>, duration: Duration) {
+    CYCLE_DURATION
+        .with_label_values(&[project_label(project)])
+        .observe(seconds(duration));
+}
+
+pub fn pull_requests_seen(project: &Option<String>, count: usize) {
+    PULL_REQUESTS_SEEN
+        .with_label_values(&[project_label(project)])
+        .inc_by(count as f64)
+        .unwrap_or_else(|err| warn!("Unable to count Pull Requests: {}", err));
+}
+
+pub fn merge_failed() {
+    FUSIONNER_MERGE_FAILURES.inc();
+}
+
+pub fn websocket_client_connected() {
+    WEBSOCKET_CLIENTS.inc();
+}
+
+pub fn websocket_client_disconnected() {
+    WEBSOCKET_CLIENTS.dec();
+}
+
+/// The `event` label of the builds that a message is about
+fn build_event(opcode: &OpCode) -> Option<&'static str> {
+    match *opcode {
+        OpCode::BuildScheduled => Some("queued"),
+        OpCode::BuildFound => Some("found"),
+        OpCode::BuildFinished { success: true } => Some("succeeded"),
+        OpCode::BuildFinished { success: false } => Some("failed"),
+        _ => None,
+    }
+}
+
+/// Count the builds broadcast to `receiver`
+pub fn record(receiver: Receiver<Message>) -> Result<JoinHandle<()>, String> {
+    Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for message in receiver.iter() {
+                if let Some(event) = build_event(&message.opcode) {
+                    BUILDS
+                        .with_label_values(&[project_label(&message.project), event])
+                        .inc();
+                }
+            }
+        })
+        .map_err(|e| e.to_string())
+}
+
+/// Every metric in the Prometheus text format, with its content type
+fn render() -> Result<(String, Vec<u8>), String> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| e.to_string())?;
+    Ok((encoder.format_type().to_string(), buffer))
+}
+
+/// Respond with `body`, and a status of 503 unless the check is `passing`
+fn respond_with_health(res: &mut Response, (passing, body): (bool, Value)) -> Vec<u8> {
+    if !passing {
+        *res.status_mut() = StatusCode::ServiceUnavailable;
+    }
+    res.headers_mut()
+        .set_raw("Content-Type", vec![b"application/json".to_vec()]);
+    body.to_string().into_bytes()
+}
+
+struct MetricsHandler;
+
+impl Handler for MetricsHandler {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, mut res: Response<'a>) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or_default().to_string(),
+            _ => "".to_string(),
+        };
+
+        let body = match (&req.method, &*path) {
+            (&Method::Get, METRICS_PATH) => match render() {
+                Ok((content_type, body)) => {
+                    res.headers_mut()
+                        .set_raw("Content-Type", vec![content_type.into_bytes()]);
+                    body
+                }
+                Err(err) => {
+                    error!("Unable to render metrics: {}", err);
+                    *res.status_mut() = StatusCode::InternalServerError;
+                    vec![]
+                }
+            },
+            (&Method::Get, LIVENESS_PATH) => respond_with_health(&mut res, health::liveness()),
+            (&Method::Get, READINESS_PATH) => respond_with_health(&mut res, health::readiness()),
+            (_, METRICS_PATH) | (_, LIVENESS_PATH) | (_, READINESS_PATH) => {
+                *res.status_mut() = StatusCode::MethodNotAllowed;
+                vec![]
+            }
+            _ => {
+                *res.status_mut() = StatusCode::NotFound;
+                vec![]
+            }
+        };
+
+        if let Err(err) = res.send(&body) {
+            warn!("Unable to respond to metrics request: {}", err);
+        }
+    }
+}
+
+/// Serve the metrics at `/metrics`, and the health checks at `/healthz` and `/readyz`, on `address`
+pub fn listen(address: &str) -> Result<Listening, String> {
+    Server::http(address)
+        .and_then(|server| server.handle(MetricsHandler))
+        .map_err(|err| format!("Unable to start metrics and health endpoint: {}", err))
+}
+
+/// Times and counts the errors of the calls made to a repository
+pub struct MeteredRepository {
+    inner: Box<::Repository>,
+    project: Option<String>,
+    backend: &'static str,
+}
+
+impl MeteredRepository {
+    pub fn new(inner: Box<::Repository>, project: &Option<String>, backend: &'static str) -> MeteredRepository {
+        MeteredRepository {
+            inner: inner,
+            project: project.clone(),
+            backend: backend,
+        }
+    }
+}
+
+impl ::Repository for MeteredRepository {
+    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+        observe(&self.project, self.backend, || self.inner.get_pr_list())
+    }
+
+    fn get_pr(&self, id: i32) -> Result<Option<::PullRequest>, Error> {
+        observe(&self.project, self.backend, || self.inner.get_pr(id))
+    }
+
+    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.build_queued(pr, build))
+    }
+
+    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.build_running(pr, build))
+    }
+
+    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.build_success(pr, build))
+    }
+
+    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.build_failure(pr, build))
+    }
+
+    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.post_build(pr, build))
+    }
+
+    fn post_verdict(&self, pr: &::PullRequest, verdict: &::Verdict) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.post_verdict(pr, verdict))
+    }
+}
+
+/// Times and counts the errors of the calls made to a continuous integrator
+pub struct MeteredIntegrator {
+    inner: Box<::ContinuousIntegrator>,
+    project: Option<String>,
+    backend: &'static str,
+}
+
+impl MeteredIntegrator {
+    pub fn new(
+        inner: Box<::ContinuousIntegrator>,
+        project: &Option<String>,
+        backend: &'static str,
+    ) -> MeteredIntegrator {
+        MeteredIntegrator {
+            inner: inner,
+            project: project.clone(),
+            backend: backend,
+        }
+    }
+}
+
+impl ::ContinuousIntegrator for MeteredIntegrator {
+    fn build_id(&self) -> &str {
+        self.inner.build_id()
+    }
+
+    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
+        observe(&self.project, self.backend, || self.inner.get_build_list(pr))
+    }
+
+    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+        observe(&self.project, self.backend, || self.inner.get_build(build_id))
+    }
+
+    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, Error> {
+        observe(&self.project, self.backend, || self.inner.queue_build(pr))
+    }
+
+    fn refresh_vcs(&self) -> Result<(), Error> {
+        observe(&self.project, self.backend, || self.inner.refresh_vcs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use super::{observe, record, render, API_ERRORS, BUILDS};
+    use error::Error;
+    use fanout::{Fanout, Message, OpCode};
+
+    #[test]
+    fn failed_calls_are_counted_as_errors() {
+        let errors = API_ERRORS.with_label_values(&["test_backend"]);
+        let before = errors.get();
+
+        assert_eq!(Ok(1), observe(&None, "test_backend", || Ok::<i32, Error>(1)));
+        assert_eq!(before, errors.get());
+        assert!(observe(&None, "test_backend", || Err::<i32, Error>(Error::transport("Unreachable"))).is_err());
+        assert_eq!(before + 1.0, errors.get());
+    }
+
+    #[test]
+    fn broadcast_builds_are_counted() {
+        let mut fanout = Fanout::<Message>::new();
+        let recorder = record(fanout.subscribe()).unwrap();
+        let tagged = fanout.tagged("metrics_test");
+        let counted = BUILDS.with_label_values(&["metrics_test", "failed"]);
+        let before = counted.get();
+
+        tagged.broadcast(Message::new(OpCode::BuildFinished { success: false }, &"build").unwrap());
+        tagged.broadcast(Message::new(OpCode::OpenPullRequest, &"pr").unwrap());
+        drop(tagged);
+        fanout.join().unwrap();
+        recorder.join().unwrap();
+
+        assert_eq!(before + 1.0, counted.get());
+        let (content_type, body) = render().unwrap();
+        assert!(content_type.starts_with("text/plain"));
+        assert!(str::from_utf8(&body).unwrap().contains("pr_demon_builds_total"));
+    }
+}
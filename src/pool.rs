@@ -0,0 +1,131 @@
+//! Bounded pool of worker threads to handle pull requests concurrently
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam;
+
+use logging;
+use shutdown::Shutdown;
+
+/// Log output of handled items, printed in the order of the items
+struct Output {
+    next: usize,
+    pending: Vec<Option<Vec<String>>>,
+}
+
+impl Output {
+    fn new(len: usize) -> Output {
+        Output {
+            next: 0,
+            pending: (0..len).map(|_| None).collect(),
+        }
+    }
+
+    /// Store the output of an item and print every output that is no longer waiting for an earlier item
+    fn complete(&mut self, index: usize, lines: Vec<String>) -> Vec<String> {
+        self.pending[index] = Some(lines);
+
+        let mut printable = vec![];
+        while self.next < self.pending.len() {
+            match self.pending[self.next].take() {
+                Some(lines) => printable.extend(lines),
+                None => break,
+            }
+            self.next += 1;
+        }
+        printable
+    }
+}
+
+/// Call `handler` for every item on up to `workers` threads.
+///
+/// The log output of each item is printed as one group, in the order of `items`. A panic while handling an item
+/// is logged and does not affect other items. Items that have not been started when `shutdown` is requested are
+/// skipped.
+pub fn for_each<T, F>(items: &[T], workers: usize, shutdown: &Shutdown, handler: F)
+where
+    T: Sync,
+    F: Fn(&T) + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let output = Mutex::new(Output::new(items.len()));
+    let workers = cmp::max(1, cmp::min(workers, items.len()));
+
+    crossbeam::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= items.len() {
+                    break;
+                }
+
+                if shutdown.is_requested() {
+                    // Let the output of items before this one be printed
+                    let mut output = output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    logging::print(&output.complete(index, vec![]));
+                    continue;
+                }
+
+                let ((), lines) = logging::capture(|| {
+                    if panic::catch_unwind(AssertUnwindSafe(|| handler(&items[index]))).is_err() {
+                        error!("Worker panicked while handling item {}", index);
+                    }
+                });
+
+                let mut output = output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                logging::print(&output.complete(index, lines));
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{for_each, Output};
+    use shutdown::Shutdown;
+
+    #[test]
+    fn output_is_released_in_item_order() {
+        let mut output = Output::new(3);
+        assert!(output.complete(1, vec!["b".to_string()]).is_empty());
+        assert_eq!(
+            vec!["a".to_string(), "b".to_string()],
+            output.complete(0, vec!["a".to_string()])
+        );
+        assert_eq!(vec!["c".to_string()], output.complete(2, vec!["c".to_string()]));
+    }
+
+    #[test]
+    fn every_item_is_handled_despite_panics() {
+        let handled = Mutex::new(vec![]);
+        for_each(&[1, 2, 3, 4, 5], 3, &Shutdown::new(), |&item| {
+            if item == 3 {
+                panic!("Item 3 is broken");
+            }
+            thread::sleep(Duration::from_millis((10 * (5 - item)) as u64));
+            handled.lock().unwrap().push(item);
+        });
+
+        let mut handled = handled.into_inner().unwrap();
+        handled.sort();
+        assert_eq!(vec![1, 2, 4, 5], handled);
+    }
+
+    #[test]
+    fn items_are_skipped_after_shutdown_is_requested() {
+        let handled = Mutex::new(vec![]);
+        let shutdown = Shutdown::new();
+        for_each(&[1, 2, 3], 1, &shutdown, |&item| {
+            handled.lock().unwrap().push(item);
+            shutdown.request();
+        });
+
+        assert_eq!(vec![1], handled.into_inner().unwrap());
+    }
+}
@@ -0,0 +1,105 @@
+//! Detects when the configuration file should be reloaded: when it is modified, or on SIGHUP
+use std::cell::Cell;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use signal_hook;
+
+pub struct Watcher {
+    /// `None` if the configuration was read from stdin, which cannot be read again
+    path: Option<PathBuf>,
+    /// Modification time of the configuration file when it was last loaded
+    loaded: Cell<Option<SystemTime>>,
+    hangup: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    /// Watch the configuration file at `path`, which has just been loaded. `-` stands for stdin.
+    pub fn new(path: &str) -> Watcher {
+        let path = match path {
+            "-" => None,
+            path => Some(PathBuf::from(path)),
+        };
+        Watcher {
+            loaded: Cell::new(path.as_ref().and_then(|path| modified(path))),
+            path: path,
+            hangup: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Also reload the configuration on SIGHUP
+    pub fn register_hangup(self) -> Result<Watcher, String> {
+        signal_hook::flag::register(signal_hook::SIGHUP, self.hangup.clone())
+            .map_err(|err| format!("Unable to register SIGHUP handler: {}", err))?;
+        Ok(self)
+    }
+
+    /// Whether the configuration should be reloaded
+    pub fn changed(&self) -> bool {
+        if self.path.is_none() {
+            return false;
+        }
+        self.hangup.load(Ordering::SeqCst) || self.current() != self.loaded.get()
+    }
+
+    /// Path of the configuration file to reload. Marks the current configuration file as loaded.
+    pub fn reload(&self) -> Option<&PathBuf> {
+        self.hangup.store(false, Ordering::SeqCst);
+        self.loaded.set(self.current());
+        self.path.as_ref()
+    }
+
+    fn current(&self) -> Option<SystemTime> {
+        self.path.as_ref().and_then(|path| modified(path))
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, SystemTime};
+
+    use tempdir::TempDir;
+    use super::Watcher;
+
+    #[test]
+    fn changes_to_the_configuration_file_are_detected() {
+        let td = TempDir::new("reload").unwrap();
+        let path = td.path().join("config.yaml");
+        File::create(&path).unwrap().write_all(b"first").unwrap();
+
+        let watcher = Watcher::new(path.to_str().unwrap());
+        assert!(!watcher.changed());
+
+        // Pretend that the file was loaded before its last modification
+        watcher
+            .loaded
+            .set(Some(SystemTime::now() - Duration::from_secs(60)));
+        assert!(watcher.changed());
+        assert_eq!(Some(&path), watcher.reload());
+        assert!(!watcher.changed());
+
+        watcher.hangup.store(true, Ordering::SeqCst);
+        assert!(watcher.changed());
+        watcher.reload();
+        assert!(!watcher.changed());
+    }
+
+    #[test]
+    fn stdin_is_never_reloaded() {
+        let watcher = Watcher::new("-");
+        watcher.hangup.store(true, Ordering::SeqCst);
+        assert!(!watcher.changed());
+    }
+}
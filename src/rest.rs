@@ -1,10 +1,186 @@
+use std::cmp;
+use std::str;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
 use hyper::mime::{Attr, Mime, SubLevel, TopLevel, Value};
+use rand::{self, Rng};
 use reqwest;
-use reqwest::{Client, Error, Method, Response, StatusCode};
-use reqwest::header::{qitem, Accept, Authorization, Basic, ContentType, UserAgent};
+use reqwest::{Client, Method, Response, StatusCode};
+use reqwest::header::{qitem, Accept, Authorization, Basic, Bearer, ContentType, UserAgent};
 use serde::de::DeserializeOwned;
 use serde_json;
 
+use error::Error;
+use shutdown::Shutdown;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF_MS: u64 = 500;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 30_000;
+/// Milliseconds between checks for a shutdown while waiting to retry
+const POLL_INTERVAL_MS: u64 = 100;
+
+lazy_static! {
+    static ref SHARED: RwLock<Option<Arc<Shared>>> = RwLock::new(None);
+    static ref SHUTDOWN: Mutex<Shutdown> = Mutex::new(Shutdown::new());
+}
+
+/// Timeouts and retries of the requests made to every backend
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug, Default)]
+pub struct HttpConfiguration {
+    /// Seconds to wait for each read and write of a response. Defaults to 30.
+    pub timeout: Option<u64>,
+    /// Times to retry an idempotent request that failed transiently. Defaults to 3.
+    pub retries: Option<u32>,
+    /// Milliseconds to wait before the first retry, doubled for every retry after it. Defaults to 500.
+    pub backoff: Option<u64>,
+    /// Milliseconds to wait at most before a retry, even if the backend asks for longer. Defaults to 30000.
+    pub max_backoff: Option<u64>,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn new(config: &HttpConfiguration) -> RetryPolicy {
+        RetryPolicy {
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            backoff: Duration::from_millis(config.backoff.unwrap_or(DEFAULT_BACKOFF_MS)),
+            max_backoff: Duration::from_millis(config.max_backoff.unwrap_or(DEFAULT_MAX_BACKOFF_MS)),
+        }
+    }
+
+    /// Time to wait before retry `attempt`, counting from 0: as long as the backend asked for, or else an exponential
+    /// backoff with jitter so that requests that failed together are not retried together
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let delay = retry_after.unwrap_or_else(|| {
+            let exponential = 1u32
+                .checked_shl(attempt)
+                .and_then(|factor| self.backoff.checked_mul(factor))
+                .unwrap_or(self.max_backoff);
+            let millis = millis(exponential);
+            Duration::from_millis(millis / 2 + rand::thread_rng().gen_range(0, millis / 2 + 1))
+        });
+        if delay > self.max_backoff {
+            self.max_backoff
+        } else {
+            delay
+        }
+    }
+}
+
+/// The client shared by every request, so that connections are pooled
+struct Shared {
+    client: Client,
+    retry_policy: RetryPolicy,
+}
+
+impl Shared {
+    fn new(config: &HttpConfiguration) -> Result<Shared, Error> {
+        let mut client = Client::new().map_err(|err| Error::transport(err.to_string()))?;
+        client.timeout(Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS)));
+        Ok(Shared {
+            client: client,
+            retry_policy: RetryPolicy::new(config),
+        })
+    }
+}
+
+/// Make every request from now on with the timeouts and retries of `config`
+pub fn configure(config: &HttpConfiguration) -> Result<(), String> {
+    let shared = Shared::new(config).map_err(|err| format!("Unable to create HTTP client: {}", err))?;
+    *SHARED.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(shared));
+    Ok(())
+}
+
+/// Stop waiting to retry requests once `shutdown` is requested
+pub fn stop_retrying_on(shutdown: &Shutdown) {
+    *SHUTDOWN.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = shutdown.clone();
+}
+
+/// The shared client, with the default configuration unless it has been configured
+fn shared() -> Result<Arc<Shared>, Error> {
+    let configured = SHARED
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    match configured {
+        Some(shared) => Ok(shared),
+        None => {
+            let shared = Arc::new(Shared::new(&HttpConfiguration::default())?);
+            *SHARED.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(shared.clone());
+            Ok(shared)
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+}
+
+/// Requests that can be sent again without changing their outcome
+fn is_idempotent(method: &Method) -> bool {
+    match *method {
+        Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options | Method::Trace => true,
+        _ => false,
+    }
+}
+
+/// Responses that may succeed if the request is sent again later
+fn is_transient(status: &StatusCode) -> bool {
+    status.is_server_error() || *status == StatusCode::TooManyRequests
+}
+
+/// The time to wait that the backend asked for with `Retry-After`, either in seconds or as an HTTP date
+fn retry_after(headers: &reqwest::header::Headers) -> Option<Duration> {
+    let value = headers.get_raw("Retry-After")?.first()?;
+    let value = str::from_utf8(value).ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value).ok()?;
+            Some(
+                date.with_timezone(&Utc)
+                    .signed_duration_since(Utc::now())
+                    .to_std()
+                    .unwrap_or_else(|_| Duration::from_secs(0)),
+            )
+        }
+    }
+}
+
+/// How requests to a backend that accepts either a password or a token are authenticated
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Auth {
+    Basic { username: String, password: String },
+    /// Personal access tokens of Bitbucket Server, and access tokens of TeamCity
+    Bearer { token: String },
+}
+
+impl Auth {
+    /// Authenticate with the `token` setting of a backend, or else with its `username` and `password` settings
+    pub fn new(username: &Option<String>, password: &Option<String>, token: &Option<String>) -> Result<Auth, String> {
+        match (username, password, token) {
+            (_, &Some(_), &Some(_)) => Err("Only one of `password` or `token` can be configured".to_string()),
+            (_, &None, &Some(ref token)) => Ok(Auth::Bearer {
+                token: token.to_string(),
+            }),
+            (&Some(ref username), &Some(ref password), &None) => Ok(Auth::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err("Either `username` and `password`, or `token` must be configured".to_string()),
+        }
+    }
+}
+
 pub struct Headers {
     pub headers: reqwest::header::Headers,
 }
@@ -26,6 +202,29 @@ impl Headers {
         self
     }
 
+    pub fn add_auth_header(&mut self, auth: &Auth) -> &mut Headers {
+        match *auth {
+            Auth::Basic {
+                ref username,
+                ref password,
+            } => self.headers.set(Authorization(Basic {
+                username: username.to_string(),
+                password: Some(password.to_string()),
+            })),
+            Auth::Bearer { ref token } => self.headers.set(Authorization(Bearer {
+                token: token.to_string(),
+            })),
+        }
+        self
+    }
+
+    /// GitLab's `PRIVATE-TOKEN` header for personal access tokens
+    pub fn add_private_token_header(&mut self, token: &str) -> &mut Headers {
+        self.headers
+            .set_raw("PRIVATE-TOKEN", vec![token.as_bytes().to_vec()]);
+        self
+    }
+
     pub fn add_accept_json_header(&mut self) -> &mut Headers {
         self.headers.set(Accept(vec![
             qitem(Mime(
@@ -61,14 +260,36 @@ impl Headers {
     }
 }
 
-pub fn get<T>(url: &str, headers: reqwest::header::Headers) -> Result<T, String>
+pub fn get<T>(url: &str, headers: reqwest::header::Headers) -> Result<T, Error>
 where
     T: DeserializeOwned,
 {
     request(url, reqwest::Method::Get, &None, headers, &StatusCode::Ok)
 }
 
-pub fn post<T>(url: &str, body: &str, headers: reqwest::header::Headers, status_code: &StatusCode) -> Result<T, String>
+/// Retrieve the items from every page of a list API paged with `per_page` and `page`, as GitHub's and GitLab's are.
+/// A page with fewer than `page_size` items is the last.
+pub fn get_all_pages<T>(url: &str, page_size: usize, headers: reqwest::header::Headers) -> Result<Vec<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let separator = if url.contains('?') { "&" } else { "?" };
+    let mut values = vec![];
+    let mut page = 1;
+    loop {
+        let page_url = format!("{}{}per_page={}&page={}", url, separator, page_size, page);
+        let page_values = get::<Vec<T>>(&page_url, headers.clone())?;
+        let is_last_page = page_values.len() < page_size;
+        values.extend(page_values);
+
+        if is_last_page {
+            return Ok(values);
+        }
+        page += 1;
+    }
+}
+
+pub fn post<T>(url: &str, body: &str, headers: reqwest::header::Headers, status_code: &StatusCode) -> Result<T, Error>
 where
     T: DeserializeOwned,
 {
@@ -81,11 +302,15 @@ where
     )
 }
 
+pub fn get_raw(url: &str, headers: reqwest::header::Headers) -> Result<Response, Error> {
+    request_raw(url, reqwest::Method::Get, &None, headers)
+}
+
 pub fn post_raw(url: &str, body: &str, headers: reqwest::header::Headers) -> Result<Response, Error> {
     request_raw(url, reqwest::Method::Post, &Some(body.to_owned()), headers)
 }
 
-pub fn put<T>(url: &str, body: &str, headers: reqwest::header::Headers, status_code: &StatusCode) -> Result<T, String>
+pub fn put<T>(url: &str, body: &str, headers: reqwest::header::Headers, status_code: &StatusCode) -> Result<T, Error>
 where
     T: DeserializeOwned,
 {
@@ -98,14 +323,27 @@ where
     )
 }
 
-fn request_raw(
+pub fn patch<T>(url: &str, body: &str, headers: reqwest::header::Headers, status_code: &StatusCode) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    request(
+        url,
+        reqwest::Method::Patch,
+        &Some(body.to_owned()),
+        headers,
+        status_code,
+    )
+}
+
+fn send(
+    client: &Client,
     url: &str,
     method: Method,
     body: &Option<String>,
     headers: reqwest::header::Headers,
 ) -> Result<Response, Error> {
     debug!("Requesting {} with {}", url, headers);
-    let client = Client::new()?;
     let request_builder = client.request(method, url);
     let request_builder = request_builder.headers(headers);
 
@@ -113,7 +351,89 @@ fn request_raw(
         Some(ref body_content) => request_builder.body(body_content.clone()),
         None => request_builder,
     };
-    request_builder.send()
+    request_builder
+        .send()
+        .map_err(|err| Error::transport(err.to_string()))
+}
+
+/// Send the request, retrying idempotent requests that fail transiently
+fn request_raw(
+    url: &str,
+    method: Method,
+    body: &Option<String>,
+    headers: reqwest::header::Headers,
+) -> Result<Response, Error> {
+    let shared = shared()?;
+    let shutdown = SHUTDOWN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    send_with_retries(&shared, &shutdown, url, method, body, headers)
+}
+
+/// Sleep for `duration`, unless a shutdown is requested in the meantime. Returns whether the sleep was cut short.
+fn sleep_unless_shutdown(duration: Duration, shutdown: &Shutdown) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if shutdown.is_requested() {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        thread::sleep(cmp::min(deadline - now, Duration::from_millis(POLL_INTERVAL_MS)));
+    }
+}
+
+fn send_with_retries(
+    shared: &Shared,
+    shutdown: &Shutdown,
+    url: &str,
+    method: Method,
+    body: &Option<String>,
+    headers: reqwest::header::Headers,
+) -> Result<Response, Error> {
+    let retry_policy = &shared.retry_policy;
+    let retries = if is_idempotent(&method) {
+        retry_policy.retries
+    } else {
+        0
+    };
+
+    let mut attempt = 0;
+    loop {
+        let result = send(&shared.client, url, method.clone(), body, headers.clone());
+        let retry = match result {
+            Ok(ref response) if !is_transient(response.status()) => None,
+            _ if attempt >= retries => None,
+            Ok(ref response) => Some((
+                response.status().to_string(),
+                retry_policy.delay(attempt, retry_after(response.headers())),
+            )),
+            Err(ref err) => Some((err.to_string(), retry_policy.delay(attempt, None))),
+        };
+
+        match retry {
+            None => return result,
+            Some((reason, delay)) => {
+                warn!(
+                    "{} {} failed with {} — retrying in {}ms ({} of {})",
+                    method,
+                    url,
+                    reason,
+                    millis(delay),
+                    attempt + 1,
+                    retries
+                );
+                if sleep_unless_shutdown(delay, shutdown) {
+                    info!("Shutdown requested — not retrying {} {}", method, url);
+                    return result;
+                }
+                attempt += 1;
+            }
+        }
+    }
 }
 
 fn request<T>(
@@ -122,15 +442,197 @@ fn request<T>(
     body: &Option<String>,
     headers: reqwest::header::Headers,
     status_code: &StatusCode,
-) -> Result<T, String>
+) -> Result<T, Error>
 where
     T: DeserializeOwned,
 {
-    let response = request_raw(url, method, body, headers).map_err(|err| err.to_string())?;
+    let response = request_raw(url, method, body, headers)?;
     match response.status() {
         status if status == status_code => (),
-        e => return Err(e.to_string()),
+        status => return Err(Error::status(status.to_u16(), status.to_string())),
     };
 
-    serde_json::from_reader(response).map_err(|err| format!("Error parsing response: {}", err))
+    serde_json::from_reader(response).map_err(|err| Error::decode(format!("Error parsing response: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use reqwest::{Client, Method, StatusCode};
+    use reqwest::header::Headers;
+
+    use super::{get_all_pages, retry_after, send_with_retries, Auth, RetryPolicy, Shared};
+    use shutdown::Shutdown;
+    use test_server::{StubResponse, StubServer};
+
+    fn retry_policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries: retries,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        }
+    }
+
+    fn shared(retry_policy: RetryPolicy) -> Shared {
+        Shared {
+            client: Client::new().unwrap(),
+            retry_policy: retry_policy,
+        }
+    }
+
+    /// Responds with 503 to the first `failures` requests, and with 200 after
+    fn flaky_server(failures: usize) -> StubServer {
+        let received = Arc::new(AtomicUsize::new(0));
+        StubServer::new(move |_| {
+            if received.fetch_add(1, Ordering::SeqCst) < failures {
+                StubResponse::json(StatusCode::ServiceUnavailable, "").with_header("Retry-After", "0")
+            } else {
+                StubResponse::json(StatusCode::Ok, "{}")
+            }
+        })
+    }
+
+    #[test]
+    fn idempotent_requests_are_retried_until_they_succeed() {
+        let server = flaky_server(2);
+
+        let response = send_with_retries(
+            &shared(retry_policy(3)),
+            &Shutdown::new(),
+            &server.url(),
+            Method::Get,
+            &None,
+            Headers::new(),
+        ).unwrap();
+        assert_eq!(&StatusCode::Ok, response.status());
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[test]
+    fn retries_are_given_up_after_the_configured_number() {
+        let server = flaky_server(10);
+
+        let response = send_with_retries(
+            &shared(retry_policy(2)),
+            &Shutdown::new(),
+            &server.url(),
+            Method::Get,
+            &None,
+            Headers::new(),
+        ).unwrap();
+        assert_eq!(&StatusCode::ServiceUnavailable, response.status());
+        assert_eq!(3, server.requests().len());
+    }
+
+    #[test]
+    fn non_idempotent_requests_are_not_retried() {
+        let server = flaky_server(1);
+
+        let body = Some("{}".to_string());
+        let response = send_with_retries(
+            &shared(retry_policy(3)),
+            &Shutdown::new(),
+            &server.url(),
+            Method::Post,
+            &body,
+            Headers::new(),
+        ).unwrap();
+        assert_eq!(&StatusCode::ServiceUnavailable, response.status());
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn retries_are_given_up_once_shutdown_is_requested() {
+        let server = flaky_server(10);
+        let shutdown = Shutdown::new();
+        shutdown.request();
+
+        let response = send_with_retries(
+            &shared(retry_policy(3)),
+            &shutdown,
+            &server.url(),
+            Method::Get,
+            &None,
+            Headers::new(),
+        ).unwrap();
+        assert_eq!(&StatusCode::ServiceUnavailable, response.status());
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn numbered_pages_are_requested_until_a_page_is_not_full() {
+        let server = StubServer::new(|request| {
+            if request.path.ends_with("page=1") {
+                StubResponse::json(StatusCode::Ok, "[1, 2]")
+            } else {
+                StubResponse::json(StatusCode::Ok, "[3]")
+            }
+        });
+
+        let values = get_all_pages::<i32>(&format!("{}/items?state=open", server.url()), 2, Headers::new()).unwrap();
+        assert_eq!(vec![1, 2, 3], values);
+
+        let paths: Vec<String> = server.requests().into_iter().map(|request| request.path).collect();
+        assert_eq!(
+            vec![
+                "/items?state=open&per_page=2&page=1".to_string(),
+                "/items?state=open&per_page=2&page=2".to_string(),
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn backoff_is_jittered_exponential_and_capped() {
+        let policy = RetryPolicy {
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+
+        let first = policy.delay(0, None);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let third = policy.delay(2, None);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+        assert!(policy.delay(40, None) <= Duration::from_millis(1000));
+        assert_eq!(Duration::from_secs(1), policy.delay(0, Some(Duration::from_secs(120))));
+    }
+
+    #[test]
+    fn a_token_is_used_instead_of_a_password() {
+        let username = Some("username".to_string());
+        let password = Some("password".to_string());
+        let token = Some("token".to_string());
+
+        assert_eq!(
+            Ok(Auth::Bearer {
+                token: "token".to_string(),
+            }),
+            Auth::new(&None, &None, &token)
+        );
+        assert_eq!(
+            Ok(Auth::Basic {
+                username: "username".to_string(),
+                password: "password".to_string(),
+            }),
+            Auth::new(&username, &password, &None)
+        );
+        assert!(Auth::new(&username, &password, &token).is_err());
+        assert!(Auth::new(&None, &password, &None).is_err());
+    }
+
+    #[test]
+    fn retry_after_is_read_in_seconds_or_as_a_date() {
+        let mut headers = Headers::new();
+        assert_eq!(None, retry_after(&headers));
+
+        headers.set_raw("Retry-After", vec![b"120".to_vec()]);
+        assert_eq!(Some(Duration::from_secs(120)), retry_after(&headers));
+
+        headers.set_raw("Retry-After", vec![b"Wed, 21 Oct 2015 07:28:00 GMT".to_vec()]);
+        assert_eq!(Some(Duration::from_secs(0)), retry_after(&headers));
+    }
 }
@@ -0,0 +1,161 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{self, DateTime, TimeZone};
+use cron;
+
+/// Determines when the next iteration of the main loop should run
+pub enum Schedule {
+    Fixed(Duration),
+    /// The earliest upcoming time across all the expressions wins
+    Cron(Vec<cron::Schedule>),
+}
+
+impl Schedule {
+    pub fn from_interval(interval: &::Interval) -> Result<Schedule, String> {
+        match *interval {
+            ::Interval::Fixed { interval } => Ok(Schedule::Fixed(Duration::new(interval, 0))),
+            ::Interval::Cron { ref expression } => {
+                let expressions = expression.to_vec();
+                if expressions.is_empty() {
+                    Err("At least one cron expression is required")?;
+                }
+
+                let schedules = expressions
+                    .iter()
+                    .map(|expression| {
+                        cron::Schedule::from_str(expression)
+                            .map_err(|e| format!("Invalid cron expression `{}`: {}", expression, e))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok(Schedule::Cron(schedules))
+            }
+        }
+    }
+
+    /// Returns the next time the loop should run after `now`, or `None` if the schedule will never fire again
+    pub fn next_after<Z>(&self, now: &DateTime<Z>) -> Option<DateTime<Z>>
+    where
+        Z: TimeZone,
+    {
+        match *self {
+            Schedule::Fixed(ref duration) => {
+                Some(now.clone() + chrono::Duration::seconds(duration.as_secs() as i64))
+            }
+            Schedule::Cron(ref schedules) => schedules
+                .iter()
+                .filter_map(|schedule| schedule.after(now).next())
+                .min(),
+        }
+    }
+
+    /// Returns the time between the next two times the loop should run after `now`, or `None` if the schedule will
+    /// not fire twice more
+    pub fn period_after<Z>(&self, now: &DateTime<Z>) -> Option<Duration>
+    where
+        Z: TimeZone,
+    {
+        let next = self.next_after(now)?;
+        let following = self.next_after(&next)?;
+        Some(Self::duration_until(&next, &following))
+    }
+
+    /// Duration to sleep from `now` until `next`
+    pub fn duration_until<Z>(now: &DateTime<Z>, next: &DateTime<Z>) -> Duration
+    where
+        Z: TimeZone,
+    {
+        next.clone()
+            .signed_duration_since(now.clone())
+            .to_std()
+            .unwrap_or_else(|_| Duration::new(0, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::Schedule;
+    use {Interval, OneOrMany};
+
+    fn office_hours() -> Interval {
+        Interval::Cron {
+            expression: OneOrMany::Many(vec![
+                "0 */2 9-17 * * *".to_string(),
+                "0 */30 * * * *".to_string(),
+            ]),
+        }
+    }
+
+    #[test]
+    fn fixed_interval_fires_after_interval() {
+        let schedule = Schedule::from_interval(&Interval::Fixed { interval: 999 }).unwrap();
+        let now = Utc.ymd(2018, 6, 4).and_hms(10, 0, 0);
+
+        let next = schedule.next_after(&now).unwrap();
+        assert_eq!(Utc.ymd(2018, 6, 4).and_hms(10, 16, 39), next);
+        assert_eq!(Duration::new(999, 0), Schedule::duration_until(&now, &next));
+    }
+
+    #[test]
+    fn single_cron_expression_is_parsed() {
+        let interval = Interval::Cron {
+            expression: OneOrMany::One("0 */5 * * * *".to_string()),
+        };
+        let schedule = Schedule::from_interval(&interval).unwrap();
+        let now = Utc.ymd(2018, 6, 4).and_hms(10, 1, 0);
+
+        assert_eq!(
+            Some(Utc.ymd(2018, 6, 4).and_hms(10, 5, 0)),
+            schedule.next_after(&now)
+        );
+    }
+
+    #[test]
+    fn earliest_cron_expression_wins() {
+        let schedule = Schedule::from_interval(&office_hours()).unwrap();
+
+        let during_office_hours = Utc.ymd(2018, 6, 4).and_hms(10, 1, 0);
+        assert_eq!(
+            Some(Utc.ymd(2018, 6, 4).and_hms(10, 2, 0)),
+            schedule.next_after(&during_office_hours)
+        );
+
+        let after_office_hours = Utc.ymd(2018, 6, 4).and_hms(20, 1, 0);
+        assert_eq!(
+            Some(Utc.ymd(2018, 6, 4).and_hms(20, 30, 0)),
+            schedule.next_after(&after_office_hours)
+        );
+    }
+
+    #[test]
+    fn period_is_the_time_between_the_next_two_runs() {
+        let fixed = Schedule::from_interval(&Interval::Fixed { interval: 999 }).unwrap();
+        let now = Utc.ymd(2018, 6, 4).and_hms(10, 0, 0);
+        assert_eq!(Some(Duration::new(999, 0)), fixed.period_after(&now));
+
+        // Moments before a run, the period is still that of the schedule
+        let schedule = Schedule::from_interval(&office_hours()).unwrap();
+        let just_before_a_run = Utc.ymd(2018, 6, 4).and_hms(10, 1, 59);
+        assert_eq!(Some(Duration::new(120, 0)), schedule.period_after(&just_before_a_run));
+    }
+
+    #[test]
+    fn invalid_cron_expressions_are_rejected() {
+        let interval = Interval::Cron {
+            expression: OneOrMany::One("not a cron expression".to_string()),
+        };
+        assert!(Schedule::from_interval(&interval).is_err());
+    }
+
+    #[test]
+    fn empty_cron_expressions_are_rejected() {
+        let interval = Interval::Cron {
+            expression: OneOrMany::Many(vec![]),
+        };
+        assert!(Schedule::from_interval(&interval).is_err());
+    }
+}
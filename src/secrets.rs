@@ -0,0 +1,155 @@
+//! Secrets referenced from the configuration file, so that they need not be written in it
+//!
+//! Only the credentials are resolved: the values of the `password`, `token`, `secret` and `key_passphrase` settings,
+//! and any value nested under them. They can contain `${env:NAME}`, which is replaced by the environment variable
+//! `NAME`. A value of `file:PATH` is replaced by the contents of the file at `PATH`, without trailing newlines. Any
+//! other setting is left as it is written, so that URLs, paths and commands need no escaping.
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+use serde_yaml::{Mapping, Value};
+
+const ENV_PREFIX: &'static str = "${env:";
+const FILE_PREFIX: &'static str = "file:";
+/// Settings that hold credentials, whose values are resolved
+const CREDENTIAL_KEYS: &'static [&'static str] = &["password", "token", "secret", "key_passphrase"];
+
+/// Replace every secret referenced by the credentials of `value`
+pub fn resolve(value: Value) -> Result<Value, String> {
+    resolve_at(value, "", false)
+}
+
+/// Resolve the credentials in `value`, which is itself one if `is_credential`
+fn resolve_at(value: Value, path: &str, is_credential: bool) -> Result<Value, String> {
+    match value {
+        Value::String(string) if is_credential => resolve_string(&string, path).map(Value::String),
+        Value::Sequence(values) => values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| resolve_at(value, &format!("{}[{}]", path, index), is_credential))
+            .collect::<Result<Vec<_>, String>>()
+            .map(Value::Sequence),
+        Value::Mapping(mapping) => mapping
+            .into_iter()
+            .map(|(key, value)| {
+                let key_path = match key {
+                    Value::String(ref key) if path.is_empty() => key.to_string(),
+                    Value::String(ref key) => format!("{}.{}", path, key),
+                    ref key => format!("{}.{:?}", path, key),
+                };
+                let is_credential = match key {
+                    Value::String(ref key) => is_credential || CREDENTIAL_KEYS.contains(&&**key),
+                    _ => is_credential,
+                };
+                resolve_at(value, &key_path, is_credential).map(|value| (key, value))
+            })
+            .collect::<Result<Mapping, String>>()
+            .map(Value::Mapping),
+        value => Ok(value),
+    }
+}
+
+fn resolve_string(string: &str, path: &str) -> Result<String, String> {
+    if string.starts_with(FILE_PREFIX) {
+        return read_file(&string[FILE_PREFIX.len()..], path);
+    }
+
+    let mut resolved = String::new();
+    let mut rest = string;
+    while let Some(start) = rest.find(ENV_PREFIX) {
+        let name_start = start + ENV_PREFIX.len();
+        let name_end = match rest[name_start..].find('}') {
+            Some(length) => name_start + length,
+            None => return Err(format!("Unterminated `{}` in `{}`", ENV_PREFIX, path)),
+        };
+        let name = &rest[name_start..name_end];
+        let variable = env::var(name).map_err(|err| {
+            format!(
+                "Unable to read environment variable `{}` referenced by `{}`: {}",
+                name, path, err
+            )
+        })?;
+
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(&variable);
+        rest = &rest[name_end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+fn read_file(file_path: &str, path: &str) -> Result<String, String> {
+    let mut contents = String::new();
+    File::open(file_path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| format!("Unable to read file {} referenced by `{}`: {}", file_path, path, err))?;
+    Ok(contents.trim_right_matches(|c| c == '\n' || c == '\r').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use serde_yaml::{self, Value};
+
+    use tempdir::TempDir;
+    use super::resolve;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn environment_variables_are_substituted() {
+        env::set_var("PR_DEMON_SECRETS_TEST_PASSWORD", "hunter2");
+        env::set_var("PR_DEMON_SECRETS_TEST_HOST", "bitbucket.example.com");
+
+        let resolved = resolve(yaml(
+            "bitbucket:\n  password: ${env:PR_DEMON_SECRETS_TEST_PASSWORD}\n  \
+             base_url: https://${env:PR_DEMON_SECRETS_TEST_HOST}/stash\n  page_size: 25\n",
+        )).unwrap();
+        assert_eq!(
+            yaml(
+                "bitbucket:\n  password: hunter2\n  \
+                 base_url: https://${env:PR_DEMON_SECRETS_TEST_HOST}/stash\n  page_size: 25\n"
+            ),
+            resolved
+        );
+    }
+
+    #[test]
+    fn files_are_read_without_trailing_newlines() {
+        let td = TempDir::new("secrets").unwrap();
+        let path = td.path().join("tc_token");
+        File::create(&path).unwrap().write_all(b"token\n").unwrap();
+
+        let resolved = resolve(yaml(&format!(
+            "projects:\n  - teamcity:\n      token: file:{}\n    fusionner:\n      repository:\n        \
+             uri: file:/srv/repo.git\n        password:\n          password: file:{0}\n",
+            path.display()
+        ))).unwrap();
+        assert_eq!(
+            yaml(
+                "projects:\n  - teamcity:\n      token: token\n    fusionner:\n      repository:\n        \
+                 uri: file:/srv/repo.git\n        password:\n          password: token\n"
+            ),
+            resolved
+        );
+    }
+
+    #[test]
+    fn missing_secrets_are_reported_with_where_they_are_referenced() {
+        env::remove_var("PR_DEMON_SECRETS_TEST_MISSING");
+
+        let err = resolve(yaml("teamcity:\n  password: ${env:PR_DEMON_SECRETS_TEST_MISSING}\n")).unwrap_err();
+        assert!(err.contains("`PR_DEMON_SECRETS_TEST_MISSING`"));
+        assert!(err.contains("`teamcity.password`"));
+
+        let err = resolve(yaml("projects:\n  - bitbucket:\n      password: file:/nonexistent/secret\n")).unwrap_err();
+        assert!(err.contains("/nonexistent/secret"));
+        assert!(err.contains("`projects[0].bitbucket.password`"));
+    }
+}
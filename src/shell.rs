@@ -0,0 +1,693 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::Builder;
+
+use serde_json;
+use url::Url;
+
+use error::Error;
+
+/// Name used as the build configuration ID
+pub const BUILD_ID: &'static str = "shell";
+const DEFAULT_CONCURRENCY: usize = 1;
+const DEFAULT_KEEP_BUILDS: usize = 1000;
+
+lazy_static! {
+    /// Shells started by this process, by state directory, so that reloading the configuration does not run builds
+    /// twice
+    static ref STARTED: Mutex<HashMap<PathBuf, Started>> = Mutex::new(HashMap::new());
+    /// Serialises the git commands that change worktrees, which cannot run at the same time in a repository
+    static ref WORKTREES: Mutex<()> = Mutex::new(());
+}
+
+struct Started {
+    config: ShellConfiguration,
+    /// Shared by every shell of the state directory, so that build IDs are only allocated once
+    lock: Arc<Mutex<()>>,
+    queue: Weak<Mutex<Sender<i32>>>,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct ShellConfiguration {
+    /// Command to run with `sh -c`. Details of the PR are available in `PR_DEMON_*` environment variables.
+    pub command: String,
+    /// Directory where build records, logs and workspaces are kept
+    pub state_path: String,
+    /// Path to a local git repository. If set, each build runs in a worktree of this repository, checked out at the
+    /// commit to build. Otherwise, each build runs in an empty directory.
+    pub repository_path: Option<String>,
+    /// Number of builds to run at the same time. Defaults to 1.
+    pub concurrency: Option<usize>,
+    /// Number of the latest builds whose records and logs are kept. Defaults to 1000.
+    pub keep_builds: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+struct BuildRecord {
+    id: i32,
+    pr: ::PullRequest,
+    state: ::BuildState,
+    status: ::BuildStatus,
+    exit_code: Option<i32>,
+    error: Option<String>,
+}
+
+/// Build records and logs persisted in the state directory, with the IDs of the builds of each commit indexed as empty
+/// files in `commits/<commit>/`
+#[derive(Clone)]
+struct Store {
+    path: PathBuf,
+    /// Serialises allocation of build IDs and writes to records
+    lock: Arc<Mutex<()>>,
+}
+
+impl Store {
+    fn new(path: &str) -> Result<Store, String> {
+        let path = PathBuf::from(path);
+        // State directories from before the index was kept need their builds indexed
+        let indexed = path.join("commits").exists();
+        for directory in &["builds", "commits", "workspaces"] {
+            fs::create_dir_all(path.join(directory))
+                .map_err(|e| format!("Unable to create state directory {:?}: {}", path, e))?;
+        }
+        let path = fs::canonicalize(&path).map_err(|e| e.to_string())?;
+
+        let store = Store {
+            path: path,
+            lock: Arc::new(Mutex::new(())),
+        };
+        if !indexed {
+            for record in store.list()? {
+                store.index(&record)?;
+            }
+        }
+        Ok(store)
+    }
+
+    fn record_path(&self, id: i32) -> PathBuf {
+        self.path.join("builds").join(format!("{}.json", id))
+    }
+
+    fn log_path(&self, id: i32) -> PathBuf {
+        self.path.join("builds").join(format!("{}.log", id))
+    }
+
+    fn workspace_path(&self, id: i32) -> PathBuf {
+        self.path.join("workspaces").join(id.to_string())
+    }
+
+    fn commit_path(&self, commit: &str) -> PathBuf {
+        self.path.join("commits").join(commit)
+    }
+
+    fn read(&self, id: i32) -> Result<BuildRecord, Error> {
+        let file = File::open(self.record_path(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::not_found(format!("Build {} does not exist", id)),
+            _ => Error::from(format!("Unable to read build {}: {}", id, e)),
+        })?;
+        serde_json::from_reader(file).map_err(|e| Error::decode(format!("Unable to decode build {}: {}", id, e)))
+    }
+
+    fn write(&self, record: &BuildRecord) -> Result<(), String> {
+        let _lock = self.lock.lock().unwrap();
+        self.write_unlocked(record)
+    }
+
+    /// Write to a temporary file first so that readers never see a partially written record
+    fn write_unlocked(&self, record: &BuildRecord) -> Result<(), String> {
+        let path = self.record_path(record.id);
+        let temporary_path = path.with_extension("json.tmp");
+        {
+            let mut file = File::create(&temporary_path).map_err(|e| e.to_string())?;
+            let encoded = serde_json::to_string(record).map_err(|e| e.to_string())?;
+            file.write_all(encoded.as_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        fs::rename(&temporary_path, &path).map_err(|e| format!("Unable to write build {}: {}", record.id, e))
+    }
+
+    /// IDs of the files in `directory` named `<id>` followed by `extension`, in ascending order
+    fn ids_in(directory: &Path, extension: &str) -> Result<Vec<i32>, String> {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut ids = vec![];
+        for entry in entries {
+            let name = entry.map_err(|e| e.to_string())?.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(extension) {
+                if let Ok(id) = name[..name.len() - extension.len()].parse::<i32>() {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn ids(&self) -> Result<Vec<i32>, String> {
+        Self::ids_in(&self.path.join("builds"), ".json")
+    }
+
+    /// IDs of the builds of `commit`, in ascending order
+    fn ids_of_commit(&self, commit: &str) -> Result<Vec<i32>, String> {
+        Self::ids_in(&self.commit_path(commit), "")
+    }
+
+    fn list(&self) -> Result<Vec<BuildRecord>, String> {
+        self.ids()?
+            .into_iter()
+            .map(|id| self.read(id).map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    fn index(&self, record: &BuildRecord) -> Result<(), String> {
+        let commit_path = self.commit_path(&record.pr.from_commit);
+        fs::create_dir_all(&commit_path)
+            .and_then(|_| File::create(commit_path.join(record.id.to_string())))
+            .map(|_| ())
+            .map_err(|e| format!("Unable to index build {}: {}", record.id, e))
+    }
+
+    /// Remove the records, logs and index entries of the finished builds older than the latest `keep`
+    fn prune(&self, keep: usize) -> Result<(), String> {
+        let _lock = self.lock.lock().unwrap();
+        let ids = self.ids()?;
+        let old = ids.len().saturating_sub(keep);
+        for &id in &ids[..old] {
+            let record = self.read(id).map_err(|e| e.to_string())?;
+            if record.state != ::BuildState::Finished {
+                continue;
+            }
+            debug!("Pruning build {} of PR #{}", id, record.pr.id);
+            let commit_path = self.commit_path(&record.pr.from_commit);
+            for path in &[commit_path.join(id.to_string()), self.log_path(id), self.record_path(id)] {
+                if let Err(e) = fs::remove_file(path) {
+                    if e.kind() != ErrorKind::NotFound {
+                        return Err(format!("Unable to prune build {}: {}", id, e));
+                    }
+                }
+            }
+            // Only succeeds once the commit has no builds left
+            let _ = fs::remove_dir(&commit_path);
+        }
+        Ok(())
+    }
+
+    /// Allocate a new build ID and record the build as queued
+    fn create(&self, pr: &::PullRequest) -> Result<BuildRecord, String> {
+        let _lock = self.lock.lock().unwrap();
+        // Only old builds are pruned, so the latest ID is never reused
+        let id = self.ids()?.last().cloned().unwrap_or(0) + 1;
+        let record = BuildRecord {
+            id: id,
+            pr: pr.clone(),
+            state: ::BuildState::Queued,
+            status: ::BuildStatus::Unknown,
+            exit_code: None,
+            error: None,
+        };
+        self.write_unlocked(&record)?;
+        self.index(&record)?;
+        Ok(record)
+    }
+
+    fn to_build_details(&self, record: &BuildRecord) -> ::BuildDetails {
+        let log_path = self.log_path(record.id);
+        let web_url = Url::from_file_path(&log_path)
+            .map(|url| url.to_string())
+            .unwrap_or_else(|_| log_path.to_string_lossy().to_string());
+        let status_text = match (&record.error, record.exit_code) {
+            (&Some(ref error), _) => Some(error.to_string()),
+            (&None, Some(code)) => Some(format!("Exited with status {}", code)),
+            (&None, None) if record.state == ::BuildState::Finished => Some("Terminated by signal".to_string()),
+            (&None, None) => None,
+        };
+
+        ::BuildDetails {
+            id: record.id,
+            build_id: BUILD_ID.to_string(),
+            web_url: web_url,
+            commit: Some(record.pr.from_commit.to_string()),
+            branch_name: format!("pull/{}/merge", record.pr.id),
+            state: record.state.clone(),
+            status: record.status.clone(),
+            status_text: status_text,
+        }
+    }
+}
+
+/// Runs builds as local shell commands
+pub struct Shell {
+    store: Store,
+    queue: Arc<Mutex<Sender<i32>>>,
+}
+
+impl Shell {
+    /// Start running builds in the state directory of `config`. A shell that is still running with the same
+    /// configuration is reused, and builds interrupted by a restart are only resumed by the first shell of the process.
+    pub fn new(config: &ShellConfiguration) -> Result<Shell, String> {
+        let concurrency = config.concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+        if concurrency == 0 {
+            return Err("`concurrency` must be at least 1".to_string());
+        }
+
+        let mut started = STARTED.lock().unwrap();
+        let mut store = Store::new(&config.state_path)?;
+        let resume = match started.get(&store.path) {
+            Some(started) => {
+                store.lock = started.lock.clone();
+                if started.config == *config {
+                    if let Some(queue) = started.queue.upgrade() {
+                        return Ok(Shell {
+                            store: store,
+                            queue: queue,
+                        });
+                    }
+                }
+                // Builds queued by the previous shell are still run by its workers
+                false
+            }
+            None => true,
+        };
+
+        let (sender, receiver) = channel::<i32>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..concurrency {
+            let config = config.clone();
+            let store = store.clone();
+            let receiver = receiver.clone();
+            Builder::new()
+                .name(format!("shell_{}", index))
+                .spawn(move || Self::work(&config, &store, &receiver))
+                .map_err(|e| e.to_string())?;
+        }
+
+        // Resume builds that were interrupted by a restart
+        if resume {
+            for mut record in store.list()? {
+                if record.state != ::BuildState::Finished {
+                    info!("Resuming build {} for PR #{}", record.id, record.pr.id);
+                    record.state = ::BuildState::Queued;
+                    store.write(&record)?;
+                    sender.send(record.id).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+
+        let queue = Arc::new(Mutex::new(sender));
+        started.insert(
+            store.path.clone(),
+            Started {
+                config: config.clone(),
+                lock: store.lock.clone(),
+                queue: Arc::downgrade(&queue),
+            },
+        );
+        Ok(Shell {
+            store: store,
+            queue: queue,
+        })
+    }
+
+    /// Look up the builds in the state directory of `config` without running any, e.g. for a dry run
+    pub fn read_only(config: &ShellConfiguration) -> Result<Shell, String> {
+        let store = Store::new(&config.state_path)?;
+        // Nothing receives from the queue, as dry runs never queue builds
+        let (sender, _) = channel::<i32>();
+        Ok(Shell {
+            store: store,
+            queue: Arc::new(Mutex::new(sender)),
+        })
+    }
+
+    fn work(config: &ShellConfiguration, store: &Store, receiver: &Mutex<Receiver<i32>>) {
+        loop {
+            let id = match receiver.lock().unwrap().recv() {
+                Ok(id) => id,
+                // `Shell` has been dropped
+                Err(_) => return,
+            };
+            if let Err(err) = Self::run(config, store, id) {
+                error!("Error running build {}: {}", id, err);
+            }
+        }
+    }
+
+    fn run(config: &ShellConfiguration, store: &Store, id: i32) -> Result<(), String> {
+        let mut record = store.read(id).map_err(|e| e.to_string())?;
+        record.state = ::BuildState::Running;
+        store.write(&record)?;
+
+        debug!("Running build {} for PR #{}", id, record.pr.id);
+        match Self::execute(config, store, &record) {
+            Ok(status) => {
+                record.exit_code = status.code();
+                record.status = if status.success() {
+                    ::BuildStatus::Success
+                } else {
+                    ::BuildStatus::Failure
+                };
+            }
+            Err(err) => {
+                record.error = Some(err);
+                record.status = ::BuildStatus::Failure;
+            }
+        }
+
+        record.state = ::BuildState::Finished;
+        store.write(&record)?;
+        if let Err(err) = store.prune(config.keep_builds.unwrap_or(DEFAULT_KEEP_BUILDS)) {
+            warn!("Unable to prune old builds: {}", err);
+        }
+        Ok(())
+    }
+
+    fn execute(config: &ShellConfiguration, store: &Store, record: &BuildRecord) -> Result<ExitStatus, String> {
+        let log = File::create(store.log_path(record.id)).map_err(|e| format!("Unable to create log: {}", e))?;
+        let stderr_log = log.try_clone().map_err(|e| e.to_string())?;
+
+        let workspace = store.workspace_path(record.id);
+        // The workspace might be left over from an interrupted run
+        if workspace.exists() {
+            fs::remove_dir_all(&workspace).map_err(|e| e.to_string())?;
+        }
+        match config.repository_path {
+            Some(ref repository) => {
+                let workspace = workspace.to_string_lossy();
+                let commit = format!("{}^{{commit}}", record.pr.from_commit);
+                let _worktrees = WORKTREES.lock().unwrap();
+                git(repository, &["worktree", "prune"])?;
+                if git(repository, &["cat-file", "-e", commit.as_str()]).is_err() {
+                    git(
+                        repository,
+                        &["fetch", "--quiet", "origin", record.pr.from_ref.as_str()],
+                    )?;
+                }
+                git(
+                    repository,
+                    &[
+                        "worktree",
+                        "add",
+                        "--detach",
+                        &*workspace,
+                        record.pr.from_commit.as_str(),
+                    ],
+                )?;
+            }
+            None => fs::create_dir_all(&workspace).map_err(|e| e.to_string())?,
+        }
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&config.command)
+            .current_dir(&workspace)
+            .env("PR_DEMON_BUILD_ID", record.id.to_string())
+            .env("PR_DEMON_PR_ID", record.pr.id.to_string())
+            .env("PR_DEMON_PR_URL", &record.pr.web_url)
+            .env("PR_DEMON_FROM_REF", &record.pr.from_ref)
+            .env("PR_DEMON_FROM_COMMIT", &record.pr.from_commit)
+            .env("PR_DEMON_TO_REF", &record.pr.to_ref)
+            .env("PR_DEMON_TO_COMMIT", &record.pr.to_commit)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(log))
+            .stderr(Stdio::from(stderr_log))
+            .status()
+            .map_err(|e| format!("Unable to run command: {}", e));
+
+        fs::remove_dir_all(&workspace).map_err(|e| e.to_string())?;
+        if let Some(ref repository) = config.repository_path {
+            let _worktrees = WORKTREES.lock().unwrap();
+            git(repository, &["worktree", "prune"])?;
+        }
+        status
+    }
+}
+
+fn git(repository: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repository)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Unable to run git: {}", e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+impl ::ContinuousIntegrator for Shell {
+    fn build_id(&self) -> &str {
+        BUILD_ID
+    }
+
+    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
+        Ok(self.store
+            .ids_of_commit(&pr.from_commit)?
+            .into_iter()
+            .rev()
+            .map(|id| ::Build { id: id })
+            .collect())
+    }
+
+    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+        let record = self.store.read(build_id)?;
+        Ok(self.store.to_build_details(&record))
+    }
+
+    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, Error> {
+        let record = self.store.create(pr)?;
+        self.queue
+            .lock()
+            .unwrap()
+            .send(record.id)
+            .map_err(|e| format!("Build runner has stopped: {}", e))?;
+        Ok(self.store.to_build_details(&record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+    use ContinuousIntegrator;
+    use super::{BuildRecord, Shell, ShellConfiguration, Store};
+
+    fn config(tempdir: &TempDir, command: &str) -> ShellConfiguration {
+        ShellConfiguration {
+            command: command.to_string(),
+            state_path: tempdir.path().to_str().unwrap().to_string(),
+            repository_path: None,
+            concurrency: None,
+            keep_builds: None,
+        }
+    }
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 111,
+            web_url: "http://www.foobar.com/pr/111".to_owned(),
+            from_ref: "refs/heads/branch_name".to_owned(),
+            from_commit: "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b".to_owned(),
+            to_ref: "refs/heads/master".to_owned(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_owned(),
+            title: "A very important PR".to_owned(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_owned(),
+                email: "aaron@xiao.ming".to_owned(),
+            },
+        }
+    }
+
+    /// Poll until the build has finished
+    fn wait_for_build(shell: &Shell, id: i32) -> ::BuildDetails {
+        for _ in 0..100 {
+            let build = shell.get_build(id).unwrap();
+            if build.state == ::BuildState::Finished {
+                return build;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Build {} did not finish in time", id);
+    }
+
+    #[test]
+    fn successful_command_finishes_with_success() {
+        let td = TempDir::new("shell").unwrap();
+        let shell = Shell::new(&config(&td, "echo \"$PR_DEMON_PR_ID $PR_DEMON_FROM_COMMIT\"")).unwrap();
+
+        let queued = shell.queue_build(&pull_request()).unwrap();
+        assert_eq!(1, queued.id);
+        assert_eq!(::BuildState::Queued, queued.state);
+        assert_eq!(Some(pull_request().from_commit), queued.commit);
+
+        let build = wait_for_build(&shell, queued.id);
+        assert_eq!(::BuildStatus::Success, build.status);
+        assert_eq!(Some("Exited with status 0".to_string()), build.status_text);
+
+        let mut log = String::new();
+        File::open(td.path().join("builds").join("1.log"))
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        assert_eq!(
+            "111 363c1dfda4cdf5a01c2d210e49942c8c8e7e898b\n",
+            log
+        );
+    }
+
+    #[test]
+    fn failing_command_finishes_with_failure() {
+        let td = TempDir::new("shell").unwrap();
+        let shell = Shell::new(&config(&td, "exit 3")).unwrap();
+
+        let queued = shell.queue_build(&pull_request()).unwrap();
+        let build = wait_for_build(&shell, queued.id);
+        assert_eq!(::BuildStatus::Failure, build.status);
+        assert_eq!(Some("Exited with status 3".to_string()), build.status_text);
+    }
+
+    #[test]
+    fn get_build_list_returns_builds_for_commit_latest_first() {
+        let td = TempDir::new("shell").unwrap();
+        let shell = Shell::new(&config(&td, "true")).unwrap();
+
+        let mut other_pr = pull_request();
+        other_pr.from_commit = "foobar".to_string();
+
+        let first = shell.queue_build(&pull_request()).unwrap();
+        let other = shell.queue_build(&other_pr).unwrap();
+        let second = shell.queue_build(&pull_request()).unwrap();
+
+        let ids: Vec<i32> = shell
+            .get_build_list(&pull_request())
+            .unwrap()
+            .iter()
+            .map(|build| build.id)
+            .collect();
+        assert_eq!(vec![second.id, first.id], ids);
+
+        for id in &[first.id, other.id, second.id] {
+            wait_for_build(&shell, *id);
+        }
+    }
+
+    #[test]
+    fn only_the_latest_finished_builds_are_kept() {
+        let td = TempDir::new("shell").unwrap();
+        let store = Store::new(td.path().to_str().unwrap()).unwrap();
+        for _ in 0..3 {
+            let mut record = store.create(&pull_request()).unwrap();
+            record.state = ::BuildState::Finished;
+            store.write(&record).unwrap();
+        }
+        let unfinished = store.create(&pull_request()).unwrap();
+
+        store.prune(2).unwrap();
+        assert_eq!(vec![3, unfinished.id], store.ids().unwrap());
+        assert_eq!(vec![3, unfinished.id], store.ids_of_commit(&pull_request().from_commit).unwrap());
+        assert!(!store.record_path(1).exists());
+        assert_eq!(unfinished.id + 1, store.create(&pull_request()).unwrap().id);
+    }
+
+    #[test]
+    fn builds_of_state_directories_without_an_index_are_indexed() {
+        let td = TempDir::new("shell").unwrap();
+        let record = Store::new(td.path().to_str().unwrap()).unwrap().create(&pull_request()).unwrap();
+        fs::remove_dir_all(td.path().join("commits")).unwrap();
+
+        let store = Store::new(td.path().to_str().unwrap()).unwrap();
+        assert_eq!(vec![record.id], store.ids_of_commit(&pull_request().from_commit).unwrap());
+    }
+
+    #[test]
+    fn concurrency_must_be_at_least_one() {
+        let td = TempDir::new("shell").unwrap();
+        let mut config = config(&td, "true");
+        config.concurrency = Some(0);
+        assert!(Shell::new(&config).is_err());
+    }
+
+    #[test]
+    fn interrupted_builds_are_resumed() {
+        let td = TempDir::new("shell").unwrap();
+        let config = config(&td, "true");
+        {
+            let store = Store::new(&config.state_path).unwrap();
+            let mut record: BuildRecord = store.create(&pull_request()).unwrap();
+            record.state = ::BuildState::Running;
+            store.write(&record).unwrap();
+        }
+
+        let shell = Shell::new(&config).unwrap();
+        let build = wait_for_build(&shell, 1);
+        assert_eq!(::BuildStatus::Success, build.status);
+    }
+
+    #[test]
+    fn builds_are_only_resumed_once() {
+        let td = TempDir::new("shell").unwrap();
+        let config = config(&td, "echo $PR_DEMON_BUILD_ID >> ../../runs");
+        {
+            let store = Store::new(&config.state_path).unwrap();
+            let mut record: BuildRecord = store.create(&pull_request()).unwrap();
+            record.state = ::BuildState::Running;
+            store.write(&record).unwrap();
+        }
+
+        // As when the configuration is reloaded, unchanged and then changed
+        let shell = Shell::new(&config).unwrap();
+        let reloaded = Shell::new(&config).unwrap();
+        let mut changed = config.clone();
+        changed.concurrency = Some(2);
+        let changed = Shell::new(&changed).unwrap();
+
+        let queued = changed.queue_build(&pull_request()).unwrap();
+        assert_eq!(2, queued.id);
+        wait_for_build(&shell, 1);
+        wait_for_build(&reloaded, queued.id);
+        thread::sleep(Duration::from_millis(200));
+
+        let mut runs = String::new();
+        File::open(td.path().join("runs"))
+            .unwrap()
+            .read_to_string(&mut runs)
+            .unwrap();
+        let mut runs: Vec<&str> = runs.lines().collect();
+        runs.sort();
+        assert_eq!(vec!["1", "2"], runs);
+    }
+
+    #[test]
+    fn read_only_shells_do_not_run_builds() {
+        let td = TempDir::new("shell").unwrap();
+        let config = config(&td, "true");
+        {
+            let store = Store::new(&config.state_path).unwrap();
+            store.create(&pull_request()).unwrap();
+        }
+
+        let shell = Shell::read_only(&config).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(::BuildState::Queued, shell.get_build(1).unwrap().state);
+    }
+}
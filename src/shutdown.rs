@@ -0,0 +1,72 @@
+//! Graceful shutdown on SIGTERM and SIGINT
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Builder};
+use std::time::Duration;
+
+use signal_hook;
+use signal_hook::iterator::Signals;
+
+/// Whether the daemon has been asked to shut down
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// A shutdown that is only ever requested by calling `request`
+    pub fn new() -> Shutdown {
+        Shutdown {
+            requested: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Request a shutdown on SIGTERM or SIGINT.
+    ///
+    /// If the daemon has not exited `timeout` after the first signal, or if a second signal is received, the process
+    /// exits immediately with a non-zero status.
+    pub fn register(timeout: Duration) -> Result<Shutdown, String> {
+        let shutdown = Shutdown::new();
+        let signals = Signals::new(&[signal_hook::SIGTERM, signal_hook::SIGINT])
+            .map_err(|err| format!("Unable to register signal handlers: {}", err))?;
+
+        let cloned = shutdown.clone();
+        Builder::new()
+            .name("shutdown".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    if cloned.is_requested() {
+                        error!("Received signal {} again — exiting immediately", signal);
+                        process::exit(1);
+                    }
+
+                    info!(
+                        "Received signal {} — shutting down within {} seconds",
+                        signal,
+                        timeout.as_secs()
+                    );
+                    cloned.request();
+                    Builder::new()
+                        .name("shutdown_deadline".to_string())
+                        .spawn(move || {
+                            thread::sleep(timeout);
+                            error!("Unable to shut down within {} seconds — exiting", timeout.as_secs());
+                            process::exit(1);
+                        })
+                        .unwrap_or_else(|err| panic!("Unable to enforce shutdown deadline: {}", err));
+                }
+            })
+            .map_err(|err| err.to_string())?;
+
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,773 @@
+//! Store of what the daemon knows about each pull request, optionally persisted so that it survives restarts
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde_json;
+
+use error::{Error, ErrorKind};
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct StateConfiguration {
+    /// Directory to keep the state in
+    pub path: String,
+}
+
+impl StateConfiguration {
+    /// Configuration for the state of one of several projects, kept in a subdirectory named after the project
+    pub fn for_project(&self, project: &str) -> StateConfiguration {
+        StateConfiguration {
+            path: Path::new(&self.path).join(project).to_string_lossy().into_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct PullRequestState {
+    pub id: i32,
+    /// Head commit of the PR when it was last listed
+    pub from_commit: String,
+    /// Latest build of the PR, by build configuration
+    pub builds: BTreeMap<String, TrackedBuild>,
+    /// Comment posted on the PR, by build configuration
+    pub comments: BTreeMap<String, TrackedComment>,
+    /// Last verdict posted with `Repository::post_verdict`
+    #[serde(default)]
+    pub verdict: Option<PostedVerdict>,
+}
+
+impl PullRequestState {
+    fn new(id: i32) -> PullRequestState {
+        PullRequestState {
+            id: id,
+            from_commit: "".to_string(),
+            builds: BTreeMap::new(),
+            comments: BTreeMap::new(),
+            verdict: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct TrackedBuild {
+    pub id: i32,
+    /// Commit the build was queued for
+    pub commit: String,
+    /// Last build state the repository was notified of with a comment
+    pub posted: Option<PostedStatus>,
+    /// Last build state posted with `Repository::post_build`
+    #[serde(default)]
+    pub status_posted: Option<PostedStatus>,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct PostedStatus {
+    /// Commit the build state was posted for, which PR transformers can make differ between notifications
+    #[serde(default)]
+    pub commit: String,
+    pub state: ::BuildState,
+    pub status: ::BuildStatus,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct PostedVerdict {
+    /// Commit the verdict was posted for
+    pub commit: String,
+    pub verdict: ::Verdict,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct TrackedComment {
+    pub id: i32,
+    pub version: i32,
+    pub text: String,
+}
+
+/// Pull request states, persisted as one JSON file per PR or only kept in memory
+#[derive(Clone)]
+pub struct Store {
+    /// Directory of the state files, if the state is persisted
+    path: Option<PathBuf>,
+    memory: Arc<Mutex<BTreeMap<i32, PullRequestState>>>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Store {
+    pub fn open(config: &StateConfiguration) -> Result<Store, String> {
+        let store = Store {
+            path: Some(PathBuf::from(&config.path)),
+            ..Store::in_memory()
+        };
+        fs::create_dir_all(store.pull_requests_path().unwrap())
+            .map_err(|err| format!("Unable to create state directory {}: {}", config.path, err))?;
+        Ok(store)
+    }
+
+    /// A store that is lost when the daemon exits
+    pub fn in_memory() -> Store {
+        Store {
+            path: None,
+            memory: Arc::new(Mutex::new(BTreeMap::new())),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn pull_requests_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|path| path.join("pull_requests"))
+    }
+
+    fn pull_request_path(&self, pr_id: i32) -> Option<PathBuf> {
+        self.pull_requests_path()
+            .map(|path| path.join(format!("{}.json", pr_id)))
+    }
+
+    fn memory(&self) -> Result<MutexGuard<BTreeMap<i32, PullRequestState>>, String> {
+        self.memory.lock().map_err(|err| err.to_string())
+    }
+
+    pub fn get(&self, pr_id: i32) -> Result<Option<PullRequestState>, String> {
+        let path = match self.pull_request_path(pr_id) {
+            Some(path) => path,
+            None => return Ok(self.memory()?.get(&pr_id).cloned()),
+        };
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(file)
+                .map(Some)
+                .map_err(|err| format!("Unable to read state of PR #{}: {}", pr_id, err)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("Unable to read state of PR #{}: {}", pr_id, err)),
+        }
+    }
+
+    /// Modify the state of a PR, creating it if it does not exist yet
+    pub fn update<F, R>(&self, pr_id: i32, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&mut PullRequestState) -> R,
+    {
+        let _lock = self.lock.lock().map_err(|err| err.to_string())?;
+        let mut state = self.get(pr_id)?.unwrap_or_else(|| PullRequestState::new(pr_id));
+        let result = f(&mut state);
+
+        let path = match self.pull_request_path(pr_id) {
+            Some(path) => path,
+            None => {
+                self.memory()?.insert(pr_id, state);
+                return Ok(result);
+            }
+        };
+
+        // Write to a temporary file first so that a crash cannot leave a truncated state behind
+        let temporary_path = path.with_extension("json.tmp");
+        let file = File::create(&temporary_path).map_err(|err| err.to_string())?;
+        serde_json::to_writer(file, &state).map_err(|err| err.to_string())?;
+        fs::rename(&temporary_path, &path).map_err(|err| err.to_string())?;
+        Ok(result)
+    }
+
+    pub fn remove(&self, pr_id: i32) -> Result<(), String> {
+        let _lock = self.lock.lock().map_err(|err| err.to_string())?;
+        let path = match self.pull_request_path(pr_id) {
+            Some(path) => path,
+            None => {
+                self.memory()?.remove(&pr_id);
+                return Ok(());
+            }
+        };
+        match fs::remove_file(path) {
+            Err(ref err) if err.kind() != io::ErrorKind::NotFound => Err(err.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// IDs of every PR with a stored state
+    pub fn ids(&self) -> Result<Vec<i32>, String> {
+        let path = match self.pull_requests_path() {
+            Some(path) => path,
+            None => return Ok(self.memory()?.keys().cloned().collect()),
+        };
+
+        let entries = fs::read_dir(path).map_err(|err| err.to_string())?;
+        let mut ids = vec![];
+        for entry in entries {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            if path.extension().map_or(false, |extension| extension == "json") {
+                if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+/// Records the PRs seen by a repository, and only notifies it of builds whose state has changed
+pub struct TrackedRepository {
+    inner: Box<::Repository>,
+    store: Store,
+}
+
+/// Ways of notifying a repository of the state of a build
+#[derive(Clone, Copy, Debug)]
+enum Notification {
+    /// `build_queued`, `build_running`, `build_success` or `build_failure`
+    Comment,
+    /// `post_build`
+    Status,
+}
+
+impl Notification {
+    fn posted<'a>(&self, tracked: &'a mut TrackedBuild) -> &'a mut Option<PostedStatus> {
+        match *self {
+            Notification::Comment => &mut tracked.posted,
+            Notification::Status => &mut tracked.status_posted,
+        }
+    }
+}
+
+impl PostedStatus {
+    fn of(pr: &::PullRequest, build: &::BuildDetails) -> PostedStatus {
+        PostedStatus {
+            commit: pr.from_commit.to_string(),
+            state: build.state.clone(),
+            status: build.status.clone(),
+        }
+    }
+}
+
+impl TrackedRepository {
+    pub fn new(inner: Box<::Repository>, store: &Store) -> TrackedRepository {
+        TrackedRepository {
+            inner: inner,
+            store: store.clone(),
+        }
+    }
+
+    fn record_seen(&self, prs: &[::PullRequest]) -> Result<(), String> {
+        for pr in prs {
+            self.store.update(pr.id, |state| {
+                if state.from_commit != pr.from_commit {
+                    if !state.from_commit.is_empty() {
+                        info!(
+                            "PR #{} updated from {} to {}",
+                            pr.id, state.from_commit, pr.from_commit
+                        );
+                    }
+                    state.from_commit = pr.from_commit.to_string();
+                }
+            })?;
+        }
+
+        for id in self.store.ids()? {
+            if !prs.iter().any(|pr| pr.id == id) {
+                debug!("Forgetting state of closed PR #{}", id);
+                self.store.remove(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the repository has already been notified of the current state of the build for the commit of `pr`
+    fn is_posted(&self, pr: &::PullRequest, build: &::BuildDetails, notification: Notification) -> bool {
+        let state = match self.store.get(pr.id) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("{}", err);
+                return false;
+            }
+        };
+
+        state
+            .and_then(|mut state| state.builds.remove(&build.build_id))
+            .map_or(false, |mut tracked| {
+                tracked.id == build.id && *notification.posted(&mut tracked) == Some(PostedStatus::of(pr, build))
+            })
+    }
+
+    fn record_posted(&self, pr: &::PullRequest, build: &::BuildDetails, notification: Notification) {
+        let result = self.store.update(pr.id, |state| {
+            let tracked = state
+                .builds
+                .entry(build.build_id.to_string())
+                .or_insert_with(|| TrackedBuild {
+                    id: build.id,
+                    commit: pr.from_commit.to_string(),
+                    posted: None,
+                    status_posted: None,
+                });
+            // Each notification keeps the commit it was posted for, as they need not be about the same commit
+            if tracked.id != build.id {
+                *tracked = TrackedBuild {
+                    id: build.id,
+                    commit: pr.from_commit.to_string(),
+                    posted: None,
+                    status_posted: None,
+                };
+            }
+            *notification.posted(tracked) = Some(PostedStatus::of(pr, build));
+        });
+        if let Err(err) = result {
+            warn!("Unable to record state of PR #{}: {}", pr.id, err);
+        }
+    }
+
+    /// Call `notify` unless the repository has already been notified of the state of the build
+    fn notify_on_change<F>(
+        &self,
+        pr: &::PullRequest,
+        build: &::BuildDetails,
+        notification: Notification,
+        notify: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&::Repository) -> Result<(), Error>,
+    {
+        if self.is_posted(pr, build, notification) {
+            debug!(
+                "Build {} of PR #{} is still {:?} {:?}, skipping {:?} notification",
+                build.id, pr.id, build.state, build.status, notification
+            );
+            return Ok(());
+        }
+
+        notify(&*self.inner)?;
+        self.record_posted(pr, build, notification);
+        Ok(())
+    }
+}
+
+impl ::Repository for TrackedRepository {
+    fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+        let prs = self.inner.get_pr_list()?;
+        if let Err(err) = self.record_seen(&prs) {
+            warn!("Unable to record Pull Requests: {}", err);
+        }
+        Ok(prs)
+    }
+
+    /// Only a full list tells which pull requests were closed, so nothing is recorded
+    fn get_pr(&self, id: i32) -> Result<Option<::PullRequest>, Error> {
+        self.inner.get_pr(id)
+    }
+
+    fn build_queued(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify_on_change(pr, build, Notification::Comment, |inner| {
+            inner.build_queued(pr, build)
+        })
+    }
+
+    fn build_running(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify_on_change(pr, build, Notification::Comment, |inner| {
+            inner.build_running(pr, build)
+        })
+    }
+
+    fn build_success(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify_on_change(pr, build, Notification::Comment, |inner| {
+            inner.build_success(pr, build)
+        })
+    }
+
+    fn build_failure(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify_on_change(pr, build, Notification::Comment, |inner| {
+            inner.build_failure(pr, build)
+        })
+    }
+
+    fn post_build(&self, pr: &::PullRequest, build: &::BuildDetails) -> Result<(), Error> {
+        self.notify_on_change(pr, build, Notification::Status, |inner| {
+            inner.post_build(pr, build)
+        })
+    }
+
+    fn post_verdict(&self, pr: &::PullRequest, verdict: &::Verdict) -> Result<(), Error> {
+        let posted = PostedVerdict {
+            commit: pr.from_commit.to_string(),
+            verdict: verdict.clone(),
+        };
+        match self.store.get(pr.id) {
+            Ok(Some(ref state)) if state.verdict.as_ref() == Some(&posted) => {
+                debug!("Verdict of PR #{} is still {:?}, skipping", pr.id, verdict);
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(err) => warn!("{}", err),
+        }
+
+        self.inner.post_verdict(pr, verdict)?;
+        if let Err(err) = self.store.update(pr.id, |state| state.verdict = Some(posted)) {
+            warn!("Unable to record verdict of PR #{}: {}", pr.id, err);
+        }
+        Ok(())
+    }
+}
+
+/// Remembers the builds queued by a continuous integrator so that they need not be searched for.
+///
+/// Only builds with a positive ID are used. Jenkins identifies builds still waiting in its queue by the negated ID of
+/// their queue item, which it forgets a few minutes after the build starts, and a dry run pretends to queue builds with
+/// an ID of 0.
+pub struct TrackedIntegrator {
+    inner: Box<::ContinuousIntegrator>,
+    /// Build configuration of `inner`, as reported in `BuildDetails::build_id`
+    build_id: String,
+    store: Store,
+}
+
+impl TrackedIntegrator {
+    pub fn new(inner: Box<::ContinuousIntegrator>, build_id: &str, store: &Store) -> TrackedIntegrator {
+        TrackedIntegrator {
+            inner: inner,
+            build_id: build_id.to_string(),
+            store: store.clone(),
+        }
+    }
+
+    fn tracked_build(&self, pr: &::PullRequest) -> Option<TrackedBuild> {
+        match self.store.get(pr.id) {
+            Ok(state) => state
+                .and_then(|state| state.builds.get(&self.build_id).cloned())
+                .and_then(|build| if build.commit == pr.from_commit && build.id > 0 { Some(build) } else { None }),
+            Err(err) => {
+                warn!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// Stop using the build `id` once it no longer exists, e.g. because the integrator pruned it
+    fn forget(&self, id: i32) {
+        let result = self.store.ids().and_then(|pr_ids| {
+            for pr_id in pr_ids {
+                self.store.update(pr_id, |state| {
+                    if state.builds.get(&self.build_id).map_or(false, |build| build.id == id) {
+                        state.builds.remove(&self.build_id);
+                    }
+                })?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("Unable to forget build {}: {}", id, err);
+        }
+    }
+}
+
+impl ::ContinuousIntegrator for TrackedIntegrator {
+    fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
+        match self.tracked_build(pr) {
+            Some(build) => {
+                debug!("Using tracked build {} of PR #{}", build.id, pr.id);
+                Ok(vec![::Build { id: build.id }])
+            }
+            None => self.inner.get_build_list(pr),
+        }
+    }
+
+    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+        let result = self.inner.get_build(build_id);
+        if let Err(ref err) = result {
+            if err.kind() == ErrorKind::NotFound {
+                self.forget(build_id);
+            }
+        }
+        result
+    }
+
+    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, Error> {
+        let build = self.inner.queue_build(pr)?;
+        if build.id <= 0 {
+            return Ok(build);
+        }
+        let result = self.store.update(pr.id, |state| {
+            state.builds.insert(
+                self.build_id.to_string(),
+                TrackedBuild {
+                    id: build.id,
+                    commit: pr.from_commit.to_string(),
+                    posted: None,
+                    status_posted: None,
+                },
+            );
+        });
+        if let Err(err) = result {
+            warn!("Unable to record build of PR #{}: {}", pr.id, err);
+        }
+        Ok(build)
+    }
+
+    fn refresh_vcs(&self) -> Result<(), Error> {
+        self.inner.refresh_vcs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tempdir::TempDir;
+    use super::{PostedStatus, StateConfiguration, Store, TrackedBuild, TrackedIntegrator, TrackedRepository};
+    use error::Error;
+    use {ContinuousIntegrator, Repository};
+
+    fn store(td: &TempDir) -> Store {
+        Store::open(&StateConfiguration {
+            path: td.path().to_str().unwrap().to_string(),
+        }).unwrap()
+    }
+
+    fn pull_request(id: i32, commit: &str) -> ::PullRequest {
+        ::PullRequest {
+            id: id,
+            web_url: format!("http://www.foobar.com/pr/{}", id),
+            from_ref: "refs/heads/branch_name".to_owned(),
+            from_commit: commit.to_owned(),
+            to_ref: "refs/heads/master".to_owned(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_owned(),
+            title: "A very important PR".to_owned(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_owned(),
+                email: "aaron@xiao.ming".to_owned(),
+            },
+        }
+    }
+
+    fn build(id: i32) -> ::BuildDetails {
+        ::BuildDetails {
+            id: id,
+            build_id: "foobar".to_owned(),
+            web_url: format!("http://www.goodbuilds.com/{}", id),
+            commit: None,
+            branch_name: "pull/1/merge".to_string(),
+            state: ::BuildState::Finished,
+            status: ::BuildStatus::Success,
+            status_text: None,
+        }
+    }
+
+    struct StubRepository {
+        prs: Arc<Mutex<Vec<::PullRequest>>>,
+        /// Notifications received, by name
+        notified: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl StubRepository {
+        fn new(prs: &Arc<Mutex<Vec<::PullRequest>>>) -> StubRepository {
+            StubRepository {
+                prs: prs.clone(),
+                notified: Arc::new(Mutex::new(vec![])),
+            }
+        }
+
+        fn notify(&self, name: &'static str) -> Result<(), Error> {
+            self.notified.lock().unwrap().push(name);
+            Ok(())
+        }
+    }
+
+    impl Repository for StubRepository {
+        fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
+            Ok(self.prs.lock().unwrap().clone())
+        }
+        fn build_queued(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.notify("queued")
+        }
+        fn build_running(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.notify("running")
+        }
+        fn build_success(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.notify("success")
+        }
+        fn build_failure(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            Err("Unable to post".into())
+        }
+        fn post_build(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
+            self.notify("post_build")
+        }
+        fn post_verdict(&self, _: &::PullRequest, _: &::Verdict) -> Result<(), Error> {
+            self.notify("post_verdict")
+        }
+    }
+
+    struct StubCi;
+
+    impl ContinuousIntegrator for StubCi {
+        fn build_id(&self) -> &str {
+            "foobar"
+        }
+        fn get_build_list(&self, _: &::PullRequest) -> Result<Vec<::Build>, Error> {
+            Ok(vec![])
+        }
+        fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+            Ok(build(build_id))
+        }
+        fn queue_build(&self, _: &::PullRequest) -> Result<::BuildDetails, Error> {
+            Ok(build(42))
+        }
+    }
+
+    #[test]
+    fn store_survives_reopening() {
+        let td = TempDir::new("state").unwrap();
+        store(&td)
+            .update(1, |state| state.from_commit = "foobar".to_string())
+            .unwrap();
+
+        let reopened = store(&td);
+        assert_eq!("foobar", reopened.get(1).unwrap().unwrap().from_commit);
+        assert_eq!(None, reopened.get(2).unwrap());
+        assert_eq!(vec![1], reopened.ids().unwrap());
+
+        reopened.remove(1).unwrap();
+        assert_eq!(None, reopened.get(1).unwrap());
+    }
+
+    #[test]
+    fn repository_records_seen_prs_and_posted_states() {
+        let td = TempDir::new("state").unwrap();
+        let store = store(&td);
+        let prs = Arc::new(Mutex::new(vec![pull_request(1, "abc"), pull_request(2, "def")]));
+        let repository = TrackedRepository::new(Box::new(StubRepository::new(&prs)), &store);
+
+        assert_eq!(2, repository.get_pr_list().unwrap().len());
+        assert_eq!("def", store.get(2).unwrap().unwrap().from_commit);
+
+        repository.build_success(&pull_request(1, "abc"), &build(7)).unwrap();
+        assert!(repository.build_failure(&pull_request(2, "def"), &build(8)).is_err());
+        let tracked = store.get(1).unwrap().unwrap().builds["foobar"].clone();
+        assert_eq!(7, tracked.id);
+        assert_eq!(
+            Some(PostedStatus {
+                commit: "abc".to_string(),
+                state: ::BuildState::Finished,
+                status: ::BuildStatus::Success,
+            }),
+            tracked.posted
+        );
+        assert!(store.get(2).unwrap().unwrap().builds.is_empty());
+
+        // PR 2 has been closed
+        prs.lock().unwrap().pop();
+        assert_eq!(1, repository.get_pr_list().unwrap().len());
+        assert_eq!(vec![1], store.ids().unwrap());
+    }
+
+    #[test]
+    fn integrator_reuses_tracked_builds_of_the_same_commit() {
+        let td = TempDir::new("state").unwrap();
+        let store = store(&td);
+        let integrator = TrackedIntegrator::new(Box::new(StubCi), "foobar", &store);
+
+        assert!(integrator.get_build_list(&pull_request(1, "abc")).unwrap().is_empty());
+        assert_eq!(42, integrator.queue_build(&pull_request(1, "abc")).unwrap().id);
+
+        let builds = integrator.get_build_list(&pull_request(1, "abc")).unwrap();
+        assert_eq!(vec![42], builds.iter().map(|build| build.id).collect::<Vec<_>>());
+        assert!(integrator.get_build_list(&pull_request(1, "def")).unwrap().is_empty());
+    }
+
+    /// Has queued build 42, whose records have since been pruned
+    struct PrunedCi;
+
+    impl ContinuousIntegrator for PrunedCi {
+        fn build_id(&self) -> &str {
+            "foobar"
+        }
+        fn get_build_list(&self, _: &::PullRequest) -> Result<Vec<::Build>, Error> {
+            Ok(vec![])
+        }
+        fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
+            Err(Error::not_found(format!("Build {} does not exist", build_id)))
+        }
+        fn queue_build(&self, _: &::PullRequest) -> Result<::BuildDetails, Error> {
+            Ok(build(42))
+        }
+    }
+
+    #[test]
+    fn integrator_forgets_builds_that_no_longer_exist() {
+        let td = TempDir::new("state").unwrap();
+        let store = store(&td);
+        let integrator = TrackedIntegrator::new(Box::new(PrunedCi), "foobar", &store);
+
+        integrator.queue_build(&pull_request(1, "abc")).unwrap();
+        assert_eq!(1, integrator.get_build_list(&pull_request(1, "abc")).unwrap().len());
+
+        assert!(integrator.get_build(42).is_err());
+        assert!(integrator.get_build_list(&pull_request(1, "abc")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn integrator_does_not_reuse_queue_items() {
+        let td = TempDir::new("state").unwrap();
+        let store = store(&td);
+        let integrator = TrackedIntegrator::new(Box::new(StubCi), "foobar", &store);
+        store
+            .update(1, |state| {
+                state.builds.insert(
+                    "foobar".to_string(),
+                    TrackedBuild {
+                        id: -7,
+                        commit: "abc".to_string(),
+                        posted: None,
+                        status_posted: None,
+                    },
+                );
+            })
+            .unwrap();
+
+        assert!(integrator.get_build_list(&pull_request(1, "abc")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn repository_is_only_notified_of_changed_build_states() {
+        let prs = Arc::new(Mutex::new(vec![]));
+        let stub = StubRepository::new(&prs);
+        let notified = stub.notified.clone();
+        let repository = TrackedRepository::new(Box::new(stub), &Store::in_memory());
+        let pr = pull_request(1, "abc");
+        let mut running = build(7);
+        running.state = ::BuildState::Running;
+
+        repository.build_running(&pr, &running).unwrap();
+        repository.build_running(&pr, &running).unwrap();
+        repository.post_build(&pr, &running).unwrap();
+        repository.post_build(&pr, &running).unwrap();
+        repository.build_success(&pr, &build(7)).unwrap();
+        repository.build_success(&pr, &build(7)).unwrap();
+        // Rebuilds and new commits are notified again
+        repository.build_success(&pr, &build(8)).unwrap();
+        repository.build_success(&pull_request(1, "def"), &build(8)).unwrap();
+
+        assert_eq!(
+            vec!["running", "post_build", "success", "success", "success"],
+            *notified.lock().unwrap()
+        );
+
+        // Failed notifications are retried
+        assert!(repository.build_failure(&pr, &build(9)).is_err());
+        assert!(repository.build_failure(&pr, &build(9)).is_err());
+    }
+
+    #[test]
+    fn repository_is_only_notified_of_changed_verdicts() {
+        let prs = Arc::new(Mutex::new(vec![]));
+        let stub = StubRepository::new(&prs);
+        let notified = stub.notified.clone();
+        let repository = TrackedRepository::new(Box::new(stub), &Store::in_memory());
+        let pr = pull_request(1, "abc");
+
+        repository.post_verdict(&pr, &::Verdict::Pending).unwrap();
+        repository.post_verdict(&pr, &::Verdict::Pending).unwrap();
+        repository.post_verdict(&pr, &::Verdict::Success).unwrap();
+        repository.post_verdict(&pull_request(1, "def"), &::Verdict::Success).unwrap();
+
+        assert_eq!(vec!["post_verdict"; 3], *notified.lock().unwrap());
+    }
+}
@@ -1,5 +1,14 @@
+use error::Error;
+use fanout;
 use rest;
 use hyper;
+use serde::Serialize;
+use serde_json;
+use serde_json::map::Map;
+
+macro_rules! build_cancel_request_template {
+    () => ("<buildCancelRequest comment=\"{comment}\" readdIntoQueue=\"false\" />")
+}
 
 macro_rules! build_request_template {
     () => ("
@@ -17,20 +26,15 @@ macro_rules! build_request_template {
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct TeamcityCredentials {
-    pub username: String,
-    pub password: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Access token, sent as a bearer token instead of `username` and `password`
+    pub token: Option<String>,
     pub base_url: String,
-    pub build_id: String,
-}
-
-impl ::UsernameAndPassword for TeamcityCredentials {
-    fn username(&self) -> &String {
-        &self.username
-    }
-
-    fn password(&self) -> &String {
-        &self.password
-    }
+    /// Build configuration(s) to trigger for every pull request. Each configuration is tracked independently.
+    pub build_id: ::OneOrMany<String>,
+    /// Cancel queued and running builds of older commits when a new build is queued for a PR
+    pub cancel_superseded: Option<bool>,
 }
 
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
@@ -216,11 +220,147 @@ pub struct Property {
     pub value: String,
 }
 
-impl ::ContinuousIntegrator for TeamcityCredentials {
-    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, String> {
+pub struct Teamcity {
+    pub credentials: TeamcityCredentials,
+    auth: rest::Auth,
+    /// The build configuration triggered by this integrator
+    pub build_id: String,
+    broadcaster: fanout::Fanout<fanout::Message>,
+}
+
+impl Teamcity {
+    pub fn new(
+        credentials: &TeamcityCredentials,
+        build_id: &str,
+        broadcaster: &fanout::Fanout<fanout::Message>,
+    ) -> Result<Teamcity, String> {
+        let auth = rest::Auth::new(&credentials.username, &credentials.password, &credentials.token)?;
+        Ok(Teamcity {
+            credentials: credentials.to_owned(),
+            auth: auth,
+            build_id: build_id.to_string(),
+            broadcaster: broadcaster.to_owned(),
+        })
+    }
+
+    fn broadcast<T>(&self, opcode: &str, payload: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let opcode = fanout::OpCode::Custom {
+            payload: format!("TeamCity::{}", opcode).to_owned(),
+        };
+        let message = fanout::Message::new(opcode, payload)?;
+        self.broadcaster.broadcast(message);
+        Ok(())
+    }
+
+    /// Queued and running builds on the logical branch of the PR
+    fn get_unfinished_builds(&self, pr: &::PullRequest) -> Result<Vec<BuildListItem>, Error> {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_auth_header(&self.auth)
+            .add_accept_json_header();
+
+        let mut builds = vec![];
+        for state in &["queued", "running"] {
+            let locator = format!(
+                "defaultFilter:false,state:{},branch:(name:pull/{}/merge)",
+                state, pr.id
+            );
+            let url = format!(
+                "{}/buildTypes/id:{}/builds?locator={}",
+                self.credentials.base_url, self.build_id, locator
+            );
+
+            let build_list = rest::get::<BuildList>(&url, headers.headers.clone())
+                .map_err(|err| err.context(format!("Error getting list of {} builds", state)))?;
+            builds.extend(build_list.build.unwrap_or_else(|| vec![]));
+        }
+        Ok(builds)
+    }
+
+    fn cancel_build(&self, build: &BuildListItem, comment: &str) -> Result<(), Error> {
+        let mut headers = rest::Headers::new();
+        headers
+            .add_auth_header(&self.auth)
+            .add_accept_json_header()
+            .add_content_type_xml_header();
+
+        // Queued builds are removed from the queue, running builds are stopped
+        let url = match build.state {
+            BuildState::queued => format!("{}/buildQueue/id:{}", self.credentials.base_url, build.id),
+            _ => format!("{}/builds/id:{}", self.credentials.base_url, build.id),
+        };
+        let body = format!(build_cancel_request_template!(), comment = xml_escape(comment));
+
+        let response =
+            rest::post_raw(&url, &body, headers.headers).map_err(|err| err.context("Error cancelling build"))?;
+        match response.status() {
+            status if status == &hyper::status::StatusCode::Ok => Ok(()),
+            status => Err(Error::status(status.to_u16(), status.to_string())),
+        }
+    }
+
+    /// Cancel builds for older commits of the PR in favour of `superseded_by`
+    fn cancel_superseded_builds(&self, pr: &::PullRequest, superseded_by: &::BuildDetails) -> Result<(), Error> {
+        use ContinuousIntegrator;
+
+        let current_builds = self.get_build_list(pr)?;
+        let superseded = self.get_unfinished_builds(pr)?
+            .into_iter()
+            .filter(|build| build.id != superseded_by.id)
+            .filter(|build| !current_builds.iter().any(|current| current.id == build.id));
+
+        for build in superseded {
+            let comment = format!(
+                "Superseded by build {} for commit {}",
+                superseded_by.id, pr.from_commit
+            );
+            let result = self.cancel_build(&build, &comment);
+
+            let mut event_payload = Map::new();
+            event_payload.insert(
+                "pr".to_string(),
+                serde_json::to_value(&pr).map_err(|e| Error::decode(e.to_string()))?,
+            );
+            event_payload.insert(
+                "build".to_string(),
+                serde_json::to_value(build.id).map_err(|e| Error::decode(e.to_string()))?,
+            );
+            event_payload.insert(
+                "superseded_by".to_string(),
+                serde_json::to_value(&superseded_by).map_err(|e| Error::decode(e.to_string()))?,
+            );
+
+            match result {
+                Ok(()) => {
+                    info!("Cancelled superseded build {} ({})", build.id, build.webUrl);
+                    self.broadcast("Build::Cancelled", &event_payload)?;
+                }
+                Err(err) => {
+                    warn!("Unable to cancel superseded build {}: {}", build.id, err);
+                    event_payload.insert(
+                        "error".to_string(),
+                        serde_json::to_value(&err).map_err(|e| Error::decode(e.to_string()))?,
+                    );
+                    self.broadcast("Build::CancelError", &event_payload)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ::ContinuousIntegrator for Teamcity {
+    fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    fn get_build_list(&self, pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header();
 
         let locator = format!(
@@ -229,11 +369,11 @@ impl ::ContinuousIntegrator for TeamcityCredentials {
         );
         let url = format!(
             "{}/buildTypes/id:{}/builds?locator={}",
-            self.base_url, self.build_id, locator
+            self.credentials.base_url, self.build_id, locator
         );
 
         let build_list = rest::get::<BuildList>(&url, headers.headers)
-            .map_err(|err| format!("Error getting list of builds {}", err))?;
+            .map_err(|err| err.context("Error getting list of builds"))?;
         Ok(match build_list.build {
             None => vec![],
             Some(ref builds) => builds
@@ -243,22 +383,22 @@ impl ::ContinuousIntegrator for TeamcityCredentials {
         })
     }
 
-    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, String> {
+    fn get_build(&self, build_id: i32) -> Result<::BuildDetails, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header();
 
-        let url = format!("{}/builds/id:{}", self.base_url, build_id);
+        let url = format!("{}/builds/id:{}", self.credentials.base_url, build_id);
 
-        let build = rest::get::<Build>(&url, headers.headers).map_err(|err| format!("Error getting build {}", err))?;
+        let build = rest::get::<Build>(&url, headers.headers).map_err(|err| err.context("Error getting build"))?;
         Ok(build.to_build_details())
     }
 
-    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, String> {
+    fn queue_build(&self, pr: &::PullRequest) -> Result<::BuildDetails, Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header()
             .add_content_type_xml_header();
 
@@ -271,29 +411,195 @@ impl ::ContinuousIntegrator for TeamcityCredentials {
             pr_id = pr.id,
             pr_url = pr.web_url
         );
-        let url = format!("{}/buildQueue", self.base_url);
+        let url = format!("{}/buildQueue", self.credentials.base_url);
 
         let build = rest::post::<Build>(&url, &body, headers.headers, &hyper::status::StatusCode::Ok)
-            .map_err(|err| format!("Error queuing build {}", err))?;
-        Ok(build.to_build_details())
+            .map_err(|err| err.context("Error queuing build"))?
+            .to_build_details();
+
+        if self.credentials.cancel_superseded.unwrap_or(false) {
+            // The new build is queued regardless, so failing to cancel is not an error
+            if let Err(err) = self.cancel_superseded_builds(pr, &build) {
+                warn!("Unable to cancel superseded builds: {}", err);
+            }
+        }
+        Ok(build)
     }
 
-    fn refresh_vcs(&self) -> Result<(), String> {
+    fn refresh_vcs(&self) -> Result<(), Error> {
         let mut headers = rest::Headers::new();
         headers
-            .add_authorization_header(self as &::UsernameAndPassword)
+            .add_auth_header(&self.auth)
             .add_accept_json_header();
 
         let url = format!(
             "{}/vcs-root-instances/checkingForChangesQueue?locator=buildType(id:{})",
-            self.base_url, self.build_id
+            self.credentials.base_url, self.build_id
         );
 
         let response =
-            rest::post_raw(&url, "", headers.headers).map_err(|err| format!("Error requesting for VCS fetch {}", err))?;
+            rest::post_raw(&url, "", headers.headers).map_err(|err| err.context("Error requesting for VCS fetch"))?;
         match response.status() {
             status if status == &hyper::status::StatusCode::Ok => Ok(()),
-            e => Err(e.to_string()),
+            status => Err(Error::status(status.to_u16(), status.to_string())),
         }
     }
 }
+
+/// Escape `text` to be used in XML text or attribute values
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::method::Method;
+    use hyper::status::StatusCode;
+
+    use fanout::{Fanout, Message, OpCode};
+    use test_server::{RecordedRequest, StubResponse, StubServer};
+    use ContinuousIntegrator;
+    use super::{xml_escape, Teamcity, TeamcityCredentials};
+
+    const COMMIT: &'static str = "363c1dfda4cdf5a01c2d210e49942c8c8e7e898b";
+
+    fn credentials(server: &StubServer, cancel_superseded: bool) -> TeamcityCredentials {
+        TeamcityCredentials {
+            username: Some("username".to_string()),
+            password: Some("password".to_string()),
+            token: None,
+            base_url: server.url(),
+            build_id: ::OneOrMany::One("foobar".to_string()),
+            cancel_superseded: Some(cancel_superseded),
+        }
+    }
+
+    fn pull_request() -> ::PullRequest {
+        ::PullRequest {
+            id: 111,
+            web_url: "http://www.foobar.com/pr/111".to_owned(),
+            from_ref: "refs/heads/branch_name".to_owned(),
+            from_commit: COMMIT.to_owned(),
+            to_ref: "refs/heads/master".to_owned(),
+            to_commit: "a7b1c3ad4f2d3c1dfda4cdf5a01c2d210e49942c".to_owned(),
+            title: "A very important PR".to_owned(),
+            author: ::User {
+                name: "Aaron Xiao Ming".to_owned(),
+                email: "aaron@xiao.ming".to_owned(),
+            },
+        }
+    }
+
+    fn build_list(builds: &[(i32, &str)]) -> String {
+        let builds: Vec<String> = builds
+            .iter()
+            .map(|&(id, state)| {
+                format!(
+                    r#"{{"id": {id}, "buildTypeId": "foobar", "state": "{state}", "branchName": "pull/111/merge",
+                        "href": "/builds/id:{id}", "webUrl": "http://teamcity/viewLog.html?buildId={id}"}}"#,
+                    id = id,
+                    state = state
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"count": {}, "href": "/builds", "build": [{}]}}"#,
+            builds.len(),
+            builds.join(",")
+        )
+    }
+
+    fn queued_build(id: i32) -> String {
+        format!(
+            r#"{{"id": {id}, "buildTypeId": "foobar", "state": "queued", "branchName": "pull/111/merge",
+                "href": "/buildQueue/id:{id}", "webUrl": "http://teamcity/viewQueued.html?itemId={id}",
+                "buildType": {{"id": "foobar", "name": "Foobar", "projectName": "Foo", "projectId": "Foo",
+                    "href": "/buildTypes/id:foobar", "webUrl": "http://teamcity/viewType.html?buildTypeId=foobar"}},
+                "queuedDate": "20170101T000000+0000", "changes": {{"href": "/changes"}},
+                "revisions": {{"count": 0}}, "artifacts": {{"href": "/artifacts"}},
+                "properties": {{"count": 0, "property": []}}}}"#,
+            id = id
+        )
+    }
+
+    fn respond(request: &RecordedRequest) -> StubResponse {
+        match (&request.method, request.path.as_str()) {
+            (&Method::Post, "/buildQueue") => StubResponse::json(StatusCode::Ok, &queued_build(30)),
+            (&Method::Post, _) => StubResponse::json(StatusCode::Ok, ""),
+            (_, path) if path.contains("state:queued") => {
+                StubResponse::json(StatusCode::Ok, &build_list(&[(30, "queued"), (20, "queued")]))
+            }
+            (_, path) if path.contains("state:running") => {
+                StubResponse::json(StatusCode::Ok, &build_list(&[(10, "running"), (25, "running")]))
+            }
+            // Builds of the current commit
+            _ => StubResponse::json(StatusCode::Ok, &build_list(&[(30, "queued"), (25, "running")])),
+        }
+    }
+
+    #[test]
+    fn queue_build_cancels_superseded_builds() {
+        let server = StubServer::new(respond);
+        let mut fanout = Fanout::<Message>::new();
+        let subscriber = fanout.subscribe();
+        let teamcity = Teamcity::new(&credentials(&server, true), "foobar", &fanout).unwrap();
+
+        let build = teamcity.queue_build(&pull_request()).unwrap();
+        assert_eq!(30, build.id);
+
+        let cancelled: Vec<(String, String)> = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == Method::Post && request.path != "/buildQueue")
+            .map(|request| (request.path, request.body))
+            .collect();
+        assert_eq!(2, cancelled.len());
+        assert_eq!("/buildQueue/id:20", cancelled[0].0);
+        assert_eq!("/builds/id:10", cancelled[1].0);
+        assert!(cancelled[0].1.contains(&format!("Superseded by build 30 for commit {}", COMMIT)));
+        assert!(cancelled[0].1.contains(r#"readdIntoQueue="false""#));
+
+        let messages: Vec<Message> = subscriber.iter().take(2).collect();
+        for message in messages {
+            assert_eq!(
+                OpCode::Custom {
+                    payload: "TeamCity::Build::Cancelled".to_string(),
+                },
+                message.opcode
+            );
+            assert_eq!(Some(30), message.payload["superseded_by"]["id"].as_i64().map(|id| id as i32));
+        }
+    }
+
+    #[test]
+    fn queue_build_leaves_superseded_builds_when_disabled() {
+        let server = StubServer::new(respond);
+        let fanout = Fanout::<Message>::new();
+        let teamcity = Teamcity::new(&credentials(&server, false), "foobar", &fanout).unwrap();
+
+        teamcity.queue_build(&pull_request()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!("/buildQueue", requests[0].path);
+    }
+
+    #[test]
+    fn xml_is_escaped() {
+        assert_eq!(
+            "Superseded by &quot;fix &lt;b&gt;&quot; &amp; &apos;more&apos;",
+            xml_escape("Superseded by \"fix <b>\" & 'more'")
+        );
+    }
+}
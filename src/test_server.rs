@@ -0,0 +1,134 @@
+//! A stub HTTP server to test REST backends against canned responses
+
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use hyper::header::ContentType;
+use hyper::method::Method;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: Method,
+    /// Path and query string of the request
+    pub path: String,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|&&(ref header, _)| header.to_lowercase() == name.to_lowercase())
+            .map(|&(_, ref value)| value.to_string())
+    }
+}
+
+pub struct StubResponse {
+    pub status: StatusCode,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl StubResponse {
+    pub fn json(status: StatusCode, body: &str) -> StubResponse {
+        StubResponse {
+            status: status,
+            body: body.to_string(),
+            headers: vec![],
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> StubResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+struct StubHandler<F>
+where
+    F: Fn(&RecordedRequest) -> StubResponse + Send + Sync,
+{
+    responder: F,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl<F> Handler for StubHandler<F>
+where
+    F: Fn(&RecordedRequest) -> StubResponse + Send + Sync,
+{
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a>) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.to_string(),
+            ref uri => uri.to_string(),
+        };
+        let method = req.method.clone();
+        let headers = req.headers
+            .iter()
+            .map(|header| (header.name().to_string(), header.value_string()))
+            .collect();
+        let mut body = String::new();
+        req.read_to_string(&mut body).unwrap();
+
+        let request = RecordedRequest {
+            method: method,
+            path: path,
+            body: body,
+            headers: headers,
+        };
+        let response = (self.responder)(&request);
+        self.requests.lock().unwrap().push(request);
+
+        *res.status_mut() = response.status;
+        res.headers_mut().set(ContentType::json());
+        for (name, value) in response.headers {
+            res.headers_mut().set_raw(name, vec![value.into_bytes()]);
+        }
+        res.send(response.body.as_bytes()).unwrap();
+    }
+}
+
+/// Serves responses from `responder` on a random local port until dropped
+pub struct StubServer {
+    listening: Listening,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl StubServer {
+    pub fn new<F>(responder: F) -> StubServer
+    where
+        F: Fn(&RecordedRequest) -> StubResponse + Send + Sync + 'static,
+    {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler = StubHandler {
+            responder: responder,
+            requests: requests.clone(),
+        };
+        let listening = Server::http("127.0.0.1:0")
+            .and_then(|server| server.handle(handler))
+            .unwrap_or_else(|e| panic!("Unable to start stub server: {}", e));
+
+        StubServer {
+            listening: listening,
+            requests: requests,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.listening.socket)
+    }
+
+    /// Requests received so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        let _ = self.listening.close();
+    }
+}
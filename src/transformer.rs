@@ -1,12 +1,16 @@
 use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard};
 use fusionner;
 use git2;
 
+use error::Error;
+use metrics;
+
 static DEFAULT_REFSPEC: &'static str = "refs/pull/*";
 
 macro_rules! map_err {
     ($x:expr) => {
-        $x.map_err(|e| format!("{:?}", e))
+        $x.map_err(|e| Error::git(format!("{:?}", e)))
     }
 }
 
@@ -17,6 +21,87 @@ fn to_option_str(opt: &Option<String>) -> Option<&str> {
 pub struct NoOp {}
 impl ::PrTransformer for NoOp {}
 
+/// Makes a transformer usable from worker threads by letting only one thread use it at a time
+pub struct Serialized<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> Serialized<T>
+where
+    T: ::PrTransformer + Send,
+{
+    pub fn new(inner: T) -> Serialized<T> {
+        Serialized {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<T>, Error> {
+        self.inner.lock().map_err(|e| Error::from(e.to_string()))
+    }
+}
+
+impl<T> ::PrTransformer for Serialized<T>
+where
+    T: ::PrTransformer + Send,
+{
+    fn prepare(
+        &self,
+        prs: &[::PullRequest],
+        repo: &::Repository,
+        cis: &[Box<::ContinuousIntegrator>],
+    ) -> Result<(), Error> {
+        self.lock()?.prepare(prs, repo, cis)
+    }
+
+    fn pre_build_retrieval(
+        &self,
+        pr: ::PullRequest,
+        repo: &::Repository,
+        ci: &::ContinuousIntegrator,
+    ) -> Result<::PullRequest, Error> {
+        self.lock()?.pre_build_retrieval(pr, repo, ci)
+    }
+
+    fn pre_build_scheduling(
+        &self,
+        pr: ::PullRequest,
+        repo: &::Repository,
+        ci: &::ContinuousIntegrator,
+    ) -> Result<::PullRequest, Error> {
+        self.lock()?.pre_build_scheduling(pr, repo, ci)
+    }
+
+    fn pre_build_checking(
+        &self,
+        pr: ::PullRequest,
+        build: &::BuildDetails,
+        repo: &::Repository,
+        ci: &::ContinuousIntegrator,
+    ) -> Result<::PullRequest, Error> {
+        self.lock()?.pre_build_checking(pr, build, repo, ci)
+    }
+
+    fn pre_build_status_posting(
+        &self,
+        pr: ::PullRequest,
+        build: &::BuildDetails,
+        repo: &::Repository,
+        ci: &::ContinuousIntegrator,
+    ) -> Result<::PullRequest, Error> {
+        self.lock()?.pre_build_status_posting(pr, build, repo, ci)
+    }
+
+    fn finalize(
+        &self,
+        prs: &[::PullRequest],
+        repo: &::Repository,
+        cis: &[Box<::ContinuousIntegrator>],
+    ) -> Result<(), Error> {
+        self.lock()?.finalize(prs, repo, cis)
+    }
+}
+
 #[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct FusionnerConfiguration {
     pub notes_namespace: Option<String>,
@@ -24,16 +109,18 @@ pub struct FusionnerConfiguration {
     pub push: Option<bool>,
 }
 
-pub struct Fusionner<'repo> {
-    repo: fusionner::git::Repository<'repo>,
+/// Owns its configuration, and opens its checkout whenever it is used, as the checkout borrows the configuration
+pub struct Fusionner {
     config: FusionnerConfiguration,
+    /// Merge locally, but do not push
+    dry_run: bool,
+    /// Project whose checkout this is, for the health of its calls to git
+    project: Option<String>,
 }
 
-impl<'repo> Fusionner<'repo> {
-    pub fn new(config: &'repo FusionnerConfiguration) -> Result<Fusionner<'repo>, String> {
-        let repo = map_err!(fusionner::git::Repository::<'repo>::clone_or_open(
-            &config.repository,
-        ))?;
+impl Fusionner {
+    pub fn new(config: &FusionnerConfiguration) -> Result<Fusionner, Error> {
+        let repo = map_err!(fusionner::git::Repository::clone_or_open(&config.repository))?;
 
         {
             // One time setup of refspecs
@@ -49,16 +136,31 @@ impl<'repo> Fusionner<'repo> {
         }
 
         Ok(Fusionner {
-            repo: repo,
             config: config.clone(),
+            dry_run: false,
+            project: None,
         })
     }
 
-    fn make_merger<'cb>(
+    pub fn with_dry_run(mut self, dry_run: bool) -> Fusionner {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_project(mut self, project: &Option<String>) -> Fusionner {
+        self.project = project.clone();
+        self
+    }
+
+    fn open(&self) -> Result<fusionner::git::Repository, Error> {
+        map_err!(fusionner::git::Repository::open(&self.config.repository))
+    }
+
+    fn make_merger<'repo, 'cb>(
         repo: &'repo fusionner::git::Repository<'repo>,
         namespace: Option<&str>,
         pr: Option<&::PullRequest>,
-    ) -> Result<fusionner::merger::Merger<'repo, 'cb>, String>
+    ) -> Result<fusionner::merger::Merger<'repo, 'cb>, Error>
     where
         'repo: 'cb,
     {
@@ -79,19 +181,20 @@ impl<'repo> Fusionner<'repo> {
     }
 }
 
-impl<'repo> Fusionner<'repo> {
+impl Fusionner {
     fn merge(
         &self,
+        repo: &fusionner::git::Repository,
         pr: &::PullRequest,
     ) -> Result<
         (
             fusionner::merger::Merge,
             fusionner::merger::ShouldMergeResult,
         ),
-        String,
+        Error,
     > {
         let mut merger = map_err!(Self::make_merger(
-            &self.repo,
+            repo,
             to_option_str(&self.config.notes_namespace),
             Some(&pr),
         ))?;
@@ -105,15 +208,21 @@ impl<'repo> Fusionner<'repo> {
     }
 }
 
-impl<'repo> ::PrTransformer for Fusionner<'repo> {
+impl ::PrTransformer for Fusionner {
     /// Merge all the PRs and inform the CI
-    fn prepare(&self, prs: &[::PullRequest], _repo: &::Repository, ci: &::ContinuousIntegrator) -> Result<(), String> {
+    fn prepare(
+        &self,
+        prs: &[::PullRequest],
+        _repo: &::Repository,
+        cis: &[Box<::ContinuousIntegrator>],
+    ) -> Result<(), Error> {
         let notes_refspec;
-        let mut remote = map_err!(self.repo.remote(None))?;
+        let repo = self.open()?;
+        let mut remote = map_err!(repo.remote(None))?;
 
         {
             let merger = map_err!(Self::make_merger(
-                &self.repo,
+                &repo,
                 to_option_str(&self.config.notes_namespace),
                 None,
             ))?;
@@ -135,15 +244,18 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
             info!("Fetching references");
             debug!("{:?}", references);
             let references_slice: Vec<&str> = references.iter().map(|s| &**s).collect();
-            map_err!(remote.fetch(&references_slice))?;
+            metrics::observe(&self.project, "git", || map_err!(remote.fetch(&references_slice)))?;
         }
 
         let mut references = HashSet::<String>::new();
         info!("Merging PRs");
         for pr in prs {
             info!("PR #{}", pr.id);
-            match self.merge(pr) {
-                Err(e) => error!("Error merging PR: {}", e),
+            match self.merge(&repo, pr) {
+                Err(e) => {
+                    error!("Error merging PR: {}", e);
+                    metrics::merge_failed();
+                }
                 Ok((merge, should_merge)) => {
                     if let fusionner::merger::ShouldMergeResult::Merge(_) = should_merge {
                         references.insert(merge.merge_reference.to_string());
@@ -159,13 +271,19 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
                 .map(|s| fusionner::git::RefspecStr::as_forced(s))
                 .collect();
             let references_slice: Vec<&str> = references.iter().map(|s| &**s).collect();
-            info!("Pushing to remote");
-            debug!("{:?}", references);
-            map_err!(remote.push(&references_slice))?;
+            if self.dry_run {
+                info!("Dry run: would push {:?} to remote", references);
+            } else {
+                info!("Pushing to remote");
+                debug!("{:?}", references);
+                metrics::observe(&self.project, "git", || map_err!(remote.push(&references_slice)))?;
+            }
         }
 
         info!("Requesting CI to refresh VCS");
-        ci.refresh_vcs()?;
+        for ci in cis {
+            ci.refresh_vcs()?;
+        }
         Ok(())
     }
 
@@ -175,9 +293,10 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
         pr: ::PullRequest,
         _repo: &::Repository,
         _ci: &::ContinuousIntegrator,
-    ) -> Result<::PullRequest, String> {
+    ) -> Result<::PullRequest, Error> {
+        let repo = self.open()?;
         let merger = map_err!(Self::make_merger(
-            &self.repo,
+            &repo,
             to_option_str(&self.config.notes_namespace),
             None,
         ))?;
@@ -189,7 +308,7 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
         let target_ref = &pr.to_ref;
 
         match matching_merges.get(target_ref) {
-            None => Err(format!("Unable to find merge commit for PR #{}", pr.id)),
+            None => Err(Error::git(format!("Unable to find merge commit for PR #{}", pr.id))),
             Some(merge) => {
                 let mut transformed_pr = pr.clone();
                 transformed_pr.from_ref = merge.merge_reference.to_string();
@@ -210,10 +329,11 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
         _build: &::BuildDetails,
         _repo: &::Repository,
         _ci: &::ContinuousIntegrator,
-    ) -> Result<::PullRequest, String> {
+    ) -> Result<::PullRequest, Error> {
         let merge_oid = map_err!(git2::Oid::from_str(&pr.from_commit))?;
         let target_oid = map_err!(git2::Oid::from_str(&pr.to_commit))?;
-        let merge_commit = map_err!(self.repo.repository.find_commit(merge_oid))?;
+        let repo = self.open()?;
+        let merge_commit = map_err!(repo.repository.find_commit(merge_oid))?;
 
         let pr_oid: Vec<git2::Oid> = merge_commit
             .parent_ids()
@@ -221,10 +341,10 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
             .collect();
 
         if pr_oid.len() != 1 {
-            return Err(format!(
+            return Err(Error::git(format!(
                 "Exactly one non-target OID was not found: {:?}",
                 pr_oid
-            ));
+            )));
         }
         let pr_oid = pr_oid[0];
 
@@ -242,7 +362,6 @@ impl<'repo> ::PrTransformer for Fusionner<'repo> {
 #[cfg(test)]
 mod tests {
     extern crate rand;
-    extern crate tempdir;
     extern crate url;
 
     use std::fs::File;
@@ -251,10 +370,11 @@ mod tests {
 
     use fusionner;
     use git2;
-    use self::tempdir::TempDir;
+    use tempdir::TempDir;
     use self::url::Url;
     use self::rand::Rng;
 
+    use error::Error;
     use transformer;
     use PrTransformer;
 
@@ -275,26 +395,26 @@ mod tests {
     struct StubRepository {}
 
     impl ::Repository for StubRepository {
-        fn get_pr_list(&self) -> Result<Vec<::PullRequest>, String> {
+        fn get_pr_list(&self) -> Result<Vec<::PullRequest>, Error> {
             Ok(vec![])
         }
 
-        fn build_queued(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), String> {
+        fn build_queued(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
             Ok(())
         }
 
-        fn build_running(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), String> {
+        fn build_running(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
             Ok(())
         }
 
-        fn build_success(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), String> {
+        fn build_success(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
             Ok(())
         }
 
-        fn build_failure(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), String> {
+        fn build_failure(&self, _: &::PullRequest, _: &::BuildDetails) -> Result<(), Error> {
             Ok(())
         }
-        fn post_build(&self, _pr: &::PullRequest, _build: &::BuildDetails) -> Result<(), String> {
+        fn post_build(&self, _pr: &::PullRequest, _build: &::BuildDetails) -> Result<(), Error> {
             Ok(())
         }
     }
@@ -317,13 +437,16 @@ mod tests {
     }
 
     impl ::ContinuousIntegrator for StubCi {
-        fn get_build_list(&self, _pr: &::PullRequest) -> Result<Vec<::Build>, String> {
+        fn build_id(&self) -> &str {
+            "foobar"
+        }
+        fn get_build_list(&self, _pr: &::PullRequest) -> Result<Vec<::Build>, Error> {
             Ok(vec![])
         }
-        fn get_build(&self, _build_id: i32) -> Result<::BuildDetails, String> {
+        fn get_build(&self, _build_id: i32) -> Result<::BuildDetails, Error> {
             Ok(Self::stub_details())
         }
-        fn queue_build(&self, _pr: &::PullRequest) -> Result<::BuildDetails, String> {
+        fn queue_build(&self, _pr: &::PullRequest) -> Result<::BuildDetails, Error> {
             Ok(Self::stub_details())
         }
     }
@@ -465,7 +588,8 @@ mod tests {
         };
 
         let transformer = not_err!(transformer::Fusionner::new(&transformer_config));
-        not_err!(transformer.prepare(&[pr.clone()], &StubRepository {}, &StubCi {},));
+        let cis: Vec<Box<::ContinuousIntegrator>> = vec![Box::new(StubCi {})];
+        not_err!(transformer.prepare(&[pr.clone()], &StubRepository {}, &cis));
 
         let transformed_pr = not_err!(transformer.pre_build_retrieval(pr, &StubRepository {}, &StubCi {},));
 
@@ -493,7 +617,7 @@ mod tests {
         };
 
         let transformer = not_err!(transformer::Fusionner::new(&transformer_config));
-        let (merge, _should_merge) = not_err!(transformer.merge(&pr));
+        let (merge, _should_merge) = not_err!(transformer.merge(&repo, &pr));
 
         assert_eq!("refs/pull/1/merge", merge.merge_reference);
         assert!(merge.merge_oid != format!("{}", branch_oid));
@@ -519,14 +643,14 @@ mod tests {
 
         let transformer = not_err!(transformer::Fusionner::new(&transformer_config));
         let mut merger = not_err!(transformer::Fusionner::make_merger(
-            &transformer.repo,
+            &repo,
             None,
             Some(&pr),
         ));
         let (merge, _should_merge) =
             not_err!(merger.check_and_merge(branch_oid, oid, reference, target_reference, false,));
 
-        let (actual_merge, _should_merge) = not_err!(transformer.merge(&pr));
+        let (actual_merge, _should_merge) = not_err!(transformer.merge(&repo, &pr));
         assert_eq!(merge.merge_oid, actual_merge.merge_oid);
         assert_eq!(merge.merge_reference, actual_merge.merge_reference);
 
@@ -556,7 +680,7 @@ mod tests {
 
         let transformer = not_err!(transformer::Fusionner::new(&transformer_config));
         let mut merger = not_err!(transformer::Fusionner::make_merger(
-            &transformer.repo,
+            &repo,
             None,
             Some(&pr),
         ));
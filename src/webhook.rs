@@ -0,0 +1,476 @@
+//! Listener for Bitbucket Server pull request webhooks and TeamCity build notifications
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+
+use hmac::{Hmac, Mac};
+use hyper::method::Method;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use serde_json;
+use sha2::Sha256;
+
+/// Bitbucket events that cause the affected pull request to be handled immediately
+const HANDLED_EVENTS: &'static [&'static str] = &["pr:opened", "pr:modified", "pr:from_ref_updated"];
+
+/// TeamCity notifications of builds whose status should be posted immediately
+const HANDLED_NOTIFICATIONS: &'static [&'static str] = &["buildFinished", "buildInterrupted"];
+
+/// Path that receives TeamCity build notifications. Everything else is treated as a Bitbucket webhook.
+const TEAMCITY_PATH: &'static str = "/teamcity";
+
+/// Largest body accepted, so that unauthenticated clients cannot make the daemon buffer arbitrarily large requests
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Events received from webhooks for the main loop to act on
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum Event {
+    /// A pull request of `repository` was opened or its source branch was updated
+    PullRequestUpdated { repository: RepositoryKey, pr_id: i32 },
+    /// A build of the build configuration `build_type_id` queued for a pull request has finished
+    BuildFinished {
+        build_type_id: String,
+        pr_id: i32,
+        build_id: i32,
+    },
+}
+
+/// Identifies the Bitbucket repository of a pull request
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct RepositoryKey {
+    pub project: String,
+    pub slug: String,
+}
+
+impl RepositoryKey {
+    /// Whether this is the repository configured with `project_slug` and `repo_slug`, which Bitbucket matches
+    /// regardless of case
+    pub fn is(&self, project_slug: &str, repo_slug: &str) -> bool {
+        self.project.eq_ignore_ascii_case(project_slug) && self.slug.eq_ignore_ascii_case(repo_slug)
+    }
+}
+
+#[derive(Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct WebhookConfiguration {
+    /// Address to listen on, e.g. `0.0.0.0:8081`
+    pub listen: String,
+    /// Secret configured on the Bitbucket webhook, used to verify the `X-Hub-Signature` header.
+    /// TeamCity notifications to `/teamcity` must pass it in the `X-Webhook-Secret` header.
+    pub secret: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct BitbucketEvent {
+    pullRequest: PullRequest,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct PullRequest {
+    id: i32,
+    toRef: GitReference,
+}
+
+#[derive(Deserialize, Debug)]
+struct GitReference {
+    repository: Repository,
+}
+
+#[derive(Deserialize, Debug)]
+struct Repository {
+    slug: String,
+    project: Project,
+}
+
+#[derive(Deserialize, Debug)]
+struct Project {
+    key: String,
+}
+
+/// Build notification in the JSON format of the TeamCity webhooks plugin
+#[derive(Deserialize, Debug)]
+struct TeamcityNotification {
+    build: TeamcityBuild,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct TeamcityBuild {
+    buildId: String,
+    buildTypeId: String,
+    branchName: Option<String>,
+    notifyType: String,
+}
+
+struct WebhookHandler {
+    secret: String,
+    sender: Mutex<Sender<Event>>,
+}
+
+impl WebhookHandler {
+    fn handle_event(&self, event_key: Option<&str>, signature: Option<&str>, body: &[u8]) -> StatusCode {
+        if !verify_signature(&self.secret, signature, body) {
+            warn!("Rejecting webhook with an invalid signature");
+            return StatusCode::Unauthorized;
+        }
+
+        match event_key {
+            Some(key) if HANDLED_EVENTS.contains(&key) => (),
+            key => {
+                debug!("Ignoring webhook event {:?}", key);
+                return StatusCode::NoContent;
+            }
+        }
+
+        let event: BitbucketEvent = match serde_json::from_slice(body) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("Unable to decode webhook payload: {}", err);
+                return StatusCode::BadRequest;
+            }
+        };
+
+        let pr = event.pullRequest;
+        let repository = pr.toRef.repository;
+        info!(
+            "Webhook {} received for Pull Request #{} of {}/{}",
+            event_key.unwrap_or_default(),
+            pr.id,
+            repository.project.key,
+            repository.slug
+        );
+        self.send(Event::PullRequestUpdated {
+            repository: RepositoryKey {
+                project: repository.project.key,
+                slug: repository.slug,
+            },
+            pr_id: pr.id,
+        })
+    }
+
+    fn handle_notification(&self, secret: Option<&str>, body: &[u8]) -> StatusCode {
+        let authorized = secret.map_or(false, |secret| constant_time_eq(secret.as_bytes(), self.secret.as_bytes()));
+        if !authorized {
+            warn!("Rejecting TeamCity notification with an invalid secret");
+            return StatusCode::Unauthorized;
+        }
+
+        let notification: TeamcityNotification = match serde_json::from_slice(body) {
+            Ok(notification) => notification,
+            Err(err) => {
+                warn!("Unable to decode TeamCity notification: {}", err);
+                return StatusCode::BadRequest;
+            }
+        };
+        let build = notification.build;
+        if !HANDLED_NOTIFICATIONS.contains(&&*build.notifyType) {
+            debug!("Ignoring TeamCity notification {}", build.notifyType);
+            return StatusCode::NoContent;
+        }
+
+        let pr_id = build.branchName.as_ref().and_then(|branch| parse_pr_id(branch));
+        match (pr_id, build.buildId.parse::<i32>()) {
+            (Some(pr_id), Ok(build_id)) => {
+                info!(
+                    "TeamCity build {} of {} finished for Pull Request #{}",
+                    build_id, build.buildTypeId, pr_id
+                );
+                self.send(Event::BuildFinished {
+                    build_type_id: build.buildTypeId,
+                    pr_id: pr_id,
+                    build_id: build_id,
+                })
+            }
+            _ => {
+                debug!("Ignoring TeamCity build {} on branch {:?}", build.buildId, build.branchName);
+                StatusCode::NoContent
+            }
+        }
+    }
+
+    fn send(&self, event: Event) -> StatusCode {
+        match self.sender.lock().unwrap().send(event) {
+            Ok(()) => StatusCode::Accepted,
+            Err(_) => StatusCode::ServiceUnavailable,
+        }
+    }
+}
+
+/// Extract the PR ID from the `pull/{id}/merge` logical branch name used when queuing builds
+fn parse_pr_id(branch_name: &str) -> Option<i32> {
+    let branch_name = branch_name.trim_left_matches("refs/");
+    let parts: Vec<&str> = branch_name.split('/').collect();
+    if parts.len() == 3 && parts[0] == "pull" && parts[2] == "merge" {
+        parts[1].parse().ok()
+    } else {
+        None
+    }
+}
+
+impl Handler for WebhookHandler {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a>) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => match path.find('?') {
+                Some(index) => path[..index].to_string(),
+                None => path.to_string(),
+            },
+            _ => "".to_string(),
+        };
+
+        let status = match req.method {
+            Method::Post => {
+                let event_key = req.headers
+                    .get_raw("X-Event-Key")
+                    .and_then(|values| values.first())
+                    .and_then(|value| String::from_utf8(value.clone()).ok());
+                let signature = req.headers
+                    .get_raw("X-Hub-Signature")
+                    .and_then(|values| values.first())
+                    .and_then(|value| String::from_utf8(value.clone()).ok());
+                let secret = req.headers
+                    .get_raw("X-Webhook-Secret")
+                    .and_then(|values| values.first())
+                    .and_then(|value| String::from_utf8(value.clone()).ok());
+
+                match read_body(&mut req) {
+                    Ok(Some(ref body)) if path == TEAMCITY_PATH => {
+                        self.handle_notification(secret.as_ref().map(|s| &**s), body)
+                    }
+                    Ok(Some(ref body)) => self.handle_event(
+                        event_key.as_ref().map(|s| &**s),
+                        signature.as_ref().map(|s| &**s),
+                        body,
+                    ),
+                    Ok(None) => StatusCode::PayloadTooLarge,
+                    Err(_) => StatusCode::BadRequest,
+                }
+            }
+            _ => StatusCode::MethodNotAllowed,
+        };
+
+        *res.status_mut() = status;
+        if let Err(err) = res.send(b"") {
+            warn!("Unable to respond to webhook: {}", err);
+        }
+    }
+}
+
+/// Read the body of a request, or None if it is larger than `MAX_BODY_BYTES`
+fn read_body<R: Read>(reader: R) -> io::Result<Option<Vec<u8>>> {
+    let mut body = vec![];
+    reader.take(MAX_BODY_BYTES + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        Ok(None)
+    } else {
+        Ok(Some(body))
+    }
+}
+
+/// Verify a `sha256=<hex digest>` HMAC signature of `body`
+fn verify_signature(secret: &str, signature: Option<&str>, body: &[u8]) -> bool {
+    let digest = match signature.and_then(|signature| decode_signature(signature)) {
+        Some(digest) => digest,
+        None => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.input(body);
+    mac.verify(&digest).is_ok()
+}
+
+/// Compare without returning early, so that the time taken does not tell how much of a secret was guessed
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+fn decode_signature(signature: &str) -> Option<Vec<u8>> {
+    let hex = if signature.starts_with("sha256=") {
+        &signature["sha256=".len()..]
+    } else {
+        return None;
+    };
+
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|i| hex.get(i * 2..i * 2 + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Listen for webhooks and send the resulting events to `sender`
+pub fn listen(config: &WebhookConfiguration, sender: Sender<Event>) -> Result<Listening, String> {
+    let handler = WebhookHandler {
+        secret: config.secret.to_string(),
+        sender: Mutex::new(sender),
+    };
+
+    Server::http(&*config.listen)
+        .and_then(|server| server.handle(handler))
+        .map_err(|err| format!("Unable to start webhook listener: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+
+    use hmac::{Hmac, Mac};
+    use hyper::status::StatusCode;
+    use sha2::Sha256;
+
+    use super::{constant_time_eq, decode_signature, parse_pr_id, read_body, verify_signature, MAX_BODY_BYTES};
+    use super::{Event, RepositoryKey, WebhookHandler};
+
+    const SECRET: &'static str = "foobar";
+    const PAYLOAD: &'static str = r#"{
+        "eventKey": "pr:from_ref_updated",
+        "pullRequest": {"id": 42, "toRef": {"repository": {"slug": "bar", "project": {"key": "FOO"}}}}
+    }"#;
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(SECRET.as_bytes()).unwrap();
+        mac.input(body.as_bytes());
+        let digest: Vec<String> = mac.result()
+            .code()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("sha256={}", digest.join(""))
+    }
+
+    #[test]
+    fn signatures_are_verified() {
+        let signature = sign(PAYLOAD);
+        assert!(verify_signature(SECRET, Some(&signature), PAYLOAD.as_bytes()));
+        assert!(!verify_signature("wrong", Some(&signature), PAYLOAD.as_bytes()));
+        assert!(!verify_signature(SECRET, Some(&signature), b"tampered"));
+        assert!(!verify_signature(SECRET, None, PAYLOAD.as_bytes()));
+        assert_eq!(None, decode_signature("sha1=abcd"));
+        assert_eq!(None, decode_signature("sha256=abc"));
+        assert_eq!(Some(vec![0xab, 0xcd]), decode_signature("sha256=abcd"));
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_not_read_in_full() {
+        let body = vec![b'x'; MAX_BODY_BYTES as usize];
+        assert_eq!(Some(body.clone()), read_body(Cursor::new(&body)).unwrap());
+
+        let body = vec![b'x'; MAX_BODY_BYTES as usize + 1];
+        assert_eq!(None, read_body(Cursor::new(&body)).unwrap());
+    }
+
+    #[test]
+    fn secrets_are_compared_in_full() {
+        assert!(constant_time_eq(b"foobar", b"foobar"));
+        assert!(!constant_time_eq(b"foobar", b"foobaz"));
+        assert!(!constant_time_eq(b"foobar", b"foo"));
+        assert!(!constant_time_eq(b"", b"foobar"));
+    }
+
+    #[test]
+    fn handled_events_are_sent_for_processing() {
+        let (sender, receiver) = channel();
+        let handler = WebhookHandler {
+            secret: SECRET.to_string(),
+            sender: Mutex::new(sender),
+        };
+        let signature = sign(PAYLOAD);
+
+        assert_eq!(
+            StatusCode::Accepted,
+            handler.handle_event(Some("pr:from_ref_updated"), Some(&signature), PAYLOAD.as_bytes())
+        );
+        assert_eq!(
+            Event::PullRequestUpdated {
+                repository: RepositoryKey {
+                    project: "FOO".to_string(),
+                    slug: "bar".to_string(),
+                },
+                pr_id: 42,
+            },
+            receiver.try_recv().unwrap()
+        );
+
+        assert_eq!(
+            StatusCode::NoContent,
+            handler.handle_event(Some("pr:merged"), Some(&signature), PAYLOAD.as_bytes())
+        );
+        assert_eq!(
+            StatusCode::Unauthorized,
+            handler.handle_event(Some("pr:opened"), Some("sha256=00"), PAYLOAD.as_bytes())
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn repositories_are_matched_regardless_of_case() {
+        let repository = RepositoryKey {
+            project: "FOO".to_string(),
+            slug: "bar".to_string(),
+        };
+        assert!(repository.is("foo", "bar"));
+        assert!(repository.is("FOO", "BAR"));
+        assert!(!repository.is("foo", "baz"));
+    }
+
+    #[test]
+    fn pr_ids_are_parsed_from_logical_branch_names() {
+        assert_eq!(Some(42), parse_pr_id("pull/42/merge"));
+        assert_eq!(Some(42), parse_pr_id("refs/pull/42/merge"));
+        assert_eq!(None, parse_pr_id("master"));
+        assert_eq!(None, parse_pr_id("pull/foo/merge"));
+    }
+
+    #[test]
+    fn finished_builds_are_sent_for_processing() {
+        let (sender, receiver) = channel();
+        let handler = WebhookHandler {
+            secret: SECRET.to_string(),
+            sender: Mutex::new(sender),
+        };
+        let notification = |notify_type: &str| {
+            format!(
+                r#"{{"build": {{"buildId": "1234", "buildTypeId": "foobar", "branchName": "pull/42/merge",
+                    "notifyType": "{}"}}}}"#,
+                notify_type
+            )
+        };
+
+        assert_eq!(
+            StatusCode::Accepted,
+            handler.handle_notification(Some(SECRET), notification("buildFinished").as_bytes())
+        );
+        assert_eq!(
+            Event::BuildFinished {
+                build_type_id: "foobar".to_string(),
+                pr_id: 42,
+                build_id: 1234,
+            },
+            receiver.try_recv().unwrap()
+        );
+
+        assert_eq!(
+            StatusCode::NoContent,
+            handler.handle_notification(Some(SECRET), notification("buildStarted").as_bytes())
+        );
+        assert_eq!(
+            StatusCode::Unauthorized,
+            handler.handle_notification(Some("wrong"), notification("buildFinished").as_bytes())
+        );
+        assert_eq!(
+            StatusCode::Unauthorized,
+            handler.handle_notification(None, notification("buildFinished").as_bytes())
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
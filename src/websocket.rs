@@ -1,35 +1,69 @@
 use std::sync::mpsc::Receiver;
-use std::thread::Builder;
+use std::thread::{Builder, JoinHandle};
 
 use serde::Serialize;
 use serde_json;
 use ws::{self, Handler, Message, Sender, WebSocket};
 
+use metrics;
+
 /// A WebSocket client connection
 struct Client {
     sender: Sender,
 }
 
 impl Handler for Client {
+    fn on_open(&mut self, _: ws::Handshake) -> Result<(), ws::Error> {
+        metrics::websocket_client_connected();
+        Ok(())
+    }
+
     fn on_message(&mut self, message: Message) -> Result<(), ws::Error> {
         debug!("Received message {:?}", message);
         self.sender.send(message)
     }
+
+    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+        metrics::websocket_client_disconnected();
+    }
+}
+
+/// A running websocket endpoint
+pub struct Server {
+    broadcaster: Sender,
+    listener: JoinHandle<()>,
+    sender: JoinHandle<()>,
+}
+
+impl Server {
+    /// Send the remaining messages, then close every client connection with `CloseCode::Away` and stop listening.
+    ///
+    /// Blocks until the `Fanout` feeding the endpoint has been dropped.
+    pub fn shutdown(self) -> Result<(), String> {
+        self.sender
+            .join()
+            .map_err(|_| "Websocket sender panicked".to_string())?;
+        self.broadcaster.shutdown().map_err(|e| e.to_string())?;
+        self.listener
+            .join()
+            .map_err(|_| "Websocket listener panicked".to_string())
+    }
 }
 
 /// Create a websocket endpoint
-pub fn listen<T>(address: &str, receiver: Receiver<T>) -> Result<(), String>
+pub fn listen<T>(address: &str, receiver: Receiver<T>) -> Result<Server, String>
 where
     T: Serialize + Send + Sync + Clone + 'static,
 {
     let ws = WebSocket::new(|sender| Client { sender: sender }).map_err(|e| e.to_string())?;
     let broadcaster = ws.broadcaster();
+    let sender_broadcaster = broadcaster.clone();
 
     let address = address.to_string();
-    // TODO: Manage `JoinHandle`s and recover from their panics
+    // TODO: Recover from panics of the threads
 
     // Start websocket server
-    Builder::new()
+    let listener = Builder::new()
         .name("websocket".to_string())
         .spawn(move || {
             ws.listen(address)
@@ -38,17 +72,24 @@ where
         .map_err(|e| e.to_string())?;
 
     // Message sender
-    Builder::new()
+    let sender = Builder::new()
         .name("websocket_sender".to_string())
         .spawn(move || {
             for message in receiver.iter() {
                 let message = serde_json::to_string(&message).unwrap();
-                broadcaster.send(message).unwrap()
+                if let Err(err) = sender_broadcaster.send(message) {
+                    warn!("Websocket server has stopped: {}", err);
+                    break;
+                }
             }
         })
         .map_err(|e| e.to_string())?;
 
-    Ok(())
+    Ok(Server {
+        broadcaster: broadcaster,
+        listener: listener,
+        sender: sender,
+    })
 }
 
 #[cfg(test)]